@@ -1,14 +1,216 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    self as outcall, CanisterHttpRequestArgument, HttpHeader, HttpMethod, TransformArgs,
+    TransformContext,
+};
+#[cfg(not(test))]
+use ic_cdk::api::{caller, is_controller, set_certified_data, time};
+#[cfg(not(test))]
+use ic_cdk::{call, id as canister_id};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use ic_stable_structures::{
+    BoundedStorable, Cell, DefaultMemoryImpl, Log, StableBTreeMap, Storable,
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    marker::PhantomData,
+    thread::LocalKey,
+};
+#[cfg(test)]
+use tests::system::{call, caller, canister_id, is_controller, set_certified_data, time};
 
 // Define memory and id cell types
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+type SchemaVersionCell = Cell<u32, Memory>;
+type RecordMap<T> = StableBTreeMap<u64, Stored<T>, Memory>;
+type RecordStore<T> = LocalKey<RefCell<RecordMap<T>>>;
+type IndexMap = StableBTreeMap<IndexKey, (), Memory>;
+type IndexStore = LocalKey<RefCell<IndexMap>>;
+type TextIndexMap = StableBTreeMap<TextIndexKey, (), Memory>;
+type TextIndexStore = LocalKey<RefCell<TextIndexMap>>;
+// Maps (event id, seat label) to the ticket holding the seat
+type SeatIndexMap = StableBTreeMap<SeatKey, u64, Memory>;
+// Maps (token, record id) to the weight of the token in the record
+type TokenIndexMap = StableBTreeMap<TextIndexKey, u32, Memory>;
+// An organizer's ledgers loaded by one call, by token, with the id each is stored under
+type LedgerBook = BTreeMap<Option<String>, (u64, OrganizerLedger)>;
+type AuditLog = Log<Stored<AuditEntry>, Memory, Memory>;
+type BlockLog = Log<Stored<Icrc3Value>, Memory, Memory>;
+// Maps (event id, start of period) to the tickets the event sold in the period
+type SalesBucketMap = StableBTreeMap<IndexKey, u64, Memory>;
+// Maps (user id, u64::MAX - sequence number) to an entry of the user's points history,
+// so that a user's entries are listed newest first
+type PointsHistoryMap = StableBTreeMap<IndexKey, Stored<PointsTransaction>, Memory>;
+
+// Version of the stable memory layout this build reads and writes.
+// 1: records stored as bare Candid, before versioning was introduced
+// 2: records stored in versioned envelopes, users and events gain new fields
+// 3: secondary indexes of tickets by user and by event
+// 4: secondary indexes of events by date and by location
+// 5: full-text index of events
+// 6: ticket transaction log, with a mint block for every ticket sold before it
+// 7: sales analytics of every event
+// 8: index of users by email address, which must be unique; users gain email_verified_at
+// 9: indexes of users by username, which must be unique, and by owning principal
+// 10: points histories moved out of loyalty accounts into a map of their own
+// 11: index of events by date, latest first
+// 12: index of taken seats by event
+// 13: index of events by organizer principal
+// 14: index of referrals by referrer
+// 15: indexes of tokens by symbol and of tax rates by jurisdiction
+const SCHEMA_VERSION: u32 = 15;
+const LEGACY_SCHEMA_VERSION: u32 = 1;
+
+// Stored records are prefixed with RECORD_MAGIC and the version of their layout,
+// so a build can still read what older builds wrote after a struct changes.
+// Records written before versioning are bare Candid, which always starts with "DIDL".
+const RECORD_MAGIC: u8 = 0xFF;
+const LEGACY_RECORD_VERSION: u8 = 1;
+
+// Block types of the ticket transaction log. Mints and transfers follow ICRC-7;
+// check-ins are specific to this canister.
+const BLOCK_TYPE_MINT: &str = "7mint";
+const BLOCK_TYPE_TRANSFER: &str = "7xfer";
+const BLOCK_TYPE_CHECK_IN: &str = "ticket_checkin";
+const ICRC7_URL: &str = "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-7/ICRC-7.md";
+// Check-in blocks use the generic block format ICRC-3 defines
+const ICRC3_URL: &str = "https://github.com/dfinity/ICRC-1/blob/main/standards/ICRC-3/README.md";
+// Most blocks icrc3_get_blocks returns per call
+const MAX_BLOCKS_PER_CALL: u64 = 1000;
+
+// Longest accepted text values, in bytes, so records always fit their MAX_SIZE
+const MAX_USERNAME_LENGTH: u32 = 64;
+const MAX_EMAIL_LENGTH: u32 = 254;
+const MAX_EVENT_NAME_LENGTH: u32 = 128;
+const MAX_LOCATION_LENGTH: u32 = 256;
+const MAX_SEAT_NUMBER_LENGTH: u32 = 32;
+const MAX_REFERRAL_CODE_LENGTH: u32 = 32;
+const MAX_CATEGORY_LENGTH: u32 = 64;
+const MAX_DESCRIPTION_LENGTH: u32 = 2000;
+const MAX_TAG_LENGTH: u32 = 32;
+const MAX_TAGS: usize = 10;
+const MAX_MEDIA_URLS: usize = 4;
+const MAX_URL_LENGTH: u32 = 256;
+const MAX_CONTACT_LENGTH: u32 = 254;
+const MAX_TIMEZONE_LENGTH: u32 = 64;
+const MAX_MINIMUM_AGE: u8 = 99;
+const MAX_VENUE_NAME_LENGTH: u32 = 128;
+const MAX_ADDRESS_LENGTH: u32 = 256;
+
+// Seat map bounds. Section and row names are short enough that every generated
+// "<section>-<row>-<number>" label fits in MAX_SEAT_NUMBER_LENGTH.
+const MAX_SECTIONS: usize = 32;
+const MAX_ROWS_PER_SECTION: usize = 100;
+const MAX_SEATS_PER_ROW: u32 = 999;
+const MAX_SECTION_NAME_LENGTH: u32 = 16;
+const MAX_ROW_NAME_LENGTH: u32 = 8;
+const MAX_BLOCKED_SEATS: usize = 200;
+const MAX_ZONES: usize = 16;
+const MAX_ZONE_NAME_LENGTH: u32 = 32;
+
+// Recurring events: most occurrences one series may have, and the longest gap between
+// them. Schedules work in nanoseconds, like event dates, and in UTC.
+const MAX_OCCURRENCES: usize = 100;
+const MAX_RECURRENCE_INTERVAL: u32 = 52;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+// Organizer profiles and payouts. Rates are in basis points. Until a controller sets
+// a service fee, buyers pay PLATFORM_FEE_BPS on top of the ticket price; tickets sold
+// before fees were itemized had that share kept out of their price instead.
+const MAX_ORGANIZER_NAME_LENGTH: u32 = 128;
+const ICRC1_SUBACCOUNT_LENGTH: usize = 32;
+const PLATFORM_FEE_BPS: u32 = 250;
+const MAX_BASIS_POINTS: u32 = 10_000;
+const MAX_JURISDICTION_LENGTH: u32 = 16;
+
+// Tokens events can be priced in, and how many besides its own one event may accept
+const MAX_TOKEN_SYMBOL_LENGTH: u32 = 16;
+const MAX_TOKEN_DECIMALS: u8 = 18;
+const MAX_ACCEPTED_TOKENS: usize = 4;
+const MAX_PAYOUT_REASON_LENGTH: usize = 256;
+// Key of the single record in SETTINGS_STORAGE
+const SETTINGS_KEY: u64 = 0;
+
+// Most seats purchase_best_available sells in one order
+const MAX_TICKETS_PER_ORDER: u32 = 10;
+
+// Longest text kept in a text index key; longer values are cut at a char boundary
+const MAX_INDEX_TEXT_LENGTH: u32 = 256;
+
+// Page sizes for search_events
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+// Most rows an export returns per call
+const MAX_EXPORT_ROWS: u32 = 500;
+// Most venues and events, together, one import can create
+const MAX_IMPORT_ROWS: usize = 200;
+// Most responses kept for http_request to serve as certified queries
+const MAX_CACHED_RESPONSES: usize = 500;
+
+// Email verification: how long a code stays valid, how soon another can be sent and
+// how many wrong guesses it takes
+const VERIFICATION_CODE_TTL: u64 = 15 * 60 * 1_000_000_000;
+const VERIFICATION_RESEND_INTERVAL: u64 = 60 * 1_000_000_000;
+const MAX_VERIFICATION_ATTEMPTS: u32 = 5;
+// Cycles attached to each notification request; whatever it does not use is refunded
+const NOTIFICATION_CYCLES: u128 = 2_000_000_000;
+const MAX_NOTIFICATION_RESPONSE_BYTES: u64 = 2048;
+
+// Columns of the exports, in order
+const ATTENDEE_COLUMNS: [&str; 9] = [
+    "ticket_id",
+    "username",
+    "email",
+    "seat",
+    "zone",
+    "price",
+    "purchase_date",
+    "checked_in",
+    "checked_in_at",
+];
+const SALES_COLUMNS: [&str; 12] = [
+    "ticket_id",
+    "purchase_date",
+    "username",
+    "token",
+    "base",
+    "class_premium",
+    "dynamic_adjustment",
+    "loyalty_discount",
+    "fees",
+    "tax",
+    "tax_inclusive",
+    "total",
+];
+
+// Full-text search: tokens longer than this are cut, queries use at most this many tokens,
+// and each query token looks at no more than this many index entries
+const MAX_TOKEN_LENGTH: usize = 32;
+const MAX_QUERY_TOKENS: usize = 8;
+const MAX_TOKEN_MATCHES: usize = 2000;
+// Most index entries search_events looks at per call
+const MAX_SEARCH_SCAN: usize = 2000;
+// Most audit log entries list_audit_log looks at per call
+const MAX_AUDIT_SCAN: usize = 2000;
+
+// How much a token counts towards a text search score, by the field it appears in
+const NAME_TOKEN_WEIGHT: u32 = 4;
+const TAG_TOKEN_WEIGHT: u32 = 3;
+const LOCATION_TOKEN_WEIGHT: u32 = 2;
+const DESCRIPTION_TOKEN_WEIGHT: u32 = 1;
+
+// Newest entries of the points history returned along with a loyalty account; the whole
+// history is paged through with get_points_history
+const RECENT_POINTS_HISTORY: usize = 5;
 
 // Ticket struct
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
@@ -19,6 +221,145 @@ struct Ticket {
     purchase_date: u64,
     seat_number: String,
     price: u64,
+    // The seat in the event's seat map; None for events without one and for
+    // general-admission tickets, whose seat_number is empty
+    seat: Option<SeatId>,
+    // The zone the ticket is for, on events divided into zones
+    zone: Option<String>,
+    // How price was made up; None for tickets sold before prices were itemized
+    breakdown: Option<PriceBreakdown>,
+    // When the ticket was scanned at the door
+    checked_in_at: Option<u64>,
+}
+
+// The items a ticket's price is made up of. Tax is added to the total unless it is
+// inclusive, in which case it is the part of the price that is tax.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PriceBreakdown {
+    // The token every amount is in; None for the platform's default currency
+    token: Option<String>,
+    base: u64,
+    // Class of the seat the premium is for; None for unclassed seats
+    seat_class: Option<SeatClass>,
+    class_premium: u64,
+    // Change made by dynamic pricing, which lowers prices while demand is low
+    dynamic_adjustment: i64,
+    // Tier the loyalty discount was given for; None for buyers without a loyalty account
+    loyalty_tier: Option<LoyaltyTier>,
+    loyalty_discount: u64,
+    fees: u64,
+    tax: u64,
+    tax_inclusive: bool,
+    total: u64,
+}
+
+impl PriceBreakdown {
+    // What the buyer pays for the ticket itself, without fees or tax added on top
+    fn ticket_amount(&self) -> u64 {
+        let added_tax = if self.tax_inclusive { 0 } else { self.tax };
+        self.total - self.fees - added_tax
+    }
+}
+
+// Running sales totals of an event, updated as its tickets are sold and checked in
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EventSales {
+    event_id: u64,
+    tickets_sold: u64,
+    tickets_checked_in: u64,
+    // Stays at zero until tickets can be refunded
+    tickets_refunded: u64,
+    loyalty_discounted_tickets: u64,
+    // Tickets by the loyalty tier of their buyer
+    buyer_tiers: Vec<TierSales>,
+    // Amounts by the currency they were paid in
+    currencies: Vec<CurrencySales>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TierSales {
+    // None for buyers without a loyalty account, and for tickets sold before tiers
+    // were recorded
+    tier: Option<LoyaltyTier>,
+    tickets: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CurrencySales {
+    // None for the platform's default currency
+    token: Option<String>,
+    tickets: u64,
+    // Everything buyers paid, fees and tax included
+    revenue: u64,
+    loyalty_discounts: u64,
+    // Sum of the changes dynamic pricing made to ticket prices
+    dynamic_adjustments: i64,
+    classes: Vec<ClassSales>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ClassSales {
+    // None for unclassed seats and general admission
+    seat_class: Option<SeatClass>,
+    tickets: u64,
+    revenue: u64,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum SalesInterval {
+    Hour,
+    Day,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SalesBucket {
+    // Start of the hour or day, in nanoseconds since the epoch (UTC)
+    start: u64,
+    tickets: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SalesReport {
+    sales: EventSales,
+    // Tickets sold per hour or day, oldest first; periods without sales are left out
+    buckets: Vec<SalesBucket>,
+    // Shares of the tickets sold, in basis points
+    check_in_rate: u32,
+    refund_rate: u32,
+    averages: Vec<CurrencyAverages>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CurrencyAverages {
+    token: Option<String>,
+    // Average paid per ticket, fees and tax included
+    average_price: u64,
+    // Average change dynamic pricing made to the price
+    average_dynamic_adjustment: i64,
+}
+
+// A seat in a seat map. Its label, as stored in seat_number, is "<section>-<row>-<number>".
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Debug, PartialEq)]
+struct SeatId {
+    section: String,
+    row: String,
+    number: u32,
+}
+
+impl SeatId {
+    fn parse(label: &str) -> Option<SeatId> {
+        let mut parts = label.splitn(3, '-');
+        let (section, row, number) = (parts.next()?, parts.next()?, parts.next()?);
+        Some(SeatId {
+            section: section.to_string(),
+            row: row.to_string(),
+            number: number.parse().ok()?,
+        })
+    }
+
+    fn label(&self) -> String {
+        seat_label(&self.section, &self.row, self.number)
+    }
 }
 
 // Event struct
@@ -31,17 +372,136 @@ struct Event {
     ticket_price: u64,
     total_tickets: u64,
     tickets_sold: u64,
+    pricing_mode: PricingMode,
+    // Optional so that events stored before these fields existed still decode
+    organizer: Option<Principal>,
+    category: Option<String>,
+    status: EventStatus,
+    venue_id: Option<u64>,
+    // The recurring series the event is an occurrence of
+    series_id: Option<u64>,
+    // The organizer profile the event's sales are booked to
+    organizer_id: Option<u64>,
+    // Added to ticket_price for seats of these classes
+    class_premiums: Option<Vec<ClassPremium>>,
+    // The token ticket_price is in; None for the platform's default currency
+    token: Option<String>,
+    // Other tokens tickets can be bought with, at these prices
+    token_prices: Option<Vec<TokenPrice>>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TokenPrice {
+    token: String,
+    price: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ClassPremium {
+    seat_class: SeatClass,
+    premium: u64,
+}
+
+// Stage of an event's lifecycle. Tickets can only be bought while it is OnSale.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, Debug, PartialEq)]
+enum EventStatus {
+    // Created but not yet visible; every detail can still be edited
+    #[default]
+    Draft,
+    Published,
+    OnSale,
+    // Set and cleared automatically as tickets sell out or capacity is added
+    SoldOut,
+    Postponed,
+    Cancelled,
+    Completed,
+    Archived,
+}
+
+impl EventStatus {
+    // Whether an event in this status may move to the next one
+    fn can_become(self, next: EventStatus) -> bool {
+        use EventStatus::*;
+        matches!(
+            (self, next),
+            (Draft, Published)
+                | (Draft, Cancelled)
+                | (Published, OnSale)
+                | (Published, Postponed)
+                | (Published, Cancelled)
+                | (Published, Completed)
+                | (OnSale, SoldOut)
+                | (OnSale, Postponed)
+                | (OnSale, Cancelled)
+                | (OnSale, Completed)
+                | (SoldOut, OnSale)
+                | (SoldOut, Postponed)
+                | (SoldOut, Cancelled)
+                | (SoldOut, Completed)
+                | (Postponed, Published)
+                | (Postponed, OnSale)
+                | (Postponed, Cancelled)
+                | (Cancelled, Archived)
+                | (Completed, Archived)
+        )
+    }
+
+    // Whether the event can still be edited at all
+    fn is_final(self) -> bool {
+        matches!(
+            self,
+            EventStatus::Cancelled | EventStatus::Completed | EventStatus::Archived
+        )
+    }
+}
+
+// How an event prices its tickets
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+enum PricingMode {
+    #[default]
+    Fixed,
+    // Price scales with demand from 0.5x to 1.5x of the ticket price
+    Dynamic,
 }
 
 // User struct
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct User {
     id: u64,
+    owner: Principal,
     username: String,
     email: String,
+    referral_code: String,
+    referred_by: Option<u64>,
+    // When the email address was confirmed; None until it is, and again once it changes
+    email_verified_at: Option<u64>,
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+// A user as anyone can look them up
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct UserProfile {
+    id: u64,
+    username: String,
+    referral_code: String,
+    // Only filled in for the account's owner
+    email: Option<String>,
+    email_verified_at: Option<u64>,
+}
+
+// A code sent to a user's email address, kept until it is confirmed or replaced
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EmailVerification {
+    user_id: u64,
+    // The address the code went to; changing the email makes the code useless
+    email: String,
+    code_hash: Vec<u8>,
+    issued_at: u64,
+    expires_at: u64,
+    // Wrong codes entered so far
+    attempts: u32,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
 enum LoyaltyTier {
     #[default]
     Bronze,
@@ -62,9 +522,65 @@ struct LoyaltyPoints {
     user_id: u64,
     points: u64,
     tier: LoyaltyTier,
+    // Empty in stable memory, where the history has a map of its own; responses carry
+    // the newest RECENT_POINTS_HISTORY entries
     points_history: Vec<PointsTransaction>,
 }
 
+// A page of a user's points history
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PointsHistoryPage {
+    // Newest first
+    entries: Vec<PointsTransaction>,
+    // Pass back as the cursor to fetch older entries; None on the last page
+    next_cursor: Option<u64>,
+}
+
+// Points credited to a loyalty account, encoded with their history entries so that
+// writing them cannot fail
+struct LoyaltyCredit {
+    loyalty: LoyaltyPoints,
+    stored_loyalty: Stored<LoyaltyPoints>,
+    entries: Vec<(IndexKey, Stored<PointsTransaction>)>,
+}
+
+// A pending referral settled by the referred user's first purchase, encoded so that
+// writing it cannot fail
+struct ReferralReward {
+    user_id: u64,
+    referrer_id: u64,
+    stored_referral: Stored<Referral>,
+    stored_stats: Stored<ReferralStats>,
+    credit: Option<LoyaltyCredit>,
+}
+
+// Descriptive metadata of an event, kept apart from Event so that it is not bound by
+// the Event map's record size and purchases don't have to decode it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EventDetails {
+    event_id: u64,
+    description: String,
+    tags: Vec<String>,
+    // https URLs of images and other assets
+    media_urls: Vec<String>,
+    organizer_contact: Option<String>,
+    // Doors-open and end times, in the same unit as the event's date
+    doors_open: Option<u64>,
+    end_time: Option<u64>,
+    // IANA time zone name, such as "Europe/Berlin"
+    timezone: Option<String>,
+    minimum_age: Option<u8>,
+}
+
+// An event together with its details, as returned by searches
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EventListing {
+    event: Event,
+    details: EventDetails,
+}
+
+// Seat lists of an event from before venues existed. Events with an EventSeatMap use
+// that instead.
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct EventSeating {
     event_id: u64,
@@ -73,439 +589,10649 @@ struct EventSeating {
     standard_seats: Vec<String>,
 }
 
+// A place events are held at, with the seat map events there start from
 #[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct EarlyAccessPass {
-    user_id: u64,
-    valid_until: u64,
-    priority_level: u8,
+struct Venue {
+    id: u64,
+    owner: Principal,
+    name: String,
+    address: String,
+    coordinates: Option<GeoPoint>,
+    // Everyone the venue can hold, seated or not; never less than its seat count
+    capacity: u64,
+    sections: Vec<VenueSection>,
 }
 
-// Payloads for API
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct RegisterUserPayload {
-    username: String,
-    email: String,
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct GeoPoint {
+    latitude: f64,
+    longitude: f64,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, Debug, PartialEq)]
+enum SeatClass {
+    Vip,
+    Premium,
+    #[default]
+    Standard,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct CreateEventPayload {
+struct VenueSection {
     name: String,
-    location: String,
-    date: u64,
-    ticket_price: u64,
-    total_tickets: u64,
+    seat_class: SeatClass,
+    rows: Vec<VenueRow>,
 }
 
+// A row of seats numbered from 1 to `seats`
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct PurchaseTicketPayload {
-    event_id: u64,
-    user_id: u64,
-    seat_number: String,
+struct VenueRow {
+    name: String,
+    seats: u32,
+    accessible_seats: Vec<AccessibleSeat>,
 }
 
-// Message enum
-#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-enum Message {
-    Success(String),
-    Error(String),
-    NotFound(String),
-    InvalidPayload(String),
-    InsufficientTickets(String),
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AccessibleSeat {
+    number: u32,
+    features: Vec<AccessibilityFeature>,
 }
 
-// Implementing Storable and BoundedStorable for Ticket
-impl Storable for Ticket {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+enum AccessibilityFeature {
+    Wheelchair,
+    Companion,
+    StepFree,
+    HearingLoop,
 }
-impl BoundedStorable for Ticket {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+
+// The seats of one event: a copy of its venue's seat map, which the organizer may
+// then change for this event alone
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EventSeatMap {
+    event_id: u64,
+    venue_id: Option<u64>,
+    sections: Vec<VenueSection>,
+    // Seats held back from sale, such as those taken by the stage or the sound desk
+    blocked_seats: Vec<String>,
 }
 
-// Implementing Storable and BoundedStorable for Event
-impl Storable for Event {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// A recurring event. Each occurrence is an Event of its own, with its own capacity,
+// sales and status, linked back to the series through its series_id.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EventSeries {
+    id: u64,
+    organizer: Principal,
+    name: String,
+    // Date of the template the occurrences were generated from
+    first_date: u64,
+    schedule: Schedule,
+    occurrences: u32,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// When a series' occurrences take place, starting from the template event's date
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Schedule {
+    recurrence: Recurrence,
+    // Last date an occurrence may start at; Daily and Weekly need this or a count
+    until: Option<u64>,
+    count: Option<u32>,
+    // Occurrence dates to leave out, applied after count
+    exceptions: Vec<u64>,
 }
-impl BoundedStorable for Event {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum Recurrence {
+    // Every `interval` days
+    Daily {
+        interval: u32,
+    },
+    // On the given weekdays of every `interval`th week; the template's weekday if none
+    Weekly {
+        interval: u32,
+        weekdays: Vec<Weekday>,
+    },
+    // Exactly these dates
+    Custom {
+        dates: Vec<u64>,
+    },
 }
 
-// Implementing Storable and BoundedStorable for User
-impl Storable for User {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// One occurrence of a series with its availability
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SeriesOccurrence {
+    event_id: u64,
+    date: u64,
+    status: EventStatus,
+    price: u64,
+    total_tickets: u64,
+    tickets_sold: u64,
+    available_tickets: u64,
 }
-impl BoundedStorable for User {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+
+// The zones an event is divided into. Events without zones sell seats directly.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EventZones {
+    event_id: u64,
+    zones: Vec<EventZone>,
 }
 
-// Implementing Storable and BoundedStorable for LoyaltyPoints
-impl Storable for LoyaltyPoints {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EventZone {
+    name: String,
+    kind: ZoneKind,
+    capacity: u64,
+    tickets_sold: u64,
+    // Seat map sections a reserved zone sells; empty for general admission
+    sections: Vec<String>,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, Debug, PartialEq)]
+enum ZoneKind {
+    // Sold by capacity alone, without seats
+    #[default]
+    GeneralAdmission,
+    // Sold seat by seat from the event's seat map
+    Reserved,
 }
 
-impl BoundedStorable for LoyaltyPoints {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// The profile of a principal that organizes events and gets paid for their ticket sales
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Organizer {
+    id: u64,
+    owner: Principal,
+    name: String,
+    contact_email: Option<String>,
+    payout_account: Account,
+    created_at: u64,
+    // Where the organizer's sales are taxed, as configured with set_tax_rate
+    jurisdiction: Option<String>,
 }
 
-// Implementing Storable and BoundedStorable for EventSeating
-impl Storable for EventSeating {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// An ICRC-1 account: an owner and an optional 32-byte subaccount
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// What the platform owes an organizer in one currency, in its smallest unit
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct OrganizerLedger {
+    organizer_id: u64,
+    // Organizers have a ledger per token they sell in; None is the default currency
+    token: Option<String>,
+    // Price paid for every ticket sold for the organizer's events
+    gross_sales: u64,
+    // Refunds and resale royalties stay at zero until tickets can be refunded or resold
+    refunds: u64,
+    platform_fees: u64,
+    resale_royalties: u64,
+    // Earnings from events that have not completed yet
+    pending_balance: u64,
+    // Earnings from completed events, which request_payout transfers
+    settled_balance: u64,
+    paid_out: u64,
 }
 
-impl BoundedStorable for EventSeating {
-    const MAX_SIZE: u32 = 512;
-    const IS_FIXED_SIZE: bool = false;
+// A transfer of an organizer's settled balance to their payout account
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Payout {
+    id: u64,
+    organizer_id: u64,
+    token: Option<String>,
+    amount: u64,
+    to: Account,
+    requested_at: u64,
+    status: PayoutStatus,
 }
 
-// Memory management using thread_local
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum PayoutStatus {
+    // The transfer has been sent to the payment ledger but has not been answered yet
+    Pending,
+    Completed { block_index: candid::Nat },
+    // The amount went back to the organizer's settled balance
+    Failed { reason: String },
+}
 
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
+// Canister-wide settings, changed by controllers
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Settings {
+    // ICRC-1 ledger default-currency tickets are paid, and payouts made, on; None until set
+    payment_ledger: Option<Principal>,
+    service_fee: Option<ServiceFee>,
+}
 
-    static USERS_STORAGE: RefCell<StableBTreeMap<u64, User, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-        )
-    );
+// Endpoint notifications, such as email verification codes, are posted to as JSON. It
+// delivers them to users, for instance by email.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct NotificationHook {
+    url: String,
+}
 
-    static EVENTS_STORAGE: RefCell<StableBTreeMap<u64, Event, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
-        )
-    );
+// The platform's fee on each ticket, charged to the buyer on top of its price
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+struct ServiceFee {
+    flat: u64,
+    basis_points: u32,
+}
 
-    static TICKETS_STORAGE: RefCell<StableBTreeMap<u64, Ticket, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
-        )
-    );
+// Sales tax of a jurisdiction, such as "DE" or "US-CA"
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TaxRate {
+    id: u64,
+    jurisdiction: String,
+    basis_points: u32,
+    // Whether ticket prices already include the tax, rather than having it added
+    inclusive: bool,
+}
 
-    static LOYALTY_STORAGE: RefCell<StableBTreeMap<u64, LoyaltyPoints, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
-        )
-    );
+// An ICRC-1 token events can be priced in. Amounts of it are kept in its smallest
+// unit, 10^decimals of which make one token.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Token {
+    id: u64,
+    symbol: String,
+    ledger: Principal,
+    decimals: u8,
+    // Flat part of the service fee on tickets bought with the token
+    flat_fee: u64,
+    // Default-currency units one whole token counts as when earning loyalty points
+    loyalty_rate: u64,
+}
 
-    static SEATING_STORAGE: RefCell<StableBTreeMap<u64, EventSeating, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
-        )
-    );
+// Generic value of the ICRC-3 standard, which blocks of the ticket transaction log are made of
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum Icrc3Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(candid::Nat),
+    Int(candid::Int),
+    Array(Vec<Icrc3Value>),
+    Map(Vec<(String, Icrc3Value)>),
 }
 
-// Function to register a user
-#[ic_cdk::update]
-fn register_user(payload: RegisterUserPayload) -> Result<User, Message> {
-    if payload.username.is_empty() || payload.email.is_empty() {
-        return Err(Message::InvalidPayload(
-            "Missing required fields".to_string(),
-        ));
-    }
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct GetBlocksArgs {
+    start: candid::Nat,
+    length: candid::Nat,
+}
 
-    let user_id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Counter increment failed");
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct GetBlocksResult {
+    log_length: candid::Nat,
+    blocks: Vec<BlockWithId>,
+    // Always empty: every block is kept by this canister
+    archived_blocks: Vec<ArchivedBlocks>,
+}
 
-    let user = User {
-        id: user_id,
-        username: payload.username,
-        email: payload.email,
-    };
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BlockWithId {
+    id: candid::Nat,
+    block: Icrc3Value,
+}
 
-    USERS_STORAGE.with(|storage| {
-        storage.borrow_mut().insert(user_id, user.clone());
-    });
+candid::define_function!(GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
 
-    Ok(user)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ArchivedBlocks {
+    args: Vec<GetBlocksArgs>,
+    callback: GetBlocksCallback,
 }
 
-// Function to create an event
-#[ic_cdk::update]
-fn create_event(payload: CreateEventPayload) -> Result<Event, Message> {
-    if payload.name.is_empty()
-        || payload.location.is_empty()
-        || payload.date == 0
-        || payload.ticket_price == 0
-        || payload.total_tickets == 0
-    {
-        return Err(Message::InvalidPayload(
-            "Missing required fields".to_string(),
-        ));
-    }
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct GetArchivesArgs {
+    from: Option<Principal>,
+}
 
-    let event_id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Counter increment failed");
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ArchiveInfo {
+    canister_id: Principal,
+    start: candid::Nat,
+    end: candid::Nat,
+}
 
-    let event = Event {
-        id: event_id,
-        name: payload.name,
-        location: payload.location,
-        date: payload.date,
-        ticket_price: payload.ticket_price,
-        total_tickets: payload.total_tickets,
-        tickets_sold: 0,
-    };
+// The tip of the transaction log and the certificate vouching for it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DataCertificate {
+    certificate: Vec<u8>,
+    // CBOR-encoded tree with the last_block_index and last_block_hash leaves
+    hash_tree: Vec<u8>,
+}
 
-    EVENTS_STORAGE.with(|storage| {
-        storage.borrow_mut().insert(event_id, event.clone());
-    });
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SupportedBlockType {
+    block_type: String,
+    url: String,
+}
 
-    Ok(event)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SupportedStandard {
+    name: String,
+    url: String,
 }
 
-// Function to purchase a ticket
-#[ic_cdk::update]
-fn purchase_ticket(payload: PurchaseTicketPayload) -> Result<Ticket, Message> {
-    if payload.seat_number.is_empty() {
-        return Err(Message::InvalidPayload(
-            "Seat number is required".to_string(),
-        ));
+// Tree whose root hash is certified, in the form the IC's certificates use
+enum HashTree {
+    Empty,
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>),
+    // A subtree left out of a witness, standing in with its hash
+    Pruned([u8; 32]),
+}
+
+// A request from the IC's HTTP gateway
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    certificate_version: Option<u16>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    // Asks the gateway to send the request again as an update call to http_request_update
+    upgrade: Option<bool>,
+}
+
+// A response http_request can serve, with the hash of its body that is certified
+struct CachedResponse {
+    response: HttpResponse,
+    body_hash: [u8; 32],
+}
+
+// One call to an update endpoint, as kept in the audit log. Entries are never
+// changed or removed.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AuditEntry {
+    // Position in the log, in the order calls completed
+    id: u64,
+    caller: Principal,
+    timestamp: u64,
+    // Name of the endpoint called
+    operation: String,
+    // Kind of record the endpoint acts on
+    entity: AuditEntity,
+    // Ids of the records named in the call or created by it
+    target_ids: Vec<u64>,
+    outcome: AuditOutcome,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum AuditEntity {
+    User,
+    Event,
+    Series,
+    Venue,
+    Organizer,
+    Ticket,
+    Loyalty,
+    Settings,
+    TaxRate,
+    Token,
+    Payout,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum AuditOutcome {
+    Succeeded,
+    Failed { error: Error },
+}
+
+// Arguments and errors of the ICRC-1 icrc1_transfer method
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: candid::Nat,
+    fee: Option<candid::Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum TransferError {
+    BadFee {
+        expected_fee: candid::Nat,
+    },
+    BadBurn {
+        min_burn_amount: candid::Nat,
+    },
+    InsufficientFunds {
+        balance: candid::Nat,
+    },
+    TooOld,
+    CreatedInFuture {
+        ledger_time: u64,
+    },
+    Duplicate {
+        duplicate_of: candid::Nat,
+    },
+    TemporarilyUnavailable,
+    GenericError {
+        error_code: candid::Nat,
+        message: String,
+    },
+}
+
+// Arguments and errors of the ICRC-2 icrc2_transfer_from method
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: candid::Nat,
+    fee: Option<candid::Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum TransferFromError {
+    BadFee {
+        expected_fee: candid::Nat,
+    },
+    BadBurn {
+        min_burn_amount: candid::Nat,
+    },
+    InsufficientFunds {
+        balance: candid::Nat,
+    },
+    InsufficientAllowance {
+        allowance: candid::Nat,
+    },
+    TooOld,
+    CreatedInFuture {
+        ledger_time: u64,
+    },
+    Duplicate {
+        duplicate_of: candid::Nat,
+    },
+    TemporarilyUnavailable,
+    GenericError {
+        error_code: candid::Nat,
+        message: String,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+enum ReferralStatus {
+    #[default]
+    Pending,
+    Rewarded,
+    CapReached,
+}
+
+// Links a referred user to the user whose code they registered with
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Referral {
+    referrer_id: u64,
+    referred_id: u64,
+    created_at: u64,
+    status: ReferralStatus,
+    rewarded_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ReferralStats {
+    user_id: u64,
+    referral_code: String,
+    total_referrals: u64,
+    pending_referrals: u64,
+    rewarded_referrals: u64,
+    points_earned: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EarlyAccessPass {
+    user_id: u64,
+    valid_until: u64,
+    priority_level: u8,
+}
+
+// Layouts of records written before schema versioning, kept to decode old data
+#[derive(candid::CandidType, Deserialize)]
+struct EventV1 {
+    id: u64,
+    name: String,
+    location: String,
+    date: u64,
+    ticket_price: u64,
+    total_tickets: u64,
+    tickets_sold: u64,
+}
+
+impl From<EventV1> for Event {
+    fn from(event: EventV1) -> Self {
+        Event {
+            id: event.id,
+            name: event.name,
+            location: event.location,
+            date: event.date,
+            ticket_price: event.ticket_price,
+            total_tickets: event.total_tickets,
+            tickets_sold: event.tickets_sold,
+            pricing_mode: PricingMode::Fixed,
+            organizer: None,
+            category: None,
+            status: legacy_event_status(event.tickets_sold, event.total_tickets),
+            venue_id: None,
+            series_id: None,
+            organizer_id: None,
+            class_premiums: None,
+            token: None,
+            token_prices: None,
+        }
     }
+}
 
-    EVENTS_STORAGE.with(|events| {
-        let mut events = events.borrow_mut();
-        if let Some(event) = events.get(&payload.event_id) {
-            let mut updated_event = event.clone();
+// Event layout written before events had a status
+#[derive(candid::CandidType, Deserialize)]
+struct EventV2 {
+    id: u64,
+    name: String,
+    location: String,
+    date: u64,
+    ticket_price: u64,
+    total_tickets: u64,
+    tickets_sold: u64,
+    pricing_mode: PricingMode,
+    organizer: Option<Principal>,
+    category: Option<String>,
+}
 
-            if updated_event.tickets_sold >= updated_event.total_tickets {
-                return Err(Message::InsufficientTickets(
-                    "No tickets available".to_string(),
-                ));
-            }
+impl From<EventV2> for Event {
+    fn from(event: EventV2) -> Self {
+        Event {
+            id: event.id,
+            name: event.name,
+            location: event.location,
+            date: event.date,
+            ticket_price: event.ticket_price,
+            total_tickets: event.total_tickets,
+            tickets_sold: event.tickets_sold,
+            pricing_mode: event.pricing_mode,
+            organizer: event.organizer,
+            category: event.category,
+            status: legacy_event_status(event.tickets_sold, event.total_tickets),
+            venue_id: None,
+            series_id: None,
+            organizer_id: None,
+            class_premiums: None,
+            token: None,
+            token_prices: None,
+        }
+    }
+}
 
-            let ticket_id = ID_COUNTER
-                .with(|counter| {
-                    let current_value = *counter.borrow().get();
-                    counter.borrow_mut().set(current_value + 1)
-                })
-                .expect("Counter increment failed");
+// EventDetails layout written before media, contact, times, time zone and age limits
+#[derive(candid::CandidType, Deserialize)]
+struct EventDetailsV1 {
+    event_id: u64,
+    description: String,
+    tags: Vec<String>,
+}
 
-            let ticket = Ticket {
-                id: ticket_id,
-                event_id: payload.event_id,
-                user_id: payload.user_id,
-                purchase_date: time(),
-                seat_number: payload.seat_number,
-                price: updated_event.ticket_price,
-            };
+impl From<EventDetailsV1> for EventDetails {
+    fn from(details: EventDetailsV1) -> Self {
+        EventDetails {
+            event_id: details.event_id,
+            description: details.description,
+            tags: details.tags,
+            ..Default::default()
+        }
+    }
+}
 
-            updated_event.tickets_sold += 1;
-            events.insert(payload.event_id, updated_event);
+// Events from before statuses existed were on sale as soon as they were created
+fn legacy_event_status(tickets_sold: u64, total_tickets: u64) -> EventStatus {
+    if tickets_sold >= total_tickets {
+        EventStatus::SoldOut
+    } else {
+        EventStatus::OnSale
+    }
+}
 
-            TICKETS_STORAGE.with(|tickets| {
-                tickets.borrow_mut().insert(ticket_id, ticket.clone());
-            });
+#[derive(candid::CandidType, Deserialize)]
+struct UserV1 {
+    id: u64,
+    username: String,
+    email: String,
+}
 
-            Ok(ticket)
-        } else {
-            Err(Message::NotFound("Event not found".to_string()))
+impl From<UserV1> for User {
+    fn from(user: UserV1) -> Self {
+        User {
+            id: user.id,
+            // The registering principal was not recorded, so nobody owns legacy accounts
+            // until a controller assigns one with assign_owner
+            owner: Principal::anonymous(),
+            username: user.username,
+            email: user.email,
+            referral_code: referral_code_for(user.id),
+            referred_by: None,
+            email_verified_at: None,
         }
-    })
+    }
 }
 
-// Function to list all events
-#[ic_cdk::query]
-fn list_all_events() -> Vec<Event> {
-    EVENTS_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .map(|(_, event)| event.clone())
-            .collect()
-    })
+// Payloads for API
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RegisterUserPayload {
+    username: String,
+    email: String,
+    referral_code: Option<String>,
 }
 
-// Function to list tickets for a user
-#[ic_cdk::query]
-fn list_tickets_for_user(user_id: u64) -> Vec<Ticket> {
-    TICKETS_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .filter(|(_, ticket)| ticket.user_id == user_id)
-            .map(|(_, ticket)| ticket.clone())
-            .collect()
-    })
+// Fields left out keep their current value
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct UpdateProfilePayload {
+    username: Option<String>,
+    email: Option<String>,
 }
 
-// Function to award points for ticket purchase
-#[ic_cdk::update]
-fn award_loyalty_points(user_id: u64, purchase_amount: u64) -> Result<LoyaltyPoints, Message> {
-    let points_earned = calculate_points(purchase_amount);
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+// With a venue_id, an empty location defaults to the venue's name and address, a zero
+// total_tickets to its capacity, and the event gets a copy of the venue's seat map
+struct CreateEventPayload {
+    name: String,
+    location: String,
+    date: u64,
+    ticket_price: u64,
+    total_tickets: u64,
+    venue_id: Option<u64>,
+    pricing_mode: Option<PricingMode>,
+    category: Option<String>,
+    description: Option<String>,
+    tags: Option<Vec<String>>,
+    media_urls: Option<Vec<String>>,
+    organizer_contact: Option<String>,
+    doors_open: Option<u64>,
+    end_time: Option<u64>,
+    timezone: Option<String>,
+    minimum_age: Option<u8>,
+    class_premiums: Option<Vec<ClassPremium>>,
+    token: Option<String>,
+    token_prices: Option<Vec<TokenPrice>>,
+}
 
-    LOYALTY_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        let mut loyalty = storage.get(&user_id).unwrap_or_default();
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct CreateVenuePayload {
+    name: String,
+    address: String,
+    coordinates: Option<GeoPoint>,
+    capacity: u64,
+    sections: Vec<VenueSection>,
+}
 
-        loyalty.points += points_earned;
-        loyalty.points_history.push(PointsTransaction {
-            timestamp: time(),
-            points: points_earned as i64,
-            description: format!("Points earned from purchase: {}", purchase_amount),
-        });
+// The payout account defaults to the caller's default account
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct OrganizerPayload {
+    name: String,
+    contact_email: Option<String>,
+    payout_account: Option<Account>,
+    jurisdiction: Option<String>,
+}
 
-        // Update tier based on total points
-        loyalty.tier = match loyalty.points {
-            points if points >= 10000 => LoyaltyTier::Platinum,
-            points if points >= 5000 => LoyaltyTier::Gold,
-            points if points >= 2000 => LoyaltyTier::Silver,
-            _ => LoyaltyTier::Bronze,
-        };
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TokenPayload {
+    symbol: String,
+    ledger: Principal,
+    decimals: u8,
+    flat_fee: u64,
+    loyalty_rate: u64,
+}
 
-        storage.insert(user_id, loyalty.clone());
-        Ok(loyalty)
-    })
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct TaxRatePayload {
+    jurisdiction: String,
+    basis_points: u32,
+    inclusive: bool,
 }
 
-// Function to redeem points for rewards
-#[ic_cdk::update]
-fn redeem_points(user_id: u64, points_to_redeem: u64) -> Result<String, Message> {
-    LOYALTY_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        if let Some(mut loyalty) = storage.get(&user_id) {
-            if loyalty.points >= points_to_redeem {
-                loyalty.points -= points_to_redeem;
-                loyalty.points_history.push(PointsTransaction {
-                    timestamp: time(),
-                    points: -(points_to_redeem as i64),
-                    description: "Points redemption".to_string(),
-                });
+// Fields left as None are not changed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct UpdateEventSeatingPayload {
+    sections: Option<Vec<VenueSection>>,
+    blocked_seats: Option<Vec<String>>,
+}
 
-                storage.insert(user_id, loyalty);
-                Ok("Points successfully redeemed!".to_string())
-            } else {
-                Err(Message::Error("Insufficient points".to_string()))
-            }
-        } else {
-            Err(Message::NotFound(
-                "User loyalty account not found".to_string(),
-            ))
-        }
-    })
+// Fields left as None are not changed. An empty organizer_contact, timezone, token,
+// class_premiums or token_prices, a zero doors_open or end_time, and a zero minimum_age
+// clear the value.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct UpdateEventPayload {
+    name: Option<String>,
+    location: Option<String>,
+    date: Option<u64>,
+    ticket_price: Option<u64>,
+    total_tickets: Option<u64>,
+    pricing_mode: Option<PricingMode>,
+    category: Option<String>,
+    description: Option<String>,
+    tags: Option<Vec<String>>,
+    media_urls: Option<Vec<String>>,
+    organizer_contact: Option<String>,
+    doors_open: Option<u64>,
+    end_time: Option<u64>,
+    timezone: Option<String>,
+    minimum_age: Option<u8>,
+    class_premiums: Option<Vec<ClassPremium>>,
+    token: Option<String>,
+    token_prices: Option<Vec<TokenPrice>>,
 }
 
-// Modified ticket purchase function to include dynamic pricing
-#[ic_cdk::update]
-fn purchase_ticket_with_dynamic_pricing(payload: PurchaseTicketPayload) -> Result<Ticket, Message> {
-    EVENTS_STORAGE.with(|events| {
-        let mut events = events.borrow_mut();
-        if let Some(event) = events.get(&payload.event_id) {
-            let mut updated_event = event.clone();
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+enum EventSortOrder {
+    #[default]
+    DateAscending,
+    DateDescending,
+    PriceAscending,
+    PriceDescending,
+    // Most tickets sold first
+    Popularity,
+}
 
-            // Calculate dynamic price based on demand
-            let demand_multiplier =
-                (updated_event.tickets_sold as f64 / updated_event.total_tickets as f64) + 0.5;
-            let dynamic_price = (updated_event.ticket_price as f64 * demand_multiplier) as u64;
-
-            // Apply loyalty discount if applicable
-            let final_price = LOYALTY_STORAGE.with(|storage| {
-                if let Some(loyalty) = storage.borrow().get(&payload.user_id) {
-                    match loyalty.tier {
-                        LoyaltyTier::Platinum => dynamic_price * 80 / 100, // 20% discount
-                        LoyaltyTier::Gold => dynamic_price * 85 / 100,     // 15% discount
-                        LoyaltyTier::Silver => dynamic_price * 90 / 100,   // 10% discount
-                        LoyaltyTier::Bronze => dynamic_price * 95 / 100,   // 5% discount
-                    }
-                } else {
-                    dynamic_price
-                }
-            });
+// Every filter is optional; events must match all of the ones given
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SearchEventsPayload {
+    date_from: Option<u64>,
+    date_to: Option<u64>,
+    // Matches locations starting with this text, ignoring case
+    location: Option<String>,
+    min_price: Option<u64>,
+    max_price: Option<u64>,
+    has_availability: Option<bool>,
+    organizer: Option<Principal>,
+    category: Option<String>,
+    // Without a status filter, Draft and Archived events are left out
+    status: Option<EventStatus>,
+    tag: Option<String>,
+    // Only events this age may attend
+    audience_age: Option<u8>,
+    sort: Option<EventSortOrder>,
+    // next_cursor of the previous page
+    cursor: Option<String>,
+    limit: Option<u32>,
+}
 
-            // Create ticket with dynamic price
-            let ticket_id = ID_COUNTER
-                .with(|counter| {
-                    let current_value = *counter.borrow().get();
-                    counter.borrow_mut().set(current_value + 1)
-                })
-                .expect("Counter increment failed");
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EventPage {
+    // Can be fewer than the limit, even with a next_cursor, when search_events stopped at
+    // its scan limit
+    events: Vec<EventListing>,
+    // Pass back as the cursor to fetch the next page; None on the last page
+    next_cursor: Option<String>,
+    // Set when more events matched the index lookup than search_events sorts, so some
+    // were left out; narrow the date range or location to see them
+    truncated: bool,
+}
 
-            let ticket = Ticket {
-                id: ticket_id,
-                event_id: payload.event_id,
-                user_id: payload.user_id,
-                purchase_date: time(),
-                seat_number: payload.seat_number,
-                price: final_price,
-            };
+// Filters for list_audit_log; every filter given must match
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AuditLogQuery {
+    actor: Option<Principal>,
+    entity: Option<AuditEntity>,
+    // Only calls naming or creating this record
+    target_id: Option<u64>,
+    // Endpoint name, such as "purchase_ticket"
+    operation: Option<String>,
+    // next_cursor of the previous page
+    cursor: Option<u64>,
+    limit: Option<u32>,
+}
 
-            updated_event.tickets_sold += 1;
-            events.insert(payload.event_id, updated_event);
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AuditPage {
+    // Newest first
+    entries: Vec<AuditEntry>,
+    // Pass back as the cursor to fetch older entries; None on the last page. A page cut
+    // short by the scan limit can have fewer entries than asked for, or none, and a cursor.
+    next_cursor: Option<u64>,
+    // Calls that could not be added to the log since the canister last started
+    unrecorded_calls: u64,
+}
 
-            // Award loyalty points for purchase
-            let _ = award_loyalty_points(payload.user_id, final_price);
+// Venues and events to create in one go. Events can be placed at a venue of the same
+// import by its position in venues.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+struct ImportPayload {
+    venues: Vec<CreateVenuePayload>,
+    events: Vec<ImportEventRow>,
+}
 
-            TICKETS_STORAGE.with(|tickets| {
-                tickets.borrow_mut().insert(ticket_id, ticket.clone());
-            });
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ImportEventRow {
+    event: CreateEventPayload,
+    // Position in the import's venues of the event's venue, instead of event.venue_id
+    venue_row: Option<u32>,
+    // Changes to the seat map the event gets from its venue, or a seat map of its own
+    seating: Option<UpdateEventSeatingPayload>,
+}
 
-            Ok(ticket)
-        } else {
-            Err(Message::NotFound("Event not found".to_string()))
-        }
-    })
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ImportSummary {
+    // Ids of what was created, in the order of the import's rows
+    venue_ids: Vec<u64>,
+    event_ids: Vec<u64>,
 }
 
-// Helper function to calculate points
-fn calculate_points(purchase_amount: u64) -> u64 {
-    // Base rate: 1 point per 10 units spent
-    let base_points = purchase_amount / 10;
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+struct ImportRowError {
+    table: ImportTable,
+    row: u32,
+    error: Error,
+}
 
-    // Bonus points for larger purchases
-    let bonus_points = match purchase_amount {
-        amount if amount >= 1000 => base_points / 2, // 50% bonus
-        amount if amount >= 500 => base_points / 4,  // 25% bonus
-        amount if amount >= 200 => base_points / 10, // 10% bonus
-        _ => 0,
-    };
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Debug)]
+enum ImportTable {
+    Venues,
+    Events,
+}
 
-    base_points + bonus_points
+// An event that passed validation, with the records to write for it. Ids are only
+// given out when it is written.
+struct NewEvent {
+    event: Event,
+    details: EventDetails,
+    seat_map: Option<EventSeatMap>,
 }
 
-// Exporting the candid interface
-ic_cdk::export_candid!();
+// An order that passed validation and was priced, with nothing written for it yet
+struct PreparedOrder {
+    user_id: u64,
+    event: Event,
+    // Each ticket's request, zone and price
+    tickets: Vec<(PurchaseTicketPayload, Option<String>, PriceBreakdown)>,
+}
+
+// What an order collects from its buyer through ICRC-2, and where it is paid into
+struct Payment {
+    ledger: Principal,
+    from: Account,
+    // Subaccount of the canister's account on the ledger
+    to_subaccount: Option<Vec<u8>>,
+    amount: u64,
+    // Sent as the transfer memo, so the ledger never takes the same payment twice
+    memo: u64,
+}
+
+// A new event with its id given out and its records encoded, so writing it cannot fail
+struct EncodedEvent {
+    event: Event,
+    details: EventDetails,
+    stored_event: Stored<Event>,
+    stored_details: Stored<EventDetails>,
+    stored_seat_map: Option<Stored<EventSeatMap>>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum ExportFormat {
+    Csv,
+    Json,
+}
+
+// Fields an export can leave out for privacy
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+enum ExportField {
+    Username,
+    Email,
+    // The seat and zone
+    Seat,
+    // The price and, in the sales ledger, every amount of its breakdown
+    Price,
+    PurchaseDate,
+    CheckIn,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ExportOptions {
+    format: ExportFormat,
+    // Fields left empty in every row: blank in CSV, null in JSON
+    redact: Option<Vec<ExportField>>,
+    // next_cursor of the previous chunk
+    cursor: Option<u64>,
+    limit: Option<u32>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ExportChunk {
+    // CSV lines, with the header line in the first chunk only, or a JSON array of objects
+    data: String,
+    // Pass back as the cursor to fetch the next chunk; None on the last chunk
+    next_cursor: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PurchaseTicketPayload {
+    event_id: u64,
+    user_id: u64,
+    // Left empty for general-admission zones
+    seat_number: String,
+    // Takes the place of seat_number when given
+    seat: Option<SeatId>,
+    // Required for general admission; reserved seats imply their zone
+    zone: Option<String>,
+    // Token to pay with; the event's own token if left out
+    token: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct BestAvailablePayload {
+    event_id: u64,
+    user_id: u64,
+    seat_class: SeatClass,
+    quantity: u32,
+    // Whether the seats may be spread out when no row has enough adjacent seats
+    allow_split: Option<bool>,
+    // Reserved zone to pick seats from, on events divided into zones
+    zone: Option<String>,
+    // Token to pay with; the event's own token if left out
+    token: Option<String>,
+}
+
+// The template event's date is the schedule's starting point
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CreateEventSeriesPayload {
+    event: CreateEventPayload,
+    schedule: Schedule,
+}
+
+// A zone as declared by the organizer. General-admission zones give their capacity;
+// a reserved zone's capacity is the number of seats in its sections.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EventZonePayload {
+    name: String,
+    kind: ZoneKind,
+    capacity: Option<u64>,
+    sections: Vec<String>,
+}
+
+// Version of the public API; bumped whenever the shape of responses changes
+// (2: endpoints return the structured `Error` instead of `Message`)
+const API_VERSION: u32 = 2;
+
+// Roles an endpoint may require from its caller
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+enum Role {
+    // A controller of the canister
+    Admin,
+    // The principal that registered the user account being acted on
+    AccountOwner,
+    // The principal that created the event, or owns the organizer profile, being acted on
+    Organizer,
+    // The principal that created the venue being acted on
+    VenueOwner,
+}
+
+// Error enum returned by every endpoint
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum Error {
+    UserNotFound {
+        user_id: u64,
+    },
+    EventNotFound {
+        event_id: u64,
+    },
+    LoyaltyAccountNotFound {
+        user_id: u64,
+    },
+    SoldOut {
+        event_id: u64,
+    },
+    SeatTaken {
+        seat: String,
+    },
+    UnknownSeat {
+        seat: String,
+    },
+    InsufficientPoints {
+        have: u64,
+        need: u64,
+    },
+    InvalidReferralCode {
+        code: String,
+    },
+    SelfReferral,
+    MissingField {
+        field: String,
+    },
+    InvalidField {
+        field: String,
+        reason: String,
+    },
+    FieldTooLong {
+        field: String,
+        max_length: u32,
+    },
+    Unauthorized {
+        required_role: Role,
+    },
+    RecordTooLarge {
+        record: String,
+        size: u64,
+        max_size: u32,
+    },
+    CorruptedRecord {
+        record: String,
+    },
+    StorageFailure {
+        reason: String,
+    },
+    InvalidEventStatus {
+        event_id: u64,
+        status: EventStatus,
+    },
+    InvalidStatusTransition {
+        from: EventStatus,
+        to: EventStatus,
+    },
+    FieldLocked {
+        field: String,
+    },
+    VenueNotFound {
+        venue_id: u64,
+    },
+    SeatNotForSale {
+        seat: String,
+    },
+    InsufficientSeats {
+        requested: u32,
+        available: u64,
+    },
+    NoAdjacentSeats {
+        requested: u32,
+    },
+    UnknownZone {
+        zone: String,
+    },
+    SeriesNotFound {
+        series_id: u64,
+    },
+    ZoneSoldOut {
+        zone: String,
+    },
+    OrganizerNotFound {
+        organizer_id: u64,
+    },
+    NoSettledBalance {
+        organizer_id: u64,
+    },
+    PayoutFailed {
+        reason: String,
+    },
+    TaxRateNotFound {
+        jurisdiction: String,
+    },
+    UnknownToken {
+        token: String,
+    },
+    TicketNotFound {
+        ticket_id: u64,
+    },
+    TicketCheckedIn {
+        ticket_id: u64,
+    },
+    TokenNotAccepted {
+        token: String,
+    },
+    ImportRejected {
+        errors: Vec<ImportRowError>,
+    },
+    EmailTaken {
+        email: String,
+    },
+    EmailNotVerified {
+        user_id: u64,
+    },
+    InvalidVerificationCode,
+    NotificationFailed {
+        reason: String,
+    },
+    UsernameTaken {
+        username: String,
+    },
+    UsernameNotFound {
+        username: String,
+    },
+    PaymentFailed {
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::UserNotFound { user_id } => write!(f, "User {} not found", user_id),
+            Error::EventNotFound { event_id } => write!(f, "Event {} not found", event_id),
+            Error::LoyaltyAccountNotFound { user_id } => {
+                write!(f, "User loyalty account {} not found", user_id)
+            }
+            Error::SoldOut { event_id } => write!(f, "No tickets available for event {}", event_id),
+            Error::SeatTaken { seat } => write!(f, "Seat {} is already taken", seat),
+            Error::UnknownSeat { seat } => write!(f, "Seat {} does not exist for this event", seat),
+            Error::InsufficientPoints { have, need } => {
+                write!(f, "Insufficient points: have {}, need {}", have, need)
+            }
+            Error::InvalidReferralCode { code } => write!(f, "Invalid referral code {}", code),
+            Error::SelfReferral => write!(f, "Self-referral is not allowed"),
+            Error::MissingField { field } => write!(f, "Missing required field {}", field),
+            Error::InvalidField { field, reason } => write!(f, "Invalid {}: {}", field, reason),
+            Error::FieldTooLong { field, max_length } => {
+                write!(f, "{} must be at most {} bytes", field, max_length)
+            }
+            Error::Unauthorized { required_role } => {
+                write!(f, "Caller does not have the {:?} role", required_role)
+            }
+            Error::RecordTooLarge {
+                record,
+                size,
+                max_size,
+            } => write!(
+                f,
+                "{} record is {} bytes, larger than the {} bytes it may take",
+                record, size, max_size
+            ),
+            Error::CorruptedRecord { record } => write!(f, "Stored {} record is corrupted", record),
+            Error::StorageFailure { reason } => write!(f, "Storage error: {}", reason),
+            Error::InvalidEventStatus { event_id, status } => {
+                write!(f, "Event {} is {:?}", event_id, status)
+            }
+            Error::InvalidStatusTransition { from, to } => {
+                write!(f, "An event cannot go from {:?} to {:?}", from, to)
+            }
+            Error::FieldLocked { field } => {
+                write!(
+                    f,
+                    "{} cannot be changed once ticket sales have started",
+                    field
+                )
+            }
+            Error::VenueNotFound { venue_id } => write!(f, "Venue {} not found", venue_id),
+            Error::SeatNotForSale { seat } => write!(f, "Seat {} is not for sale", seat),
+            Error::InsufficientSeats {
+                requested,
+                available,
+            } => write!(
+                f,
+                "{} seats requested but only {} are available",
+                requested, available
+            ),
+            Error::NoAdjacentSeats { requested } => {
+                write!(f, "No row has {} adjacent seats available", requested)
+            }
+            Error::UnknownZone { zone } => write!(f, "Zone {} does not exist for this event", zone),
+            Error::SeriesNotFound { series_id } => {
+                write!(f, "Event series {} not found", series_id)
+            }
+            Error::ZoneSoldOut { zone } => write!(f, "No tickets available in zone {}", zone),
+            Error::OrganizerNotFound { organizer_id } => {
+                write!(f, "Organizer {} not found", organizer_id)
+            }
+            Error::NoSettledBalance { organizer_id } => {
+                write!(
+                    f,
+                    "Organizer {} has no settled balance to pay out",
+                    organizer_id
+                )
+            }
+            Error::PayoutFailed { reason } => write!(f, "Payout failed: {}", reason),
+            Error::TaxRateNotFound { jurisdiction } => {
+                write!(f, "No tax rate is set for {}", jurisdiction)
+            }
+            Error::UnknownToken { token } => write!(f, "Token {} is not registered", token),
+            Error::TicketNotFound { ticket_id } => {
+                write!(f, "Ticket with id={} not found", ticket_id)
+            }
+            Error::TicketCheckedIn { ticket_id } => {
+                write!(f, "Ticket {} has already been checked in", ticket_id)
+            }
+            Error::TokenNotAccepted { token } => {
+                write!(f, "This event does not accept {}", token)
+            }
+            Error::ImportRejected { errors } => {
+                write!(f, "Nothing was imported: {} rows are invalid", errors.len())
+            }
+            Error::EmailTaken { email } => write!(f, "Email {} is already registered", email),
+            Error::EmailNotVerified { user_id } => write!(
+                f,
+                "User {} must confirm their email address before buying tickets",
+                user_id
+            ),
+            Error::InvalidVerificationCode => {
+                write!(f, "The verification code is wrong or has expired")
+            }
+            Error::NotificationFailed { reason } => {
+                write!(f, "Cannot send the notification: {}", reason)
+            }
+            Error::UsernameTaken { username } => {
+                write!(f, "Username {} is already taken", username)
+            }
+            Error::UsernameNotFound { username } => {
+                write!(f, "No user is called {}", username)
+            }
+            Error::PaymentFailed { reason } => write!(f, "Payment failed: {}", reason),
+        }
+    }
+}
+
+// Message enum, the error type of API version 1. Kept so clients that still
+// match on it can translate the structured errors via `to_legacy_message`.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum Message {
+    Success(String),
+    Error(String),
+    NotFound(String),
+    InvalidPayload(String),
+    InsufficientTickets(String),
+}
+
+impl From<Error> for Message {
+    fn from(error: Error) -> Self {
+        let text = error.to_string();
+        match error {
+            Error::UserNotFound { .. }
+            | Error::EventNotFound { .. }
+            | Error::LoyaltyAccountNotFound { .. }
+            | Error::VenueNotFound { .. }
+            | Error::SeriesNotFound { .. }
+            | Error::OrganizerNotFound { .. }
+            | Error::TaxRateNotFound { .. }
+            | Error::TicketNotFound { .. }
+            | Error::UsernameNotFound { .. } => Message::NotFound(text),
+            Error::SoldOut { .. }
+            | Error::InsufficientSeats { .. }
+            | Error::NoAdjacentSeats { .. }
+            | Error::ZoneSoldOut { .. } => Message::InsufficientTickets(text),
+            Error::UnknownSeat { .. }
+            | Error::UnknownZone { .. }
+            | Error::UnknownToken { .. }
+            | Error::TokenNotAccepted { .. }
+            | Error::InvalidReferralCode { .. }
+            | Error::SelfReferral
+            | Error::MissingField { .. }
+            | Error::InvalidField { .. }
+            | Error::FieldTooLong { .. }
+            | Error::FieldLocked { .. }
+            | Error::ImportRejected { .. }
+            | Error::EmailTaken { .. }
+            | Error::UsernameTaken { .. }
+            | Error::InvalidVerificationCode => Message::InvalidPayload(text),
+            Error::SeatTaken { .. }
+            | Error::SeatNotForSale { .. }
+            | Error::InsufficientPoints { .. }
+            | Error::Unauthorized { .. }
+            | Error::RecordTooLarge { .. }
+            | Error::CorruptedRecord { .. }
+            | Error::StorageFailure { .. }
+            | Error::InvalidEventStatus { .. }
+            | Error::InvalidStatusTransition { .. }
+            | Error::NoSettledBalance { .. }
+            | Error::TicketCheckedIn { .. }
+            | Error::EmailNotVerified { .. }
+            | Error::NotificationFailed { .. }
+            | Error::PayoutFailed { .. }
+            | Error::PaymentFailed { .. } => Message::Error(text),
+        }
+    }
+}
+
+// Implemented by every type kept in stable memory
+trait Record: candid::CandidType + for<'de> serde::Deserialize<'de> {
+    // Name reported in storage errors
+    const NAME: &'static str;
+    // Layout version written by this build
+    const VERSION: u8;
+    // Largest encoded size, envelope included. Must not change for maps that already
+    // exist in stable memory; a record that outgrows its bound needs a new map.
+    const MAX_SIZE: u32;
+
+    // Decodes a payload written with the given layout version
+    fn decode_version(_version: u8, payload: &[u8]) -> Result<Self, candid::Error> {
+        Decode!(payload, Self)
+    }
+}
+
+// A record as kept in stable memory: its versioned envelope, only decoded on access
+// so that an oversized or corrupted value is reported as an error instead of trapping
+struct Stored<T> {
+    bytes: Vec<u8>,
+    record: PhantomData<T>,
+}
+
+impl<T: Record> Stored<T> {
+    // Encodes a record, rejecting it if it would not fit in its map
+    fn encode(record: &T) -> Result<Self, Error> {
+        let payload = Encode!(record).map_err(|err| Error::StorageFailure {
+            reason: format!("Cannot encode {} record: {}", T::NAME, err),
+        })?;
+
+        let mut bytes = Vec::with_capacity(payload.len() + 2);
+        bytes.extend([RECORD_MAGIC, T::VERSION]);
+        bytes.extend(payload);
+
+        if bytes.len() > T::MAX_SIZE as usize {
+            return Err(Error::RecordTooLarge {
+                record: T::NAME.to_string(),
+                size: bytes.len() as u64,
+                max_size: T::MAX_SIZE,
+            });
+        }
+
+        Ok(Stored {
+            bytes,
+            record: PhantomData,
+        })
+    }
+
+    fn decode(&self) -> Result<T, Error> {
+        let (version, payload) = split_record(&self.bytes);
+        T::decode_version(version, payload).map_err(|_| Error::CorruptedRecord {
+            record: T::NAME.to_string(),
+        })
+    }
+}
+
+impl<T> Storable for Stored<T> {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Stored {
+            bytes: bytes.into_owned(),
+            record: PhantomData,
+        }
+    }
+}
+
+impl<T: Record> BoundedStorable for Stored<T> {
+    const MAX_SIZE: u32 = T::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Helper function to split a stored record into its layout version and Candid payload
+fn split_record(bytes: &[u8]) -> (u8, &[u8]) {
+    match bytes {
+        [RECORD_MAGIC, version, payload @ ..] => (*version, payload),
+        _ => (LEGACY_RECORD_VERSION, bytes),
+    }
+}
+
+// Implementing Record for Ticket
+impl Record for Ticket {
+    const NAME: &'static str = "Ticket";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 512;
+}
+
+// Implementing Record for Event
+impl Record for Event {
+    const NAME: &'static str = "Event";
+    const VERSION: u8 = 3;
+    const MAX_SIZE: u32 = 1024;
+
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, candid::Error> {
+        match version {
+            LEGACY_RECORD_VERSION => Decode!(payload, EventV1).map(Event::from),
+            2 => Decode!(payload, EventV2).map(Event::from),
+            _ => Decode!(payload, Self),
+        }
+    }
+}
+
+// Implementing Record for User
+impl Record for User {
+    const NAME: &'static str = "User";
+    const VERSION: u8 = 2;
+    const MAX_SIZE: u32 = 512;
+
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, candid::Error> {
+        match version {
+            LEGACY_RECORD_VERSION => Decode!(payload, UserV1).map(User::from),
+            _ => Decode!(payload, Self),
+        }
+    }
+}
+
+// Implementing Record for LoyaltyPoints
+impl Record for LoyaltyPoints {
+    const NAME: &'static str = "LoyaltyPoints";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 512;
+}
+
+// Implementing Record for PointsTransaction
+impl Record for PointsTransaction {
+    const NAME: &'static str = "PointsTransaction";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 256;
+}
+
+// Implementing Record for EventDetails
+impl Record for EventDetails {
+    const NAME: &'static str = "EventDetails";
+    const VERSION: u8 = 2;
+    const MAX_SIZE: u32 = 4096;
+
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, candid::Error> {
+        match version {
+            1 => Decode!(payload, EventDetailsV1).map(EventDetails::from),
+            _ => Decode!(payload, Self),
+        }
+    }
+}
+
+// Implementing Record for EventSeating
+impl Record for EventSeating {
+    const NAME: &'static str = "EventSeating";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 512;
+}
+
+// Implementing Record for Venue
+impl Record for Venue {
+    const NAME: &'static str = "Venue";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 32 * 1024;
+}
+
+// Implementing Record for EventSeatMap
+impl Record for EventSeatMap {
+    const NAME: &'static str = "EventSeatMap";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 40 * 1024;
+}
+
+// Implementing Record for EventSeries
+impl Record for EventSeries {
+    const NAME: &'static str = "EventSeries";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 4096;
+}
+
+// Implementing Record for EventZones
+impl Record for EventZones {
+    const NAME: &'static str = "EventZones";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 4096;
+}
+
+// Implementing Record for Organizer
+impl Record for Organizer {
+    const NAME: &'static str = "Organizer";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 1024;
+}
+
+// Implementing Record for OrganizerLedger
+impl Record for OrganizerLedger {
+    const NAME: &'static str = "OrganizerLedger";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 256;
+}
+
+// Implementing Record for Payout
+impl Record for Payout {
+    const NAME: &'static str = "Payout";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 1024;
+}
+
+// Implementing Record for Settings
+impl Record for Settings {
+    const NAME: &'static str = "Settings";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 256;
+}
+
+// Implementing Record for NotificationHook
+impl Record for NotificationHook {
+    const NAME: &'static str = "NotificationHook";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 512;
+}
+
+// Implementing Record for EmailVerification
+impl Record for EmailVerification {
+    const NAME: &'static str = "EmailVerification";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 512;
+}
+
+// Implementing Record for Icrc3Value, the blocks of the ticket transaction log
+impl Record for Icrc3Value {
+    const NAME: &'static str = "Block";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 1024;
+}
+
+// Implementing Record for EventSales
+impl Record for EventSales {
+    const NAME: &'static str = "EventSales";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 2048;
+}
+
+// Implementing Record for AuditEntry
+impl Record for AuditEntry {
+    const NAME: &'static str = "AuditEntry";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 4096;
+}
+
+// Implementing Record for Token
+impl Record for Token {
+    const NAME: &'static str = "Token";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 256;
+}
+
+// Implementing Record for TaxRate
+impl Record for TaxRate {
+    const NAME: &'static str = "TaxRate";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 256;
+}
+
+// Implementing Record for Referral
+impl Record for Referral {
+    const NAME: &'static str = "Referral";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 256;
+}
+
+// Implementing Record for ReferralStats
+impl Record for ReferralStats {
+    const NAME: &'static str = "ReferralStats";
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 256;
+}
+
+// Key of a secondary index: the id records are looked up by, then the record's own id,
+// so all entries for one parent form a contiguous range
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct IndexKey {
+    parent_id: u64,
+    record_id: u64,
+}
+
+impl IndexKey {
+    // Range covering every entry of the given parent
+    fn range_of(parent_id: u64) -> std::ops::RangeInclusive<IndexKey> {
+        Self::range_from(parent_id, 0)
+    }
+
+    // Range covering the entries of the given parent from a record id on
+    fn range_from(parent_id: u64, record_id: u64) -> std::ops::RangeInclusive<IndexKey> {
+        IndexKey {
+            parent_id,
+            record_id,
+        }..=IndexKey {
+            parent_id,
+            record_id: u64::MAX,
+        }
+    }
+}
+
+impl Storable for IndexKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend(self.parent_id.to_be_bytes());
+        bytes.extend(self.record_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut parent_id = [0; 8];
+        let mut record_id = [0; 8];
+        parent_id.copy_from_slice(&bytes[..8]);
+        record_id.copy_from_slice(&bytes[8..16]);
+        IndexKey {
+            parent_id: u64::from_be_bytes(parent_id),
+            record_id: u64::from_be_bytes(record_id),
+        }
+    }
+}
+
+impl BoundedStorable for IndexKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// Key of a secondary index on normalized text, followed by the record's id
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct TextIndexKey {
+    text: String,
+    record_id: u64,
+}
+
+impl Storable for TextIndexKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(self.text.len() + 8);
+        bytes.extend(self.text.as_bytes());
+        bytes.extend(self.record_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let split = bytes.len().saturating_sub(8);
+        let mut record_id = [0; 8];
+        record_id.copy_from_slice(&bytes[split..]);
+        TextIndexKey {
+            text: String::from_utf8_lossy(&bytes[..split]).into_owned(),
+            record_id: u64::from_be_bytes(record_id),
+        }
+    }
+}
+
+impl BoundedStorable for TextIndexKey {
+    const MAX_SIZE: u32 = MAX_INDEX_TEXT_LENGTH + 8;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Key of the index of taken seats: an event's id followed by a seat label
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct SeatKey {
+    event_id: u64,
+    seat: String,
+}
+
+impl Storable for SeatKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(8 + self.seat.len());
+        bytes.extend(self.event_id.to_be_bytes());
+        bytes.extend(self.seat.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut event_id = [0; 8];
+        event_id.copy_from_slice(&bytes[..8]);
+        SeatKey {
+            event_id: u64::from_be_bytes(event_id),
+            seat: String::from_utf8_lossy(&bytes[8..]).into_owned(),
+        }
+    }
+}
+
+impl BoundedStorable for SeatKey {
+    const MAX_SIZE: u32 = 8 + MAX_SEAT_NUMBER_LENGTH;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Referral program settings
+const REFERRAL_CODE_PREFIX: &str = "REF";
+const REFERRAL_REWARD_POINTS: u64 = 500;
+// Referrers stop earning points once this many referrals have been rewarded
+const MAX_REWARDED_REFERRALS: u64 = 20;
+
+// Memory management using thread_local
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    // Canisters installed before versioning have no cell yet, hence the legacy default
+    static STORED_SCHEMA_VERSION: RefCell<SchemaVersionCell> = RefCell::new(
+        SchemaVersionCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+            LEGACY_SCHEMA_VERSION,
+        )
+        .expect("Cannot create the schema version cell")
+    );
+
+    static USERS_STORAGE: RefCell<RecordMap<User>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+        )
+    );
+
+    static EVENTS_STORAGE: RefCell<RecordMap<Event>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+        )
+    );
+
+    static TICKETS_STORAGE: RefCell<RecordMap<Ticket>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+        )
+    );
+
+    static LOYALTY_STORAGE: RefCell<RecordMap<LoyaltyPoints>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+        )
+    );
+
+    static SEATING_STORAGE: RefCell<RecordMap<EventSeating>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+        )
+    );
+
+    // Keyed by the id of the referred user
+    static REFERRALS_STORAGE: RefCell<RecordMap<Referral>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+        )
+    );
+
+    // (referrer_id, referred user_id) for every referral
+    static REFERRALS_BY_REFERRER: RefCell<IndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+        )
+    );
+
+    // Keyed by the id of the referrer
+    static REFERRAL_STATS_STORAGE: RefCell<RecordMap<ReferralStats>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+        )
+    );
+
+    // (user_id, ticket_id) for every ticket a user currently holds
+    static TICKETS_BY_USER: RefCell<IndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+        )
+    );
+
+    // (event_id, ticket_id) for every ticket issued for an event
+    static TICKETS_BY_EVENT: RefCell<IndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+        )
+    );
+
+    // (event_id, seat label) -> ticket_id for every ticket with a seat
+    static TICKETS_BY_SEAT: RefCell<SeatIndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+        )
+    );
+
+    // (date, event_id) for every event
+    static EVENTS_BY_DATE: RefCell<IndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+        )
+    );
+
+    // (u64::MAX - date, event_id) for every event, so later dates come first
+    static EVENTS_BY_DATE_DESC: RefCell<IndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+        )
+    );
+
+    // (organizer principal as text, event_id) for every event with an organizer
+    static EVENTS_BY_ORGANIZER: RefCell<TextIndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+        )
+    );
+
+    // (normalized location, event_id) for every event
+    static EVENTS_BY_LOCATION: RefCell<TextIndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+        )
+    );
+
+    static EVENT_DETAILS_STORAGE: RefCell<RecordMap<EventDetails>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+        )
+    );
+
+    // (token, event_id) for every token of an event's name, location, category, tags and description
+    static EVENT_TOKENS: RefCell<TokenIndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+        )
+    );
+
+    static VENUES_STORAGE: RefCell<RecordMap<Venue>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+        )
+    );
+
+    // Keyed by event id
+    static EVENT_SEAT_MAPS_STORAGE: RefCell<RecordMap<EventSeatMap>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+        )
+    );
+
+    // Keyed by event id
+    static EVENT_ZONES_STORAGE: RefCell<RecordMap<EventZones>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+        )
+    );
+
+    static EVENT_SERIES_STORAGE: RefCell<RecordMap<EventSeries>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+        )
+    );
+
+    // (series_id, event_id) for every occurrence of a series
+    static SERIES_OCCURRENCES: RefCell<IndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+        )
+    );
+
+    // Holds one record, under SETTINGS_KEY
+    static SETTINGS_STORAGE: RefCell<RecordMap<Settings>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+        )
+    );
+
+    static ORGANIZERS_STORAGE: RefCell<RecordMap<Organizer>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+        )
+    );
+
+    // (owner principal as text, organizer_id) for every organizer
+    static ORGANIZERS_BY_OWNER: RefCell<TextIndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+        )
+    );
+
+    // Keyed by organizer id
+    static ORGANIZER_LEDGERS_STORAGE: RefCell<RecordMap<OrganizerLedger>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+        )
+    );
+
+    static PAYOUTS_STORAGE: RefCell<RecordMap<Payout>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+        )
+    );
+
+    // (organizer_id, payout_id) for every payout an organizer requested
+    static PAYOUTS_BY_ORGANIZER: RefCell<IndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+        )
+    );
+
+    static TAX_RATES_STORAGE: RefCell<RecordMap<TaxRate>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+        )
+    );
+
+    static TOKENS_STORAGE: RefCell<RecordMap<Token>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+        )
+    );
+
+    // (symbol, token id) for every token
+    static TOKENS_BY_SYMBOL: RefCell<TextIndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+        )
+    );
+
+    // (jurisdiction, tax rate id) for every tax rate
+    static TAX_RATES_BY_JURISDICTION: RefCell<TextIndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+        )
+    );
+
+    // (organizer_id, ledger_id) for every ledger an organizer has in a token. Ledgers
+    // in the default currency are keyed by organizer id instead.
+    static ORGANIZER_TOKEN_LEDGERS: RefCell<IndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+        )
+    );
+
+    static AUDIT_LOG: RefCell<AuditLog> = RefCell::new(
+        AuditLog::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))),
+        )
+        .expect("Cannot create the audit log")
+    );
+
+    static BLOCK_LOG: RefCell<BlockLog> = RefCell::new(
+        BlockLog::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))),
+        )
+        .expect("Cannot create the ticket transaction log")
+    );
+
+    static EVENT_SALES_STORAGE: RefCell<RecordMap<EventSales>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+        )
+    );
+
+    static SALES_BY_HOUR: RefCell<SalesBucketMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+        )
+    );
+
+    static SALES_BY_DAY: RefCell<SalesBucketMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+        )
+    );
+
+    // (normalized email, user_id) for every user
+    static USERS_BY_EMAIL: RefCell<TextIndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+        )
+    );
+
+    // (lowercased username, user_id) for every user
+    static USERS_BY_USERNAME: RefCell<TextIndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+        )
+    );
+
+    // (owner principal, user_id) for every user registered by a non-anonymous caller
+    static USERS_BY_OWNER: RefCell<TextIndexMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+        )
+    );
+
+    // Pending email verifications, by user id
+    static EMAIL_VERIFICATIONS_STORAGE: RefCell<RecordMap<EmailVerification>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+        )
+    );
+
+    // Holds the notification hook under SETTINGS_KEY
+    static NOTIFICATION_HOOK_STORAGE: RefCell<RecordMap<NotificationHook>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+        )
+    );
+
+    static POINTS_HISTORY: RefCell<PointsHistoryMap> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+        )
+    );
+
+    // Responses to http_request, by path. Kept on the heap and emptied by every update
+    // call, since any of them can change what a page shows.
+    static HTTP_RESPONSES: RefCell<BTreeMap<String, CachedResponse>> =
+        const { RefCell::new(BTreeMap::new()) };
+
+    // Calls that could not be added to the audit log since the canister last started
+    static UNRECORDED_CALLS: RefCell<u64> = const { RefCell::new(0) };
+}
+
+#[ic_cdk::init]
+fn init() {
+    set_schema_version(SCHEMA_VERSION);
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    // All state lives in stable memory, so there is nothing to serialize here.
+    // Stamp the layout this build wrote so the next build knows what to migrate from.
+    set_schema_version(SCHEMA_VERSION);
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    let stored_version = STORED_SCHEMA_VERSION.with(|cell| *cell.borrow().get());
+    if stored_version > SCHEMA_VERSION {
+        ic_cdk::trap(&format!(
+            "Stable memory uses schema version {} but this build only understands up to {}",
+            stored_version, SCHEMA_VERSION
+        ));
+    }
+
+    migrate_storage(stored_version);
+    set_schema_version(SCHEMA_VERSION);
+    // Certified data does not survive an upgrade
+    certify_data();
+}
+
+// Helper function to bring stable memory from an older schema version up to date
+fn migrate_storage(from_version: u32) {
+    if from_version < 2 {
+        // Re-encode every record so it is stored in a versioned envelope.
+        // Decoding goes through the legacy layouts, which fills in the new fields.
+        rewrite_records(&USERS_STORAGE);
+        rewrite_records(&EVENTS_STORAGE);
+        rewrite_records(&TICKETS_STORAGE);
+        rewrite_records(&LOYALTY_STORAGE);
+        rewrite_records(&SEATING_STORAGE);
+    }
+
+    if from_version < 3 {
+        for ticket in load_all(&TICKETS_STORAGE) {
+            index_ticket(&ticket);
+        }
+    }
+
+    if from_version < 4 {
+        for event in load_all(&EVENTS_STORAGE) {
+            index_event(&event);
+        }
+    }
+
+    // Events created before version 5 have no details, so only their own fields are indexed
+    if from_version < 5 {
+        for event in load_all(&EVENTS_STORAGE) {
+            index_event_text(&event, &EventDetails::default());
+        }
+    }
+
+    // Tickets could not change hands before version 6, so their buyers still hold them
+    if from_version < 6 {
+        for ticket in load_all(&TICKETS_STORAGE) {
+            record_block(
+                BLOCK_TYPE_MINT,
+                mint_transaction(&ticket),
+                ticket.purchase_date,
+            );
+        }
+    }
+
+    if from_version < 7 {
+        let mut all_sales: BTreeMap<u64, EventSales> = BTreeMap::new();
+        for ticket in load_all(&TICKETS_STORAGE) {
+            let sales = all_sales
+                .entry(ticket.event_id)
+                .or_insert_with(|| EventSales {
+                    event_id: ticket.event_id,
+                    ..Default::default()
+                });
+            record_sale(sales, &ticket);
+            if ticket.checked_in_at.is_some() {
+                sales.tickets_checked_in += 1;
+            }
+            count_sale_in_buckets(&ticket);
+        }
+        for (event_id, sales) in all_sales {
+            store(&EVENT_SALES_STORAGE, event_id, &sales).unwrap_or_else(|err| {
+                ic_cdk::trap(&format!(
+                    "Cannot migrate sales of event {}: {}",
+                    event_id, err
+                ))
+            });
+        }
+    }
+
+    // Emails were not unique before version 8; addresses several users share stay
+    // indexed under each of them
+    if from_version < 8 {
+        for user in load_all(&USERS_STORAGE) {
+            index_user(&USERS_BY_EMAIL, email_key(&user.email), user.id);
+        }
+    }
+
+    // Likewise for usernames before version 9; lookups find the first user with a name
+    if from_version < 9 {
+        for user in load_all(&USERS_STORAGE) {
+            index_user(&USERS_BY_USERNAME, username_key(&user.username), user.id);
+            if user.owner != Principal::anonymous() {
+                index_user(&USERS_BY_OWNER, user.owner.to_text(), user.id);
+            }
+        }
+    }
+
+    // Loyalty accounts kept their last few history entries inline before version 10.
+    // Accounts from the first builds never recorded their user id, so go by the key.
+    if from_version < 10 {
+        let accounts: Vec<(u64, Stored<LoyaltyPoints>)> =
+            LOYALTY_STORAGE.with(|storage| storage.borrow().iter().collect());
+        for (user_id, stored) in accounts {
+            let moved = stored.decode().and_then(|mut loyalty| {
+                let entries = std::mem::take(&mut loyalty.points_history)
+                    .iter()
+                    .enumerate()
+                    .map(|(seq, entry)| {
+                        Ok((points_key(user_id, seq as u64), Stored::encode(entry)?))
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?;
+                loyalty.user_id = user_id;
+                Ok((Stored::encode(&loyalty)?, entries))
+            });
+            let (stored, entries) = moved.unwrap_or_else(|err| {
+                ic_cdk::trap(&format!(
+                    "Cannot migrate the points history of user {}: {}",
+                    user_id, err
+                ))
+            });
+            save(&LOYALTY_STORAGE, user_id, stored);
+            save_points_entries(entries);
+        }
+    }
+
+    if from_version < 11 {
+        for event in load_all(&EVENTS_STORAGE) {
+            index_event(&event);
+        }
+    }
+
+    if from_version < 12 {
+        for ticket in load_all(&TICKETS_STORAGE) {
+            index_ticket(&ticket);
+        }
+    }
+
+    if from_version < 13 {
+        for event in load_all(&EVENTS_STORAGE) {
+            index_event(&event);
+        }
+    }
+
+    if from_version < 14 {
+        for referral in load_all(&REFERRALS_STORAGE) {
+            index_referral(&referral);
+        }
+    }
+
+    if from_version < 15 {
+        for token in load_all(&TOKENS_STORAGE) {
+            index_text_key(&TOKENS_BY_SYMBOL, token.symbol, token.id);
+        }
+        for tax_rate in load_all(&TAX_RATES_STORAGE) {
+            index_text_key(
+                &TAX_RATES_BY_JURISDICTION,
+                tax_rate.jurisdiction,
+                tax_rate.id,
+            );
+        }
+    }
+}
+
+// Helper function to read every record of a map and write it back in the current layout.
+// Trapping here is deliberate: it rolls the upgrade back instead of leaving bad data.
+fn rewrite_records<T: Record>(storage: &'static RecordStore<T>) {
+    storage.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let records: Vec<(u64, Stored<T>)> = storage.iter().collect();
+        for (key, stored) in records {
+            let rewritten = stored
+                .decode()
+                .and_then(|record| Stored::encode(&record))
+                .unwrap_or_else(|err| {
+                    ic_cdk::trap(&format!("Cannot migrate {} {}: {}", T::NAME, key, err))
+                });
+            storage.insert(key, rewritten);
+        }
+    });
+}
+
+fn set_schema_version(version: u32) {
+    STORED_SCHEMA_VERSION.with(|cell| {
+        cell.borrow_mut()
+            .set(version)
+            .expect("Cannot update the schema version");
+    });
+}
+
+// Function to register a user
+#[ic_cdk::update]
+fn register_user(payload: RegisterUserPayload) -> Result<User, Error> {
+    audited("register_user", AuditEntity::User, &[], || {
+        let username = check_username(&payload.username, None)?;
+        let email = check_email(&payload.email)?;
+        if find_user_in(&USERS_BY_EMAIL, &email_key(&email))?.is_some() {
+            return Err(Error::EmailTaken { email });
+        }
+
+        let caller = caller();
+
+        // Resolve the referrer before allocating an id so a bad code leaves no trace
+        let referrer = match payload.referral_code.as_deref().map(str::trim) {
+            Some(code) if !code.is_empty() => {
+                check_length("referral_code", code, MAX_REFERRAL_CODE_LENGTH)?;
+                let referrer = find_user_by_referral_code(code)?.ok_or_else(|| {
+                    Error::InvalidReferralCode {
+                        code: code.to_string(),
+                    }
+                })?;
+
+                // Emails are unique, so only the principal can give a self-referral away
+                if caller != Principal::anonymous() && referrer.owner == caller {
+                    return Err(Error::SelfReferral);
+                }
+                Some(referrer)
+            }
+            _ => None,
+        };
+
+        let user_id = next_id()?;
+
+        let user = User {
+            id: user_id,
+            owner: caller,
+            username,
+            email,
+            referral_code: referral_code_for(user_id),
+            referred_by: referrer.as_ref().map(|referrer| referrer.id),
+            email_verified_at: None,
+        };
+
+        // Encode everything up front so a failure cannot leave a half-registered user
+        let stored_user = Stored::encode(&user)?;
+        let referral_records = match referrer {
+            Some(referrer) => {
+                let referral = Referral {
+                    referrer_id: referrer.id,
+                    referred_id: user_id,
+                    created_at: time(),
+                    status: ReferralStatus::Pending,
+                    rewarded_at: None,
+                };
+
+                let mut stats = referral_stats_for(&referrer)?;
+                stats.total_referrals += 1;
+                stats.pending_referrals += 1;
+
+                Some((
+                    referral.clone(),
+                    Stored::encode(&referral)?,
+                    Stored::encode(&stats)?,
+                ))
+            }
+            None => None,
+        };
+
+        save(&USERS_STORAGE, user_id, stored_user);
+        index_user_fields(&user);
+        if let Some((referral, stored_referral, stats)) = referral_records {
+            save(&REFERRALS_STORAGE, user_id, stored_referral);
+            save(&REFERRAL_STATS_STORAGE, referral.referrer_id, stats);
+            index_referral(&referral);
+        }
+
+        Ok(user)
+    })
+}
+
+// Function to get referral statistics for a user
+#[ic_cdk::query]
+fn get_referral_stats(user_id: u64) -> Result<ReferralStats, Error> {
+    let user = load(&USERS_STORAGE, user_id)?.ok_or(Error::UserNotFound { user_id })?;
+    referral_stats_for(&user)
+}
+
+// Function to get a user's public profile
+#[ic_cdk::query]
+fn get_user(user_id: u64) -> Result<UserProfile, Error> {
+    let user = load(&USERS_STORAGE, user_id)?.ok_or(Error::UserNotFound { user_id })?;
+    Ok(user_profile(user))
+}
+
+// Function to look up a user's public profile by username, ignoring case
+#[ic_cdk::query]
+fn get_user_by_username(username: String) -> Result<UserProfile, Error> {
+    let user = find_user_in(&USERS_BY_USERNAME, &username_key(&username))?.ok_or_else(|| {
+        Error::UsernameNotFound {
+            username: username.trim().to_string(),
+        }
+    })?;
+    Ok(user_profile(user))
+}
+
+// Function to get the profile of the caller's account, email included. Callers who
+// registered several accounts get the first of them.
+#[ic_cdk::query]
+fn get_my_profile() -> Result<UserProfile, Error> {
+    let caller = caller();
+    let user = if caller == Principal::anonymous() {
+        None
+    } else {
+        find_user_in(&USERS_BY_OWNER, &caller.to_text())?
+    };
+    user.map(user_profile).ok_or(Error::Unauthorized {
+        required_role: Role::AccountOwner,
+    })
+}
+
+// Function for a controller to hand an account nobody owns, such as one registered
+// before owners were recorded, to the principal its user signs in with
+#[ic_cdk::update]
+fn assign_owner(user_id: u64, owner: Principal) -> Result<User, Error> {
+    audited("assign_owner", AuditEntity::User, &[user_id], || {
+        require_admin()?;
+        if owner == Principal::anonymous() {
+            return Err(invalid_field(
+                "owner",
+                "must not be the anonymous principal",
+            ));
+        }
+
+        let mut user = load(&USERS_STORAGE, user_id)?.ok_or(Error::UserNotFound { user_id })?;
+        if user.owner != Principal::anonymous() {
+            return Err(invalid_field("user_id", "the account already has an owner"));
+        }
+
+        user.owner = owner;
+        store(&USERS_STORAGE, user_id, &user)?;
+        index_user(&USERS_BY_OWNER, owner.to_text(), user_id);
+
+        Ok(user)
+    })
+}
+
+// Function to change a user's username or email address. A new email address has to be
+// confirmed again.
+#[ic_cdk::update]
+fn update_profile(user_id: u64, payload: UpdateProfilePayload) -> Result<User, Error> {
+    audited("update_profile", AuditEntity::User, &[user_id], || {
+        let old_user = require_account_owner(user_id)?;
+        let mut user = old_user.clone();
+
+        if let Some(username) = payload.username {
+            user.username = check_username(&username, Some(user_id))?;
+        }
+        let email_changed = match payload.email {
+            Some(email) => {
+                let email = check_email(&email)?;
+                let changed = email_key(&email) != email_key(&old_user.email);
+                if changed {
+                    if find_user_in(&USERS_BY_EMAIL, &email_key(&email))?.is_some() {
+                        return Err(Error::EmailTaken { email });
+                    }
+                    user.email_verified_at = None;
+                }
+                user.email = email;
+                changed
+            }
+            None => false,
+        };
+
+        store(&USERS_STORAGE, user_id, &user)?;
+        unindex_user_fields(&old_user);
+        index_user_fields(&user);
+        if email_changed {
+            EMAIL_VERIFICATIONS_STORAGE.with(|storage| storage.borrow_mut().remove(&user_id));
+        }
+
+        Ok(user)
+    })
+}
+
+// Function to delete a user's account together with its loyalty points, referral records
+// and pending email verification. Tickets for events still to come must be used or
+// transferred first; past tickets stay in the sales records, no longer listed under the user.
+#[ic_cdk::update]
+fn delete_account(user_id: u64) -> Result<(), Error> {
+    audited("delete_account", AuditEntity::User, &[user_id], || {
+        let user = require_account_owner(user_id)?;
+        let tickets = load_indexed_tickets(&TICKETS_BY_USER, user_id);
+        for ticket in &tickets {
+            let upcoming = ticket.checked_in_at.is_none()
+                && load(&EVENTS_STORAGE, ticket.event_id)?
+                    .is_some_and(|event| !event.status.is_final());
+            if upcoming {
+                return Err(invalid_field(
+                    "user_id",
+                    "the account still holds tickets for upcoming events",
+                ));
+            }
+        }
+
+        // A referral still pending can never be rewarded now, so it stops counting as
+        // pending for the referrer
+        let referral = load(&REFERRALS_STORAGE, user_id)?;
+        let referrer_stats = match &referral {
+            Some(referral) if matches!(referral.status, ReferralStatus::Pending) => {
+                match load(&USERS_STORAGE, referral.referrer_id)? {
+                    Some(referrer) => {
+                        let mut stats = referral_stats_for(&referrer)?;
+                        stats.pending_referrals = stats.pending_referrals.saturating_sub(1);
+                        Some((referrer.id, Stored::encode(&stats)?))
+                    }
+                    None => None,
+                }
+            }
+            _ => None,
+        };
+
+        USERS_STORAGE.with(|storage| storage.borrow_mut().remove(&user_id));
+        unindex_user_fields(&user);
+        TICKETS_BY_USER.with(|index| {
+            let mut index = index.borrow_mut();
+            for ticket in &tickets {
+                index.remove(&IndexKey {
+                    parent_id: user_id,
+                    record_id: ticket.id,
+                });
+            }
+        });
+        EMAIL_VERIFICATIONS_STORAGE.with(|storage| storage.borrow_mut().remove(&user_id));
+        LOYALTY_STORAGE.with(|storage| storage.borrow_mut().remove(&user_id));
+        POINTS_HISTORY.with(|history| {
+            let mut history = history.borrow_mut();
+            let keys: Vec<IndexKey> = history
+                .range(IndexKey::range_of(user_id))
+                .map(|(key, _)| key)
+                .collect();
+            for key in keys {
+                history.remove(&key);
+            }
+        });
+        REFERRALS_STORAGE.with(|storage| storage.borrow_mut().remove(&user_id));
+        if let Some(referral) = &referral {
+            unindex_referral(referral);
+        }
+        REFERRAL_STATS_STORAGE.with(|storage| storage.borrow_mut().remove(&user_id));
+        if let Some((referrer_id, stats)) = referrer_stats {
+            save(&REFERRAL_STATS_STORAGE, referrer_id, stats);
+        }
+        // The users this account referred can no longer earn it anything
+        for referred_id in referred_by(user_id) {
+            REFERRALS_STORAGE.with(|storage| storage.borrow_mut().remove(&referred_id));
+            REFERRALS_BY_REFERRER.with(|index| {
+                index.borrow_mut().remove(&IndexKey {
+                    parent_id: user_id,
+                    record_id: referred_id,
+                })
+            });
+        }
+
+        Ok(())
+    })
+}
+
+// Function to send a user a code confirming their email address through the
+// notification hook. A new code replaces the previous one.
+#[ic_cdk::update]
+async fn request_email_verification(user_id: u64) -> Result<(), Error> {
+    let result = send_verification_code(user_id).await;
+    record_audit(
+        "request_email_verification",
+        AuditEntity::User,
+        &[user_id],
+        &result,
+    );
+    result
+}
+
+// Function to confirm a user's email address with the code sent to it
+#[ic_cdk::update]
+fn confirm_email(user_id: u64, code: String) -> Result<User, Error> {
+    audited("confirm_email", AuditEntity::User, &[user_id], || {
+        let mut user = require_account_owner(user_id)?;
+        let now = time();
+        let mut verification = load(&EMAIL_VERIFICATIONS_STORAGE, user_id)?
+            .filter(|verification| {
+                verification.email == user.email
+                    && now < verification.expires_at
+                    && verification.attempts < MAX_VERIFICATION_ATTEMPTS
+            })
+            .ok_or(Error::InvalidVerificationCode)?;
+
+        if verification.code_hash != verification_hash(user_id, code.trim()) {
+            verification.attempts += 1;
+            store(&EMAIL_VERIFICATIONS_STORAGE, user_id, &verification)?;
+            return Err(Error::InvalidVerificationCode);
+        }
+
+        user.email_verified_at = Some(now);
+        store(&USERS_STORAGE, user_id, &user)?;
+        EMAIL_VERIFICATIONS_STORAGE.with(|storage| storage.borrow_mut().remove(&user_id));
+
+        Ok(user)
+    })
+}
+
+// Function to set the endpoint notifications are posted to. Once one is set, users
+// must confirm their email address before buying tickets.
+#[ic_cdk::update]
+fn set_notification_hook(url: String) -> Result<(), Error> {
+    audited("set_notification_hook", AuditEntity::Settings, &[], || {
+        require_admin()?;
+        let url = url.trim();
+        check_length("url", url, MAX_URL_LENGTH)?;
+        if !url.starts_with("https://") {
+            return Err(invalid_field("url", "must be an https URL"));
+        }
+        store(
+            &NOTIFICATION_HOOK_STORAGE,
+            SETTINGS_KEY,
+            &NotificationHook {
+                url: url.to_string(),
+            },
+        )
+    })
+}
+
+// Function the IC calls to cut the hook's responses down to their status, so every
+// replica agrees on them. Not meant to be called directly.
+#[ic_cdk::query]
+fn transform_notification_response(args: TransformArgs) -> outcall::HttpResponse {
+    outcall::HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+// Function to create an event
+#[ic_cdk::update]
+fn create_event(payload: CreateEventPayload) -> Result<Event, Error> {
+    audited("create_event", AuditEntity::Event, &[], || {
+        insert_event(payload, None)
+    })
+}
+
+// Function to create many venues and events, with their seat maps, in one call. Every
+// row is checked before anything is written, and nothing is written unless all of them
+// are valid; the error then lists what is wrong with each invalid row.
+#[ic_cdk::update]
+fn import_catalog(payload: ImportPayload) -> Result<ImportSummary, Error> {
+    audited("import_catalog", AuditEntity::Event, &[], || {
+        import_rows(payload)
+    })
+}
+
+// Function to import venues and events from a JSON document shaped like import_catalog's payload
+#[ic_cdk::update]
+fn import_catalog_json(document: String) -> Result<ImportSummary, Error> {
+    audited("import_catalog_json", AuditEntity::Event, &[], || {
+        let payload = serde_json::from_str(&document)
+            .map_err(|err| invalid_field("document", &err.to_string()))?;
+        import_rows(payload)
+    })
+}
+
+// Function to create a recurring event: one occurrence per date of the schedule, each
+// a copy of the template event moved to that date
+#[ic_cdk::update]
+fn create_event_series(payload: CreateEventSeriesPayload) -> Result<EventSeries, Error> {
+    audited("create_event_series", AuditEntity::Series, &[], || {
+        let template = payload.event;
+        if template.date == 0 {
+            return Err(missing_field("date"));
+        }
+        let dates = schedule_dates(template.date, &payload.schedule)?;
+        let venue = match template.venue_id {
+            Some(venue_id) => {
+                Some(load(&VENUES_STORAGE, venue_id)?.ok_or(Error::VenueNotFound { venue_id })?)
+            }
+            None => None,
+        };
+
+        // Every occurrence is validated and encoded before any of them is written
+        let series_id = next_id()?;
+        let mut occurrences = Vec::with_capacity(dates.len());
+        for date in &dates {
+            let mut occurrence = template.clone();
+            occurrence.date = *date;
+            occurrence.doors_open = template
+                .doors_open
+                .map(|time| shift_time(time, template.date, *date));
+            occurrence.end_time = template
+                .end_time
+                .map(|time| shift_time(time, template.date, *date));
+            occurrences.push(prepare_event(occurrence, venue.clone(), Some(series_id))?);
+        }
+        let occurrences = occurrences
+            .into_iter()
+            .map(encode_event)
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let series = EventSeries {
+            id: series_id,
+            organizer: caller(),
+            name: template.name.clone(),
+            first_date: template.date,
+            schedule: payload.schedule,
+            occurrences: dates.len() as u32,
+        };
+        let stored_series = Stored::encode(&series)?;
+        for occurrence in occurrences {
+            save_event(occurrence);
+        }
+        save(&EVENT_SERIES_STORAGE, series_id, stored_series);
+
+        Ok(series)
+    })
+}
+
+// Function to get a recurring event
+#[ic_cdk::query]
+fn get_event_series(series_id: u64) -> Result<EventSeries, Error> {
+    load(&EVENT_SERIES_STORAGE, series_id)?.ok_or(Error::SeriesNotFound { series_id })
+}
+
+// Function to list the occurrences of a recurring event by date, with their availability
+#[ic_cdk::query]
+fn list_series_occurrences(series_id: u64) -> Result<Vec<SeriesOccurrence>, Error> {
+    if load(&EVENT_SERIES_STORAGE, series_id)?.is_none() {
+        return Err(Error::SeriesNotFound { series_id });
+    }
+
+    let mut occurrences: Vec<SeriesOccurrence> = series_occurrences(series_id)
+        .into_iter()
+        .map(|event| SeriesOccurrence {
+            event_id: event.id,
+            date: event.date,
+            status: event.status,
+            price: quote_price(&event),
+            total_tickets: event.total_tickets,
+            tickets_sold: event.tickets_sold,
+            available_tickets: event.total_tickets.saturating_sub(event.tickets_sold),
+        })
+        .collect();
+    occurrences.sort_by_key(|occurrence| (occurrence.date, occurrence.event_id));
+
+    Ok(occurrences)
+}
+
+// Function to move every occurrence of a recurring event that allows it to a new
+// status, such as publishing or cancelling a whole run. Returns the occurrences changed.
+#[ic_cdk::update]
+fn change_series_status(series_id: u64, status: EventStatus) -> Result<Vec<Event>, Error> {
+    audited(
+        "change_series_status",
+        AuditEntity::Series,
+        &[series_id],
+        || {
+            let series = load(&EVENT_SERIES_STORAGE, series_id)?
+                .ok_or(Error::SeriesNotFound { series_id })?;
+            let caller = caller();
+            let is_organizer = caller != Principal::anonymous() && series.organizer == caller;
+            if !is_organizer && !is_controller(&caller) {
+                return Err(Error::Unauthorized {
+                    required_role: Role::Organizer,
+                });
+            }
+            if status == EventStatus::Postponed {
+                return Err(invalid_field(
+                    "status",
+                    "postpone occurrences one at a time with postpone_event",
+                ));
+            }
+
+            let mut changed = Vec::new();
+            for event in series_occurrences(series_id) {
+                if event.status.can_become(status) {
+                    changed.push(change_event_status(event.id, status, None)?);
+                }
+            }
+            Ok(changed)
+        },
+    )
+}
+
+// Helper function to validate and store a new event, optionally as an occurrence of a series
+fn insert_event(payload: CreateEventPayload, series_id: Option<u64>) -> Result<Event, Error> {
+    let venue = match payload.venue_id {
+        Some(venue_id) => {
+            Some(load(&VENUES_STORAGE, venue_id)?.ok_or(Error::VenueNotFound { venue_id })?)
+        }
+        None => None,
+    };
+    write_event(prepare_event(payload, venue, series_id)?)
+}
+
+// Helper function to validate every row of an import, then write them all
+fn import_rows(payload: ImportPayload) -> Result<ImportSummary, Error> {
+    // Only controllers and callers with an organizer profile can import
+    let caller = caller();
+    if !is_controller(&caller) && organizer_of(caller)?.is_none() {
+        return Err(Error::Unauthorized {
+            required_role: Role::Organizer,
+        });
+    }
+    let rows = payload.venues.len() + payload.events.len();
+    if rows == 0 {
+        return Err(missing_field("events"));
+    }
+    if rows > MAX_IMPORT_ROWS {
+        return Err(invalid_field(
+            "events",
+            &format!(
+                "an import creates at most {} venues and events",
+                MAX_IMPORT_ROWS
+            ),
+        ));
+    }
+
+    let mut errors = Vec::new();
+    let mut venues = Vec::with_capacity(payload.venues.len());
+    for (row, payload) in payload.venues.into_iter().enumerate() {
+        let venue = check_venue(&payload).and_then(|_| {
+            let venue = Venue {
+                id: 0,
+                owner: caller,
+                name: payload.name,
+                address: payload.address,
+                coordinates: payload.coordinates,
+                capacity: payload.capacity,
+                sections: payload.sections,
+            };
+            Stored::encode(&venue)?;
+            Ok(venue)
+        });
+        match venue {
+            Ok(venue) => venues.push(Some(venue)),
+            Err(error) => {
+                errors.push(ImportRowError {
+                    table: ImportTable::Venues,
+                    row: row as u32,
+                    error,
+                });
+                venues.push(None);
+            }
+        }
+    }
+    let mut events = Vec::with_capacity(payload.events.len());
+    for (row, event_row) in payload.events.into_iter().enumerate() {
+        match prepare_import_event(event_row, &venues) {
+            Ok(event) => events.push(event),
+            Err(error) => errors.push(ImportRowError {
+                table: ImportTable::Events,
+                row: row as u32,
+                error,
+            }),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(Error::ImportRejected { errors });
+    }
+
+    // Every row was checked above; ids are given out and every record is encoded
+    // before any of them is written
+    let mut stored_venues = Vec::with_capacity(venues.len());
+    for mut venue in venues.into_iter().flatten() {
+        venue.id = next_id()?;
+        stored_venues.push((venue.id, Stored::encode(&venue)?));
+    }
+    let venue_ids: Vec<u64> = stored_venues
+        .iter()
+        .map(|(venue_id, _)| *venue_id)
+        .collect();
+    let mut encoded_events = Vec::with_capacity(events.len());
+    for (mut new_event, venue_row) in events {
+        if let Some(venue_row) = venue_row {
+            let venue_id = venue_ids[venue_row];
+            new_event.event.venue_id = Some(venue_id);
+            if let Some(seat_map) = &mut new_event.seat_map {
+                seat_map.venue_id = Some(venue_id);
+            }
+        }
+        encoded_events.push(encode_event(new_event)?);
+    }
+
+    for (venue_id, stored_venue) in stored_venues {
+        save(&VENUES_STORAGE, venue_id, stored_venue);
+    }
+    let event_ids = encoded_events
+        .into_iter()
+        .map(|encoded| save_event(encoded).id)
+        .collect();
+
+    Ok(ImportSummary {
+        venue_ids,
+        event_ids,
+    })
+}
+
+// Helper function to validate an event row of an import, returning the event and the
+// position of its venue if the import creates that too
+fn prepare_import_event(
+    row: ImportEventRow,
+    venues: &[Option<Venue>],
+) -> Result<(NewEvent, Option<usize>), Error> {
+    let venue =
+        match (row.venue_row, row.event.venue_id) {
+            (Some(_), Some(_)) => {
+                return Err(invalid_field(
+                    "venue_row",
+                    "cannot be given along with venue_id",
+                ))
+            }
+            (Some(venue_row), None) => {
+                let venue = venues
+                    .get(venue_row as usize)
+                    .ok_or_else(|| invalid_field("venue_row", "no venue is at this row"))?;
+                Some(venue.clone().ok_or_else(|| {
+                    invalid_field("venue_row", "the venue at this row is invalid")
+                })?)
+            }
+            (None, Some(venue_id)) => {
+                Some(load(&VENUES_STORAGE, venue_id)?.ok_or(Error::VenueNotFound { venue_id })?)
+            }
+            (None, None) => None,
+        };
+
+    let mut new_event = prepare_event(row.event, venue, None)?;
+    if let Some(seating) = row.seating {
+        let mut seat_map = new_event.seat_map.take().unwrap_or_else(|| EventSeatMap {
+            venue_id: new_event.event.venue_id,
+            ..Default::default()
+        });
+        apply_seating(&new_event.event, &mut seat_map, seating)?;
+        new_event.seat_map = Some(seat_map);
+    }
+
+    // Records that would not fit their maps are reported now rather than when written
+    Stored::encode(&new_event.event)?;
+    Stored::encode(&new_event.details)?;
+    if let Some(seat_map) = &new_event.seat_map {
+        Stored::encode(seat_map)?;
+    }
+    Ok((new_event, row.venue_row.map(|row| row as usize)))
+}
+
+// Helper function to change the sections and blocked seats of an event's seat map
+fn apply_seating(
+    event: &Event,
+    seat_map: &mut EventSeatMap,
+    payload: UpdateEventSeatingPayload,
+) -> Result<(), Error> {
+    if let Some(sections) = payload.sections {
+        let seats = check_sections(&sections)?;
+        if seats > event.total_tickets {
+            return Err(invalid_field(
+                "sections",
+                &format!(
+                    "hold {} seats, more than the event's {} tickets",
+                    seats, event.total_tickets
+                ),
+            ));
+        }
+        seat_map.sections = sections;
+    }
+    if let Some(blocked_seats) = payload.blocked_seats {
+        if blocked_seats.len() > MAX_BLOCKED_SEATS {
+            return Err(invalid_field(
+                "blocked_seats",
+                &format!("at most {} seats can be blocked", MAX_BLOCKED_SEATS),
+            ));
+        }
+        let mut normalized: Vec<String> = Vec::new();
+        for seat in blocked_seats {
+            let seat = seat.trim().to_string();
+            if !normalized.contains(&seat) {
+                normalized.push(seat);
+            }
+        }
+        seat_map.blocked_seats = normalized;
+    }
+    // Blocked seats must exist, including after the sections change
+    if let Some(seat) = seat_map
+        .blocked_seats
+        .iter()
+        .find(|seat| find_seat(&seat_map.sections, seat).is_none())
+    {
+        return Err(Error::UnknownSeat { seat: seat.clone() });
+    }
+    Ok(())
+}
+
+// Helper function to validate a new event and build its records, without writing them
+fn prepare_event(
+    mut payload: CreateEventPayload,
+    venue: Option<Venue>,
+    series_id: Option<u64>,
+) -> Result<NewEvent, Error> {
+    if let Some(venue) = &venue {
+        if payload.location.is_empty() {
+            payload.location = format!("{}, {}", venue.name, venue.address);
+        }
+        if payload.total_tickets == 0 {
+            payload.total_tickets = venue.capacity;
+        }
+        if payload.total_tickets > venue.capacity {
+            return Err(invalid_field(
+                "total_tickets",
+                &format!("cannot exceed the venue's capacity of {}", venue.capacity),
+            ));
+        }
+    }
+
+    if payload.name.is_empty() {
+        return Err(missing_field("name"));
+    }
+    if payload.location.is_empty() {
+        return Err(missing_field("location"));
+    }
+    if payload.date == 0 {
+        return Err(missing_field("date"));
+    }
+    if payload.ticket_price == 0 {
+        return Err(invalid_field("ticket_price", "must be greater than zero"));
+    }
+    if payload.total_tickets == 0 {
+        return Err(invalid_field("total_tickets", "must be greater than zero"));
+    }
+    check_length("name", &payload.name, MAX_EVENT_NAME_LENGTH)?;
+    check_length("location", &payload.location, MAX_LOCATION_LENGTH)?;
+
+    let category = normalize_category(payload.category)?;
+    let description = normalize_description(payload.description.unwrap_or_default())?;
+    let tags = normalize_tags(payload.tags.unwrap_or_default())?;
+    let media_urls = normalize_media_urls(payload.media_urls.unwrap_or_default())?;
+    let organizer_contact = normalize_contact(payload.organizer_contact.unwrap_or_default())?;
+    let timezone = normalize_timezone(payload.timezone.unwrap_or_default())?;
+    let minimum_age = normalize_minimum_age(payload.minimum_age.unwrap_or_default())?;
+    let class_premiums = normalize_class_premiums(payload.class_premiums.unwrap_or_default())?;
+    let token = normalize_token(payload.token.unwrap_or_default())?;
+    let token_prices = normalize_token_prices(payload.token_prices.unwrap_or_default(), &token)?;
+    let doors_open = payload.doors_open.filter(|time| *time != 0);
+    let end_time = payload.end_time.filter(|time| *time != 0);
+    check_schedule(payload.date, doors_open, end_time)?;
+
+    let organizer_id = organizer_of(caller())?.map(|organizer| organizer.id);
+
+    let event = Event {
+        id: 0,
+        name: payload.name,
+        location: payload.location,
+        date: payload.date,
+        ticket_price: payload.ticket_price,
+        total_tickets: payload.total_tickets,
+        tickets_sold: 0,
+        pricing_mode: payload.pricing_mode.unwrap_or_default(),
+        organizer: Some(caller()),
+        category,
+        status: EventStatus::Draft,
+        venue_id: venue.as_ref().map(|venue| venue.id),
+        series_id,
+        organizer_id,
+        class_premiums,
+        token,
+        token_prices,
+    };
+    let details = EventDetails {
+        event_id: 0,
+        description,
+        tags,
+        media_urls,
+        organizer_contact,
+        doors_open,
+        end_time,
+        timezone,
+        minimum_age,
+    };
+
+    let seat_map = venue.map(|venue| EventSeatMap {
+        event_id: 0,
+        venue_id: Some(venue.id),
+        sections: venue.sections,
+        blocked_seats: Vec::new(),
+    });
+
+    Ok(NewEvent {
+        event,
+        details,
+        seat_map,
+    })
+}
+
+// Helper function to give a validated event its id and write it, with its details, seat
+// map and index entries
+fn write_event(new_event: NewEvent) -> Result<Event, Error> {
+    Ok(save_event(encode_event(new_event)?))
+}
+
+// Helper function to give a new event its id and encode its records
+fn encode_event(new_event: NewEvent) -> Result<EncodedEvent, Error> {
+    let NewEvent {
+        mut event,
+        mut details,
+        mut seat_map,
+    } = new_event;
+    let event_id = next_id()?;
+    event.id = event_id;
+    details.event_id = event_id;
+    if let Some(seat_map) = &mut seat_map {
+        seat_map.event_id = event_id;
+    }
+
+    Ok(EncodedEvent {
+        stored_seat_map: seat_map.as_ref().map(Stored::encode).transpose()?,
+        stored_event: Stored::encode(&event)?,
+        stored_details: Stored::encode(&details)?,
+        event,
+        details,
+    })
+}
+
+// Helper function to write an encoded event and add it to the indexes
+fn save_event(encoded: EncodedEvent) -> Event {
+    let EncodedEvent {
+        event,
+        details,
+        stored_event,
+        stored_details,
+        stored_seat_map,
+    } = encoded;
+    let event_id = event.id;
+    save(&EVENTS_STORAGE, event_id, stored_event);
+    save(&EVENT_DETAILS_STORAGE, event_id, stored_details);
+    if let Some(stored_seat_map) = stored_seat_map {
+        save(&EVENT_SEAT_MAPS_STORAGE, event_id, stored_seat_map);
+    }
+    index_event(&event);
+    index_event_text(&event, &details);
+    if let Some(series_id) = event.series_id {
+        SERIES_OCCURRENCES.with(|index| {
+            index.borrow_mut().insert(
+                IndexKey {
+                    parent_id: series_id,
+                    record_id: event_id,
+                },
+                (),
+            );
+        });
+    }
+
+    event
+}
+
+// Function to get the seat map of an event
+#[ic_cdk::query]
+fn get_event_seating(event_id: u64) -> Result<EventSeatMap, Error> {
+    if load(&EVENTS_STORAGE, event_id)?.is_none() {
+        return Err(Error::EventNotFound { event_id });
+    }
+
+    Ok(
+        load(&EVENT_SEAT_MAPS_STORAGE, event_id)?.unwrap_or(EventSeatMap {
+            event_id,
+            ..Default::default()
+        }),
+    )
+}
+
+// Function to change an event's seat map without touching its venue. Sections are fixed
+// once tickets have sold; blocked seats can change at any time, and resize reserved zones.
+#[ic_cdk::update]
+fn update_event_seating(
+    event_id: u64,
+    payload: UpdateEventSeatingPayload,
+) -> Result<EventSeatMap, Error> {
+    audited(
+        "update_event_seating",
+        AuditEntity::Event,
+        &[event_id],
+        || {
+            let event =
+                load(&EVENTS_STORAGE, event_id)?.ok_or(Error::EventNotFound { event_id })?;
+            require_organizer(&event)?;
+            if event.status.is_final() {
+                return Err(Error::InvalidEventStatus {
+                    event_id,
+                    status: event.status,
+                });
+            }
+
+            let mut seat_map = load(&EVENT_SEAT_MAPS_STORAGE, event_id)?.unwrap_or(EventSeatMap {
+                event_id,
+                venue_id: event.venue_id,
+                ..Default::default()
+            });
+
+            if payload.sections.is_some() {
+                if event.tickets_sold > 0 {
+                    return Err(field_locked("sections"));
+                }
+                if EVENT_ZONES_STORAGE.with(|storage| storage.borrow().contains_key(&event_id)) {
+                    return Err(invalid_field(
+                        "sections",
+                        "remove the event's zones before changing its seat map",
+                    ));
+                }
+            }
+            apply_seating(&event, &mut seat_map, payload)?;
+
+            let stored_seat_map = Stored::encode(&seat_map)?;
+            // Reserved zones hold only the seats that are for sale
+            let mut zoned = None;
+            if let Some(mut event_zones) = load(&EVENT_ZONES_STORAGE, event_id)? {
+                for zone in &mut event_zones.zones {
+                    if zone.kind != ZoneKind::Reserved {
+                        continue;
+                    }
+                    zone.capacity = seat_map
+                        .sections
+                        .iter()
+                        .filter(|section| zone.sections.contains(&section.name))
+                        .map(|section| seats_for_sale(Some(&seat_map), section))
+                        .sum();
+                    if zone.capacity < zone.tickets_sold {
+                        return Err(invalid_field(
+                            "blocked_seats",
+                            &format!(
+                                "would leave zone {} fewer seats than the {} tickets it sold",
+                                zone.name, zone.tickets_sold
+                            ),
+                        ));
+                    }
+                }
+                let mut event = event;
+                event.total_tickets = event_zones.zones.iter().map(|zone| zone.capacity).sum();
+                sync_sold_out(&mut event);
+                zoned = Some((Stored::encode(&event)?, Stored::encode(&event_zones)?));
+            }
+
+            save(&EVENT_SEAT_MAPS_STORAGE, event_id, stored_seat_map);
+            if let Some((stored_event, stored_zones)) = zoned {
+                save(&EVENTS_STORAGE, event_id, stored_event);
+                save(&EVENT_ZONES_STORAGE, event_id, stored_zones);
+            }
+
+            Ok(seat_map)
+        },
+    )
+}
+
+// Function to get the zones of an event
+#[ic_cdk::query]
+fn get_event_zones(event_id: u64) -> Result<EventZones, Error> {
+    if load(&EVENTS_STORAGE, event_id)?.is_none() {
+        return Err(Error::EventNotFound { event_id });
+    }
+
+    Ok(load(&EVENT_ZONES_STORAGE, event_id)?.unwrap_or(EventZones {
+        event_id,
+        zones: Vec::new(),
+    }))
+}
+
+// Function to divide an event into general-admission and reserved zones, or with an
+// empty list to stop using zones. The event's capacity becomes the sum of its zones'.
+#[ic_cdk::update]
+fn set_event_zones(event_id: u64, zones: Vec<EventZonePayload>) -> Result<EventZones, Error> {
+    audited("set_event_zones", AuditEntity::Event, &[event_id], || {
+        let old_event =
+            load(&EVENTS_STORAGE, event_id)?.ok_or(Error::EventNotFound { event_id })?;
+        require_organizer(&old_event)?;
+        if old_event.status.is_final() {
+            return Err(Error::InvalidEventStatus {
+                event_id,
+                status: old_event.status,
+            });
+        }
+        if old_event.tickets_sold > 0 {
+            return Err(field_locked("zones"));
+        }
+        if zones.len() > MAX_ZONES {
+            return Err(invalid_field(
+                "zones",
+                &format!("at most {} zones are allowed", MAX_ZONES),
+            ));
+        }
+
+        if zones.is_empty() {
+            EVENT_ZONES_STORAGE.with(|storage| storage.borrow_mut().remove(&event_id));
+            return Ok(EventZones {
+                event_id,
+                zones: Vec::new(),
+            });
+        }
+
+        let seat_map = load(&EVENT_SEAT_MAPS_STORAGE, event_id)?;
+        let mut event_zones = EventZones {
+            event_id,
+            zones: Vec::new(),
+        };
+        for zone in zones {
+            let name = zone.name.trim().to_string();
+            if name.is_empty() {
+                return Err(missing_field("zone name"));
+            }
+            check_length("zone name", &name, MAX_ZONE_NAME_LENGTH)?;
+            if event_zones.zones.iter().any(|other| other.name == name) {
+                return Err(invalid_field(
+                    "zones",
+                    &format!("zone {} appears twice", name),
+                ));
+            }
+
+            let capacity = match zone.kind {
+                ZoneKind::GeneralAdmission => {
+                    if !zone.sections.is_empty() {
+                        return Err(invalid_field(
+                            "zones",
+                            "general-admission zones have no sections",
+                        ));
+                    }
+                    zone.capacity
+                        .filter(|capacity| *capacity > 0)
+                        .ok_or_else(|| {
+                            invalid_field("zones", "general-admission zones need a capacity")
+                        })?
+                }
+                ZoneKind::Reserved => {
+                    if zone.sections.is_empty() {
+                        return Err(invalid_field("zones", "reserved zones need sections"));
+                    }
+                    let mut seats: u64 = 0;
+                    for section_name in &zone.sections {
+                        let section = seat_map
+                            .as_ref()
+                            .and_then(|seat_map| {
+                                seat_map
+                                    .sections
+                                    .iter()
+                                    .find(|section| section.name == *section_name)
+                            })
+                            .ok_or_else(|| {
+                                invalid_field(
+                                    "zones",
+                                    &format!("section {} is not in the seat map", section_name),
+                                )
+                            })?;
+                        let claimed = event_zones
+                            .zones
+                            .iter()
+                            .any(|other| other.sections.contains(section_name))
+                            || zone
+                                .sections
+                                .iter()
+                                .filter(|name| *name == section_name)
+                                .count()
+                                > 1;
+                        if claimed {
+                            return Err(invalid_field(
+                                "zones",
+                                &format!("section {} is in more than one zone", section_name),
+                            ));
+                        }
+                        seats += seats_for_sale(seat_map.as_ref(), section);
+                    }
+                    seats
+                }
+            };
+
+            event_zones.zones.push(EventZone {
+                name,
+                kind: zone.kind,
+                capacity,
+                tickets_sold: 0,
+                sections: zone.sections,
+            });
+        }
+
+        let mut event = old_event;
+        event.total_tickets = event_zones.zones.iter().map(|zone| zone.capacity).sum();
+        sync_sold_out(&mut event);
+
+        let stored_event = Stored::encode(&event)?;
+        let stored_zones = Stored::encode(&event_zones)?;
+        save(&EVENTS_STORAGE, event_id, stored_event);
+        save(&EVENT_ZONES_STORAGE, event_id, stored_zones);
+
+        Ok(event_zones)
+    })
+}
+
+// Function to register a venue that events can then be created at
+#[ic_cdk::update]
+fn create_venue(payload: CreateVenuePayload) -> Result<Venue, Error> {
+    audited("create_venue", AuditEntity::Venue, &[], || {
+        check_venue(&payload)?;
+
+        let venue_id = next_id()?;
+        let venue = Venue {
+            id: venue_id,
+            owner: caller(),
+            name: payload.name,
+            address: payload.address,
+            coordinates: payload.coordinates,
+            capacity: payload.capacity,
+            sections: payload.sections,
+        };
+
+        store(&VENUES_STORAGE, venue_id, &venue)?;
+
+        Ok(venue)
+    })
+}
+
+// Function to change a venue. Events already created there keep their own copy of the
+// old seat map.
+#[ic_cdk::update]
+fn update_venue(venue_id: u64, payload: CreateVenuePayload) -> Result<Venue, Error> {
+    audited("update_venue", AuditEntity::Venue, &[venue_id], || {
+        let venue = load(&VENUES_STORAGE, venue_id)?.ok_or(Error::VenueNotFound { venue_id })?;
+        let caller = caller();
+        let is_owner = caller != Principal::anonymous() && venue.owner == caller;
+        if !is_owner && !is_controller(&caller) {
+            return Err(Error::Unauthorized {
+                required_role: Role::VenueOwner,
+            });
+        }
+        check_venue(&payload)?;
+
+        let venue = Venue {
+            id: venue_id,
+            owner: venue.owner,
+            name: payload.name,
+            address: payload.address,
+            coordinates: payload.coordinates,
+            capacity: payload.capacity,
+            sections: payload.sections,
+        };
+
+        store(&VENUES_STORAGE, venue_id, &venue)?;
+
+        Ok(venue)
+    })
+}
+
+// Function to get a venue
+#[ic_cdk::query]
+fn get_venue(venue_id: u64) -> Result<Venue, Error> {
+    load(&VENUES_STORAGE, venue_id)?.ok_or(Error::VenueNotFound { venue_id })
+}
+
+// Function to list all venues
+#[ic_cdk::query]
+fn list_venues() -> Vec<Venue> {
+    load_all(&VENUES_STORAGE)
+}
+
+// Function to create an organizer profile for the caller. Events the caller created
+// before having one are moved to it. Their sales so far count towards gross sales and
+// platform fees, but not the balances, since the platform collected them.
+#[ic_cdk::update]
+fn create_organizer(payload: OrganizerPayload) -> Result<Organizer, Error> {
+    audited("create_organizer", AuditEntity::Organizer, &[], || {
+        let caller = caller();
+        if caller == Principal::anonymous() {
+            return Err(invalid_field(
+                "caller",
+                "the anonymous principal cannot organize events",
+            ));
+        }
+        if organizer_of(caller)?.is_some() {
+            return Err(invalid_field("caller", "already has an organizer profile"));
+        }
+
+        let name = check_organizer_name(&payload.name)?;
+        let contact_email = normalize_contact_email(payload.contact_email.unwrap_or_default())?;
+        let jurisdiction = normalize_jurisdiction(payload.jurisdiction.unwrap_or_default())?;
+        let payout_account = payload.payout_account.unwrap_or(Account {
+            owner: caller,
+            subaccount: None,
+        });
+        check_account(&payout_account)?;
+
+        let organizer_id = next_id()?;
+        let organizer = Organizer {
+            id: organizer_id,
+            owner: caller,
+            name,
+            contact_email,
+            payout_account,
+            created_at: time(),
+            jurisdiction,
+        };
+
+        let mut ledgers = LedgerBook::new();
+        ledgers.insert(
+            None,
+            (
+                organizer_id,
+                OrganizerLedger {
+                    organizer_id,
+                    ..Default::default()
+                },
+            ),
+        );
+        // Sales made so far were paid into the canister's default account, not the
+        // organizer's subaccounts, so they are reported but never paid out
+        let mut stored_events = Vec::new();
+        for event_id in events_organized_by(caller) {
+            let Some(mut event) = load(&EVENTS_STORAGE, event_id)? else {
+                continue;
+            };
+            if event.organizer_id.is_some() {
+                continue;
+            }
+            for ticket in load_indexed_tickets(&TICKETS_BY_EVENT, event.id) {
+                let ledger = ledger_in(&mut ledgers, organizer_id, ticket_token(&ticket))?;
+                book_uncollected_sale(ledger, &ticket);
+            }
+            event.organizer_id = Some(organizer_id);
+            stored_events.push((event.id, Stored::encode(&event)?));
+        }
+
+        let stored_organizer = Stored::encode(&organizer)?;
+        let stored_ledgers = encode_ledgers(ledgers)?;
+        save(&ORGANIZERS_STORAGE, organizer_id, stored_organizer);
+        save_ledgers(organizer_id, stored_ledgers);
+        for (event_id, stored_event) in stored_events {
+            save(&EVENTS_STORAGE, event_id, stored_event);
+        }
+        ORGANIZERS_BY_OWNER.with(|index| {
+            index.borrow_mut().insert(
+                TextIndexKey {
+                    text: caller.to_text(),
+                    record_id: organizer_id,
+                },
+                (),
+            );
+        });
+
+        Ok(organizer)
+    })
+}
+
+// Function to update an organizer profile. Fields left out keep their value, and
+// an empty contact_email or jurisdiction removes it.
+#[ic_cdk::update]
+fn update_organizer(organizer_id: u64, payload: OrganizerPayload) -> Result<Organizer, Error> {
+    audited(
+        "update_organizer",
+        AuditEntity::Organizer,
+        &[organizer_id],
+        || {
+            let mut organizer = require_organizer_owner(organizer_id)?;
+
+            organizer.name = check_organizer_name(&payload.name)?;
+            if let Some(contact_email) = payload.contact_email {
+                organizer.contact_email = normalize_contact_email(contact_email)?;
+            }
+            if let Some(payout_account) = payload.payout_account {
+                check_account(&payout_account)?;
+                organizer.payout_account = payout_account;
+            }
+            if let Some(jurisdiction) = payload.jurisdiction {
+                organizer.jurisdiction = normalize_jurisdiction(jurisdiction)?;
+            }
+
+            store(&ORGANIZERS_STORAGE, organizer_id, &organizer)?;
+            Ok(organizer)
+        },
+    )
+}
+
+// Function to get an organizer profile
+#[ic_cdk::query]
+fn get_organizer(organizer_id: u64) -> Result<Organizer, Error> {
+    load(&ORGANIZERS_STORAGE, organizer_id)?.ok_or(Error::OrganizerNotFound { organizer_id })
+}
+
+// Function to get what the platform owes an organizer in a token, or in the default
+// currency if none is given
+#[ic_cdk::query]
+fn get_organizer_ledger(
+    organizer_id: u64,
+    token: Option<String>,
+) -> Result<OrganizerLedger, Error> {
+    require_organizer_owner(organizer_id)?;
+    let token = token.map(|token| token_symbol(&token));
+    find_ledger(organizer_id, token.as_deref()).map(|(_, ledger)| ledger)
+}
+
+// Function to list the payouts an organizer requested, oldest first
+#[ic_cdk::query]
+fn list_payouts(organizer_id: u64) -> Result<Vec<Payout>, Error> {
+    require_organizer_owner(organizer_id)?;
+    let payout_ids: Vec<u64> = PAYOUTS_BY_ORGANIZER.with(|index| {
+        index
+            .borrow()
+            .range(IndexKey::range_of(organizer_id))
+            .map(|(key, _)| key.record_id)
+            .collect()
+    });
+
+    Ok(payout_ids
+        .into_iter()
+        .filter_map(|payout_id| load(&PAYOUTS_STORAGE, payout_id).ok().flatten())
+        .collect())
+}
+
+// Function to set the ICRC-1 ledger default-currency tickets are paid, and payouts made, on.
+// A fresh deploy has none, so paid tickets in the default currency fail until this is called.
+#[ic_cdk::update]
+fn set_payment_ledger(ledger: Principal) -> Result<(), Error> {
+    audited("set_payment_ledger", AuditEntity::Settings, &[], || {
+        require_admin()?;
+        let mut settings = settings()?;
+        settings.payment_ledger = Some(ledger);
+        store(&SETTINGS_STORAGE, SETTINGS_KEY, &settings)
+    })
+}
+
+// Function to set the service fee charged on every ticket
+#[ic_cdk::update]
+fn set_service_fee(service_fee: ServiceFee) -> Result<(), Error> {
+    audited("set_service_fee", AuditEntity::Settings, &[], || {
+        require_admin()?;
+        check_basis_points("basis_points", service_fee.basis_points)?;
+        let mut settings = settings()?;
+        settings.service_fee = Some(service_fee);
+        store(&SETTINGS_STORAGE, SETTINGS_KEY, &settings)
+    })
+}
+
+// Function to get the service fee charged on every ticket
+#[ic_cdk::query]
+fn get_service_fee() -> Result<ServiceFee, Error> {
+    service_fee()
+}
+
+// Function to set the tax rate of a jurisdiction, replacing any it already has
+#[ic_cdk::update]
+fn set_tax_rate(payload: TaxRatePayload) -> Result<TaxRate, Error> {
+    audited("set_tax_rate", AuditEntity::TaxRate, &[], || {
+        require_admin()?;
+        let jurisdiction = payload.jurisdiction.trim().to_ascii_uppercase();
+        if jurisdiction.is_empty() {
+            return Err(missing_field("jurisdiction"));
+        }
+        check_length("jurisdiction", &jurisdiction, MAX_JURISDICTION_LENGTH)?;
+        check_basis_points("basis_points", payload.basis_points)?;
+
+        let id = match find_tax_rate(&jurisdiction) {
+            Some(tax_rate) => tax_rate.id,
+            None => next_id()?,
+        };
+        let tax_rate = TaxRate {
+            id,
+            jurisdiction,
+            basis_points: payload.basis_points,
+            inclusive: payload.inclusive,
+        };
+        store(&TAX_RATES_STORAGE, id, &tax_rate)?;
+        index_text_key(
+            &TAX_RATES_BY_JURISDICTION,
+            tax_rate.jurisdiction.clone(),
+            id,
+        );
+        Ok(tax_rate)
+    })
+}
+
+// Function to remove the tax rate of a jurisdiction no organizer is in
+#[ic_cdk::update]
+fn remove_tax_rate(jurisdiction: String) -> Result<(), Error> {
+    audited("remove_tax_rate", AuditEntity::TaxRate, &[], || {
+        require_admin()?;
+        let jurisdiction = jurisdiction.trim().to_ascii_uppercase();
+        let tax_rate = find_tax_rate(&jurisdiction).ok_or(Error::TaxRateNotFound {
+            jurisdiction: jurisdiction.clone(),
+        })?;
+        let in_use = load_all(&ORGANIZERS_STORAGE)
+            .iter()
+            .any(|organizer| organizer.jurisdiction.as_deref() == Some(jurisdiction.as_str()));
+        if in_use {
+            return Err(invalid_field(
+                "jurisdiction",
+                "still has organizers selling in it",
+            ));
+        }
+
+        TAX_RATES_STORAGE.with(|storage| storage.borrow_mut().remove(&tax_rate.id));
+        TAX_RATES_BY_JURISDICTION.with(|index| {
+            index.borrow_mut().remove(&TextIndexKey {
+                text: jurisdiction,
+                record_id: tax_rate.id,
+            })
+        });
+        Ok(())
+    })
+}
+
+// Function to list the tax rates of every jurisdiction
+#[ic_cdk::query]
+fn list_tax_rates() -> Vec<TaxRate> {
+    load_all(&TAX_RATES_STORAGE)
+}
+
+// Function to register an ICRC-1 token events can be priced in, or update one
+#[ic_cdk::update]
+fn set_token(payload: TokenPayload) -> Result<Token, Error> {
+    audited("set_token", AuditEntity::Token, &[], || {
+        require_admin()?;
+        let symbol = token_symbol(&payload.symbol);
+        if symbol.is_empty() {
+            return Err(missing_field("symbol"));
+        }
+        check_length("symbol", &symbol, MAX_TOKEN_SYMBOL_LENGTH)?;
+        if payload.decimals > MAX_TOKEN_DECIMALS {
+            return Err(invalid_field(
+                "decimals",
+                &format!("must be at most {}", MAX_TOKEN_DECIMALS),
+            ));
+        }
+
+        // Balances already owed in the token are held on its ledger, and prices and
+        // balances are kept in its smallest unit
+        let id = match find_token(&symbol) {
+            Some(token) if token.ledger != payload.ledger => {
+                return Err(invalid_field(
+                    "ledger",
+                    "cannot change once the token is registered",
+                ))
+            }
+            Some(token) if token.decimals != payload.decimals => {
+                return Err(invalid_field(
+                    "decimals",
+                    "cannot change once the token is registered",
+                ))
+            }
+            Some(token) => token.id,
+            None => next_id()?,
+        };
+        let token = Token {
+            id,
+            symbol,
+            ledger: payload.ledger,
+            decimals: payload.decimals,
+            flat_fee: payload.flat_fee,
+            loyalty_rate: payload.loyalty_rate,
+        };
+        store(&TOKENS_STORAGE, id, &token)?;
+        index_text_key(&TOKENS_BY_SYMBOL, token.symbol.clone(), id);
+        Ok(token)
+    })
+}
+
+// Function to list the tokens events can be priced in
+#[ic_cdk::query]
+fn list_tokens() -> Vec<Token> {
+    load_all(&TOKENS_STORAGE)
+}
+
+// Function to transfer an organizer's settled balance in a token, or in the default
+// currency if none is given, to their payout account. It is paid from the organizer's
+// subaccount for that token, where their ticket sales in it were collected, which also
+// pays the ledger's transfer fee.
+#[ic_cdk::update]
+async fn request_payout(organizer_id: u64, token: Option<String>) -> Result<Payout, Error> {
+    let result = pay_out(organizer_id, token).await;
+    record_audit(
+        "request_payout",
+        AuditEntity::Payout,
+        &[organizer_id],
+        &result,
+    );
+    result
+}
+
+// Helper function to make a payout, recording it before the transfer so the balance
+// cannot be paid out twice
+async fn pay_out(organizer_id: u64, token: Option<String>) -> Result<Payout, Error> {
+    require_organizer_owner(organizer_id)?;
+    let token = token.map(|token| token_symbol(&token));
+    let payment_ledger = payment_ledger(token.as_deref())?.ok_or_else(|| Error::PayoutFailed {
+        reason: "no payment ledger is configured".to_string(),
+    })?;
+    let subaccount = organizer_subaccount(organizer_id, token.as_deref());
+    let collected = collected_balance(payment_ledger, &subaccount).await?;
+    let fee = ledger_fee(payment_ledger)
+        .await
+        .map_err(|reason| Error::PayoutFailed { reason })?;
+
+    // Everything below runs without awaiting until the transfer, so the balance read
+    // above cannot be spent by another payout in between
+    let organizer = require_organizer_owner(organizer_id)?;
+    let (ledger_id, mut ledger) = find_ledger(organizer_id, token.as_deref())?;
+    let amount = ledger.settled_balance;
+    let ledger_id = match ledger_id {
+        Some(ledger_id) if amount > 0 => ledger_id,
+        _ => return Err(Error::NoSettledBalance { organizer_id }),
+    };
+    // The subaccount pays the transfer fee on top of the amount
+    let needed = amount.saturating_add(nat_to_u64(&fee));
+    if collected < needed {
+        return Err(Error::PayoutFailed {
+            reason: format!(
+                "the ledger holds {} of the {} the payout and its fee need",
+                collected, needed
+            ),
+        });
+    }
+
+    let payout_id = next_id()?;
+    let mut payout = Payout {
+        id: payout_id,
+        organizer_id,
+        token,
+        amount,
+        to: organizer.payout_account,
+        requested_at: time(),
+        status: PayoutStatus::Pending,
+    };
+
+    // The amount leaves the settled balance before the transfer is awaited, so a
+    // second request made in the meantime cannot pay it out again
+    ledger.settled_balance = 0;
+    let stored_payout = Stored::encode(&payout)?;
+    let stored_ledger = Stored::encode(&ledger)?;
+    save(&PAYOUTS_STORAGE, payout_id, stored_payout);
+    save(&ORGANIZER_LEDGERS_STORAGE, ledger_id, stored_ledger);
+    PAYOUTS_BY_ORGANIZER.with(|index| {
+        index.borrow_mut().insert(
+            IndexKey {
+                parent_id: organizer_id,
+                record_id: payout_id,
+            },
+            (),
+        );
+    });
+
+    // The memo and creation time let the ledger reject the transfer if it is ever repeated
+    let transfer = TransferArg {
+        from_subaccount: Some(subaccount),
+        to: payout.to.clone(),
+        amount: candid::Nat::from(amount),
+        fee: None,
+        memo: Some(payout_id.to_be_bytes().to_vec()),
+        created_at_time: Some(payout.requested_at),
+    };
+    let result: ic_cdk::api::call::CallResult<(Result<candid::Nat, TransferError>,)> =
+        call(payment_ledger, "icrc1_transfer", (transfer,)).await;
+
+    // Other calls may have booked sales while the transfer was in flight
+    let mut ledger = load(&ORGANIZER_LEDGERS_STORAGE, ledger_id)
+        .and_then(|ledger| ledger.ok_or(Error::OrganizerNotFound { organizer_id }))
+        .unwrap_or_else(|err| {
+            ic_cdk::trap(&format!("Cannot record payout {}: {}", payout_id, err))
+        });
+    payout.status = match result {
+        Ok((Ok(block_index),)) => {
+            ledger.paid_out += amount;
+            PayoutStatus::Completed { block_index }
+        }
+        Ok((Err(err),)) => {
+            ledger.settled_balance += amount;
+            PayoutStatus::Failed {
+                reason: transfer_error_reason(&err),
+            }
+        }
+        Err((code, message)) => {
+            ledger.settled_balance += amount;
+            PayoutStatus::Failed {
+                reason: rejection_reason(code, &message),
+            }
+        }
+    };
+
+    // Trapping here only undoes this last step, leaving the payout Pending and its
+    // amount out of the settled balance, so it can never be paid out twice
+    store(&ORGANIZER_LEDGERS_STORAGE, ledger_id, &ledger)
+        .and_then(|_| store(&PAYOUTS_STORAGE, payout_id, &payout))
+        .unwrap_or_else(|err| {
+            ic_cdk::trap(&format!("Cannot record payout {}: {}", payout_id, err))
+        });
+
+    match payout.status {
+        PayoutStatus::Failed { reason } => Err(Error::PayoutFailed { reason }),
+        _ => Ok(payout),
+    }
+}
+
+// Helper function to ask a ledger for its transfer fee, or why it did not answer
+async fn ledger_fee(ledger: Principal) -> Result<candid::Nat, String> {
+    let result: ic_cdk::api::call::CallResult<(candid::Nat,)> = call(ledger, "icrc1_fee", ()).await;
+    result
+        .map(|(fee,)| fee)
+        .map_err(|(code, message)| rejection_reason(code, &message))
+}
+
+// Helper function to read the balance of one of the canister's subaccounts on a ledger
+async fn collected_balance(ledger: Principal, subaccount: &[u8]) -> Result<u64, Error> {
+    let account = Account {
+        owner: canister_id(),
+        subaccount: Some(subaccount.to_vec()),
+    };
+    let result: ic_cdk::api::call::CallResult<(candid::Nat,)> =
+        call(ledger, "icrc1_balance_of", (account,)).await;
+    match result {
+        Ok((balance,)) => Ok(nat_to_u64(&balance)),
+        Err((code, message)) => Err(Error::PayoutFailed {
+            reason: rejection_reason(code, &message),
+        }),
+    }
+}
+
+// Function to get the descriptive metadata of an event
+#[ic_cdk::query]
+fn get_event_details(event_id: u64) -> Result<EventDetails, Error> {
+    if load(&EVENTS_STORAGE, event_id)?.is_none() {
+        return Err(Error::EventNotFound { event_id });
+    }
+
+    event_details(event_id)
+}
+
+// Function to edit an event. Everything can change while it is a draft; once published,
+// only its capacity can, and never below the tickets already sold or the seats it maps.
+#[ic_cdk::update]
+fn update_event(event_id: u64, payload: UpdateEventPayload) -> Result<Event, Error> {
+    audited("update_event", AuditEntity::Event, &[event_id], || {
+        let old_event =
+            load(&EVENTS_STORAGE, event_id)?.ok_or(Error::EventNotFound { event_id })?;
+        require_organizer(&old_event)?;
+        if old_event.status.is_final() {
+            return Err(Error::InvalidEventStatus {
+                event_id,
+                status: old_event.status,
+            });
+        }
+        if old_event.status != EventStatus::Draft {
+            if let Some(field) = draft_only_field(&payload) {
+                return Err(field_locked(field));
+            }
+        }
+        let old_details = event_details(event_id)?;
+
+        let mut event = old_event.clone();
+        let mut details = old_details.clone();
+
+        if let Some(name) = payload.name {
+            if name.is_empty() {
+                return Err(missing_field("name"));
+            }
+            check_length("name", &name, MAX_EVENT_NAME_LENGTH)?;
+            event.name = name;
+        }
+        if let Some(location) = payload.location {
+            if location.is_empty() {
+                return Err(missing_field("location"));
+            }
+            check_length("location", &location, MAX_LOCATION_LENGTH)?;
+            event.location = location;
+        }
+        if let Some(date) = payload.date {
+            if date == 0 {
+                return Err(missing_field("date"));
+            }
+            event.date = date;
+        }
+        if let Some(ticket_price) = payload.ticket_price {
+            if ticket_price == 0 {
+                return Err(invalid_field("ticket_price", "must be greater than zero"));
+            }
+            event.ticket_price = ticket_price;
+        }
+        if let Some(total_tickets) = payload.total_tickets {
+            if total_tickets == 0 {
+                return Err(invalid_field("total_tickets", "must be greater than zero"));
+            }
+            let has_zones =
+                EVENT_ZONES_STORAGE.with(|storage| storage.borrow().contains_key(&event_id));
+            if has_zones && total_tickets != event.total_tickets {
+                return Err(invalid_field(
+                    "total_tickets",
+                    "is the sum of the event's zone capacities",
+                ));
+            }
+            if total_tickets < event.tickets_sold {
+                return Err(invalid_field(
+                    "total_tickets",
+                    &format!(
+                        "cannot be less than the {} tickets already sold",
+                        event.tickets_sold
+                    ),
+                ));
+            }
+            if let Some(seat_map) = load(&EVENT_SEAT_MAPS_STORAGE, event_id)? {
+                let seats = check_sections(&seat_map.sections)?;
+                if total_tickets < seats {
+                    return Err(invalid_field(
+                        "total_tickets",
+                        &format!("cannot be less than the {} seats in the seat map", seats),
+                    ));
+                }
+            }
+            event.total_tickets = total_tickets;
+        }
+        if let Some(pricing_mode) = payload.pricing_mode {
+            event.pricing_mode = pricing_mode;
+        }
+        if payload.category.is_some() {
+            event.category = normalize_category(payload.category)?;
+        }
+        if let Some(class_premiums) = payload.class_premiums {
+            event.class_premiums = normalize_class_premiums(class_premiums)?;
+        }
+        if let Some(token) = payload.token {
+            event.token = normalize_token(token)?;
+        }
+        // Checked again when the token changes, as the event cannot list its own token here
+        let token_prices = payload.token_prices.or_else(|| event.token_prices.take());
+        event.token_prices =
+            normalize_token_prices(token_prices.unwrap_or_default(), &event.token)?;
+        if let Some(description) = payload.description {
+            details.description = normalize_description(description)?;
+        }
+        if let Some(tags) = payload.tags {
+            details.tags = normalize_tags(tags)?;
+        }
+        if let Some(media_urls) = payload.media_urls {
+            details.media_urls = normalize_media_urls(media_urls)?;
+        }
+        if let Some(organizer_contact) = payload.organizer_contact {
+            details.organizer_contact = normalize_contact(organizer_contact)?;
+        }
+        if let Some(timezone) = payload.timezone {
+            details.timezone = normalize_timezone(timezone)?;
+        }
+        if let Some(minimum_age) = payload.minimum_age {
+            details.minimum_age = normalize_minimum_age(minimum_age)?;
+        }
+        if let Some(doors_open) = payload.doors_open {
+            details.doors_open = Some(doors_open).filter(|time| *time != 0);
+        }
+        if let Some(end_time) = payload.end_time {
+            details.end_time = Some(end_time).filter(|time| *time != 0);
+        }
+        check_schedule(event.date, details.doors_open, details.end_time)?;
+        sync_sold_out(&mut event);
+
+        let stored_event = Stored::encode(&event)?;
+        let stored_details = Stored::encode(&details)?;
+        unindex_event(&old_event);
+        unindex_event_text(&old_event, &old_details);
+        save(&EVENTS_STORAGE, event_id, stored_event);
+        save(&EVENT_DETAILS_STORAGE, event_id, stored_details);
+        index_event(&event);
+        index_event_text(&event, &details);
+
+        Ok(event)
+    })
+}
+
+// Helper function to name the first field of an event update that only drafts accept
+fn draft_only_field(payload: &UpdateEventPayload) -> Option<&'static str> {
+    [
+        ("name", payload.name.is_some()),
+        ("location", payload.location.is_some()),
+        ("date", payload.date.is_some()),
+        ("ticket_price", payload.ticket_price.is_some()),
+        ("pricing_mode", payload.pricing_mode.is_some()),
+        ("category", payload.category.is_some()),
+        ("description", payload.description.is_some()),
+        ("tags", payload.tags.is_some()),
+        ("media_urls", payload.media_urls.is_some()),
+        ("organizer_contact", payload.organizer_contact.is_some()),
+        ("doors_open", payload.doors_open.is_some()),
+        ("end_time", payload.end_time.is_some()),
+        ("timezone", payload.timezone.is_some()),
+        ("minimum_age", payload.minimum_age.is_some()),
+        ("class_premiums", payload.class_premiums.is_some()),
+        ("token", payload.token.is_some()),
+        ("token_prices", payload.token_prices.is_some()),
+    ]
+    .into_iter()
+    .find(|(_, set)| *set)
+    .map(|(field, _)| field)
+}
+
+// Function to make a draft event visible
+#[ic_cdk::update]
+fn publish_event(event_id: u64) -> Result<Event, Error> {
+    audited("publish_event", AuditEntity::Event, &[event_id], || {
+        change_event_status(event_id, EventStatus::Published, None)
+    })
+}
+
+// Function to open, or resume, ticket sales for an event
+#[ic_cdk::update]
+fn open_sales(event_id: u64) -> Result<Event, Error> {
+    audited("open_sales", AuditEntity::Event, &[event_id], || {
+        change_event_status(event_id, EventStatus::OnSale, None)
+    })
+}
+
+// Function to postpone an event to a new date; sales stay closed until open_sales
+#[ic_cdk::update]
+fn postpone_event(event_id: u64, new_date: u64) -> Result<Event, Error> {
+    audited("postpone_event", AuditEntity::Event, &[event_id], || {
+        if new_date == 0 {
+            return Err(missing_field("new_date"));
+        }
+        change_event_status(event_id, EventStatus::Postponed, Some(new_date))
+    })
+}
+
+// Function to cancel an event
+#[ic_cdk::update]
+fn cancel_event(event_id: u64) -> Result<Event, Error> {
+    audited("cancel_event", AuditEntity::Event, &[event_id], || {
+        change_event_status(event_id, EventStatus::Cancelled, None)
+    })
+}
+
+// Function to mark an event as having taken place
+#[ic_cdk::update]
+fn complete_event(event_id: u64) -> Result<Event, Error> {
+    audited("complete_event", AuditEntity::Event, &[event_id], || {
+        change_event_status(event_id, EventStatus::Completed, None)
+    })
+}
+
+// Function to archive a cancelled or completed event
+#[ic_cdk::update]
+fn archive_event(event_id: u64) -> Result<Event, Error> {
+    audited("archive_event", AuditEntity::Event, &[event_id], || {
+        change_event_status(event_id, EventStatus::Archived, None)
+    })
+}
+
+// Function to purchase a ticket
+#[ic_cdk::update]
+async fn purchase_ticket(payload: PurchaseTicketPayload) -> Result<Ticket, Error> {
+    let target_ids = [payload.event_id, payload.user_id];
+    let result = process_purchase(payload).await;
+    record_audit("purchase_ticket", AuditEntity::Ticket, &target_ids, &result);
+    result
+}
+
+// Function to list all events
+#[ic_cdk::query]
+fn list_all_events() -> Vec<Event> {
+    load_all(&EVENTS_STORAGE)
+}
+
+// Function to search events with filters, a sort order and cursor pagination
+#[ic_cdk::query]
+fn search_events(payload: SearchEventsPayload) -> Result<EventPage, Error> {
+    let order = payload.sort.clone().unwrap_or_default();
+    let cursor = payload.cursor.as_deref().map(parse_cursor).transpose()?;
+    let limit = payload
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE) as usize;
+
+    let location = payload.location.as_deref().map(index_text);
+    let category = payload.category.as_deref().map(index_text);
+    let matches = |event: &Event| {
+        let price = quote_price(event);
+        payload.date_from.map_or(true, |from| event.date >= from)
+            && payload.date_to.map_or(true, |to| event.date <= to)
+            && location.as_ref().map_or(true, |location| {
+                index_text(&event.location).starts_with(location)
+            })
+            && payload.min_price.map_or(true, |min| price >= min)
+            && payload.max_price.map_or(true, |max| price <= max)
+            && payload.has_availability.map_or(true, |available| {
+                (event.tickets_sold < event.total_tickets) == available
+            })
+            && payload
+                .organizer
+                .map_or(true, |organizer| event.organizer == Some(organizer))
+            && category.as_ref().map_or(true, |category| {
+                event.category.as_deref().map(index_text).as_ref() == Some(category)
+            })
+            && match payload.status {
+                Some(status) => event.status == status,
+                None => is_listed(event),
+            }
+    };
+    // Details are only loaded for events that pass the filters above
+    let tag = payload.tag.as_deref().map(|tag| tag.trim().to_lowercase());
+    let listing = |event: Event| {
+        let details = event_details(event.id).ok()?;
+        let suitable = tag.as_ref().map_or(true, |tag| details.tags.contains(tag))
+            && payload.audience_age.map_or(true, |age| {
+                details
+                    .minimum_age
+                    .map_or(true, |minimum_age| age >= minimum_age)
+            });
+        suitable.then_some(EventListing { event, details })
+    };
+
+    // Without a location filter, date-ordered results are streamed straight from the date
+    // index, or its inverted twin for latest first, and stop once the page is full or
+    // MAX_SEARCH_SCAN entries were looked at. A page cut short by the scan limit still has
+    // a next_cursor, pointing past the last entry looked at. Otherwise candidates come from
+    // the location index, or from the date index within the date range, and are sorted in
+    // memory; only the first MAX_SEARCH_SCAN are considered, and `truncated` is set if
+    // there were more.
+    let mut resume_at = None;
+    let mut truncated = false;
+    let mut page: Vec<(u64, EventListing)> = match (&order, &location) {
+        (EventSortOrder::DateAscending | EventSortOrder::DateDescending, None) => {
+            let descending = matches!(order, EventSortOrder::DateDescending);
+            // Maps a date to its position in the index walked, and back
+            let key_date = |date: u64| if descending { u64::MAX - date } else { date };
+            let (index, first, last) = if descending {
+                (&EVENTS_BY_DATE_DESC, payload.date_to, payload.date_from)
+            } else {
+                (&EVENTS_BY_DATE, payload.date_from, payload.date_to)
+            };
+            let start = cursor
+                .map(|(date, event_id)| IndexKey {
+                    parent_id: key_date(date),
+                    record_id: event_id.saturating_add(1),
+                })
+                .unwrap_or(IndexKey {
+                    parent_id: first.map_or(0, key_date),
+                    record_id: 0,
+                });
+            let end = IndexKey {
+                parent_id: last.map_or(u64::MAX, key_date),
+                record_id: u64::MAX,
+            };
+
+            let mut page = Vec::new();
+            let mut scanned = 0;
+            let mut last_position = None;
+            index.with(|index| {
+                for (key, _) in index.borrow().range(start..=end) {
+                    if page.len() > limit {
+                        break;
+                    }
+                    if scanned == MAX_SEARCH_SCAN {
+                        resume_at = last_position;
+                        break;
+                    }
+                    scanned += 1;
+                    let date = key_date(key.parent_id);
+                    last_position = Some((date, key.record_id));
+                    let found = load(&EVENTS_STORAGE, key.record_id)
+                        .ok()
+                        .flatten()
+                        .filter(matches)
+                        .and_then(listing);
+                    if let Some(listing) = found {
+                        page.push((date, listing));
+                    }
+                }
+            });
+            page
+        }
+        _ => {
+            let mut event_ids = match &location {
+                Some(location) => events_by_location_prefix(location),
+                None => events_by_date_range(
+                    payload.date_from.unwrap_or(0),
+                    payload.date_to.unwrap_or(u64::MAX),
+                ),
+            };
+            truncated = event_ids.len() > MAX_SEARCH_SCAN;
+            event_ids.truncate(MAX_SEARCH_SCAN);
+
+            let mut matching: Vec<(u64, Event)> = event_ids
+                .into_iter()
+                .filter_map(|event_id| load(&EVENTS_STORAGE, event_id).ok().flatten())
+                .filter(&matches)
+                .map(|event| (sort_value(&order, &event), event))
+                .collect();
+            matching.sort_by(|(a_value, a), (b_value, b)| {
+                compare_positions(&order, (*a_value, a.id), (*b_value, b.id))
+            });
+
+            matching
+                .into_iter()
+                .filter(|(value, event)| {
+                    cursor.map_or(true, |cursor| {
+                        compare_positions(&order, (*value, event.id), cursor)
+                            == std::cmp::Ordering::Greater
+                    })
+                })
+                .filter_map(|(value, event)| listing(event).map(|listing| (value, listing)))
+                .take(limit + 1)
+                .collect()
+        }
+    };
+
+    let next_cursor = if page.len() > limit {
+        page.truncate(limit);
+        page.last()
+            .map(|(value, listing)| (*value, listing.event.id))
+    } else {
+        resume_at
+    };
+
+    Ok(EventPage {
+        events: page.into_iter().map(|(_, listing)| listing).collect(),
+        next_cursor: next_cursor.map(|(value, event_id)| format!("{}-{}", value, event_id)),
+        truncated,
+    })
+}
+
+// Function to search events by text, best matches first. Every word of the query must
+// match a word of the event, either exactly or as a prefix ("jaz" matches "jazz").
+#[ic_cdk::query]
+fn search_events_text(query: String, limit: u32) -> Vec<EventListing> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let query_tokens: Vec<String> = tokenize(&query)
+        .into_iter()
+        .take(MAX_QUERY_TOKENS)
+        .collect();
+    if query_tokens.is_empty() {
+        return Vec::new();
+    }
+
+    // Each query token scores an event by its best matching token; exact matches count double
+    let mut scores: BTreeMap<u64, u32> = BTreeMap::new();
+    for (position, query_token) in query_tokens.iter().enumerate() {
+        let mut token_scores: BTreeMap<u64, u32> = BTreeMap::new();
+        EVENT_TOKENS.with(|index| {
+            for (key, weight) in index
+                .borrow()
+                .range(
+                    TextIndexKey {
+                        text: query_token.clone(),
+                        record_id: 0,
+                    }..,
+                )
+                .take_while(|(key, _)| key.text.starts_with(query_token.as_str()))
+                .take(MAX_TOKEN_MATCHES)
+            {
+                let score = if key.text == *query_token {
+                    weight * 2
+                } else {
+                    weight
+                };
+                let best = token_scores.entry(key.record_id).or_default();
+                *best = (*best).max(score);
+            }
+        });
+
+        if position == 0 {
+            scores = token_scores;
+        } else {
+            scores = scores
+                .into_iter()
+                .filter_map(|(event_id, score)| {
+                    token_scores
+                        .get(&event_id)
+                        .map(|token_score| (event_id, score + token_score))
+                })
+                .collect();
+        }
+        if scores.is_empty() {
+            return Vec::new();
+        }
+    }
+
+    let mut ranked: Vec<(u32, Event)> = scores
+        .into_iter()
+        .filter_map(|(event_id, score)| {
+            load(&EVENTS_STORAGE, event_id)
+                .ok()
+                .flatten()
+                .filter(is_listed)
+                .map(|event| (score, event))
+        })
+        .collect();
+    ranked.sort_by(|(a_score, a), (b_score, b)| {
+        b_score
+            .cmp(a_score)
+            .then(a.date.cmp(&b.date))
+            .then(a.id.cmp(&b.id))
+    });
+
+    ranked
+        .into_iter()
+        .take(limit)
+        .filter_map(|(_, event)| {
+            let details = event_details(event.id).ok()?;
+            Some(EventListing { event, details })
+        })
+        .collect()
+}
+
+// Function to list tickets for a user
+#[ic_cdk::query]
+fn list_tickets_for_user(user_id: u64) -> Vec<Ticket> {
+    load_indexed_tickets(&TICKETS_BY_USER, user_id)
+}
+
+// Function to list tickets for an event
+#[ic_cdk::query]
+fn list_tickets_for_event(event_id: u64) -> Vec<Ticket> {
+    load_indexed_tickets(&TICKETS_BY_EVENT, event_id)
+}
+
+// Function to report an event's sales: totals, sales per hour or day, and check-in
+// and refund rates. Restricted to the event's organizer.
+#[ic_cdk::query]
+fn get_sales_report(event_id: u64, interval: SalesInterval) -> Result<SalesReport, Error> {
+    let event = load(&EVENTS_STORAGE, event_id)?.ok_or(Error::EventNotFound { event_id })?;
+    require_organizer(&event)?;
+    let sales = event_sales(event_id)?;
+
+    let bucket_map = match interval {
+        SalesInterval::Hour => &SALES_BY_HOUR,
+        SalesInterval::Day => &SALES_BY_DAY,
+    };
+    let buckets = bucket_map.with(|map| {
+        map.borrow()
+            .range(IndexKey::range_of(event_id))
+            .map(|(key, tickets)| SalesBucket {
+                start: key.record_id,
+                tickets,
+            })
+            .collect()
+    });
+    let averages = sales
+        .currencies
+        .iter()
+        .map(|currency| CurrencyAverages {
+            token: currency.token.clone(),
+            average_price: currency.revenue / currency.tickets.max(1),
+            average_dynamic_adjustment: currency.dynamic_adjustments
+                / currency.tickets.max(1) as i64,
+        })
+        .collect();
+
+    Ok(SalesReport {
+        check_in_rate: share_of(sales.tickets_checked_in, sales.tickets_sold),
+        refund_rate: share_of(sales.tickets_refunded, sales.tickets_sold),
+        sales,
+        buckets,
+        averages,
+    })
+}
+
+// Function to export an event's attendees for venue security, a chunk of tickets at a
+// time. Restricted to the event's organizer.
+#[ic_cdk::query]
+fn export_attendees(event_id: u64, options: ExportOptions) -> Result<ExportChunk, Error> {
+    export_tickets(event_id, options, &ATTENDEE_COLUMNS, |ticket, redact| {
+        let user = load(&USERS_STORAGE, ticket.user_id).ok().flatten();
+        vec![
+            json!(ticket.id),
+            redacted(
+                redact,
+                ExportField::Username,
+                json!(user.as_ref().map(|user| &user.username)),
+            ),
+            redacted(
+                redact,
+                ExportField::Email,
+                json!(user.as_ref().map(|user| &user.email)),
+            ),
+            redacted(redact, ExportField::Seat, json!(ticket.seat_number)),
+            redacted(redact, ExportField::Seat, json!(ticket.zone)),
+            redacted(redact, ExportField::Price, json!(ticket.price)),
+            redacted(
+                redact,
+                ExportField::PurchaseDate,
+                json!(ticket.purchase_date),
+            ),
+            redacted(
+                redact,
+                ExportField::CheckIn,
+                json!(ticket.checked_in_at.is_some()),
+            ),
+            redacted(redact, ExportField::CheckIn, json!(ticket.checked_in_at)),
+        ]
+    })
+}
+
+// Function to export an event's sales ledger for accounting, one row per ticket with its
+// price breakdown. Tickets sold before prices were itemized only have a total.
+// Restricted to the event's organizer.
+#[ic_cdk::query]
+fn export_sales(event_id: u64, options: ExportOptions) -> Result<ExportChunk, Error> {
+    export_tickets(event_id, options, &SALES_COLUMNS, |ticket, redact| {
+        let username = load(&USERS_STORAGE, ticket.user_id)
+            .ok()
+            .flatten()
+            .map(|user| user.username);
+        let breakdown = ticket.breakdown.as_ref();
+        let amount = |value: Option<serde_json::Value>| {
+            redacted(redact, ExportField::Price, value.unwrap_or_default())
+        };
+        vec![
+            json!(ticket.id),
+            redacted(
+                redact,
+                ExportField::PurchaseDate,
+                json!(ticket.purchase_date),
+            ),
+            redacted(redact, ExportField::Username, json!(username)),
+            json!(breakdown.and_then(|breakdown| breakdown.token.as_ref())),
+            amount(breakdown.map(|breakdown| json!(breakdown.base))),
+            amount(breakdown.map(|breakdown| json!(breakdown.class_premium))),
+            amount(breakdown.map(|breakdown| json!(breakdown.dynamic_adjustment))),
+            amount(breakdown.map(|breakdown| json!(breakdown.loyalty_discount))),
+            amount(breakdown.map(|breakdown| json!(breakdown.fees))),
+            amount(breakdown.map(|breakdown| json!(breakdown.tax))),
+            amount(breakdown.map(|breakdown| json!(breakdown.tax_inclusive))),
+            amount(Some(json!(ticket.price))),
+        ]
+    })
+}
+
+// Function to give a ticket to another user. Tickets can change hands until they are
+// checked in or their event is over.
+#[ic_cdk::update]
+fn transfer_ticket(ticket_id: u64, to_user_id: u64) -> Result<Ticket, Error> {
+    audited(
+        "transfer_ticket",
+        AuditEntity::Ticket,
+        &[ticket_id, to_user_id],
+        || {
+            let mut ticket =
+                load(&TICKETS_STORAGE, ticket_id)?.ok_or(Error::TicketNotFound { ticket_id })?;
+            let from = require_account_owner(ticket.user_id)?;
+            let to = load(&USERS_STORAGE, to_user_id)?.ok_or(Error::UserNotFound {
+                user_id: to_user_id,
+            })?;
+            if to.id == from.id {
+                return Err(invalid_field("to_user_id", "already holds the ticket"));
+            }
+            if ticket.checked_in_at.is_some() {
+                return Err(Error::TicketCheckedIn { ticket_id });
+            }
+            let event = load(&EVENTS_STORAGE, ticket.event_id)?.ok_or(Error::EventNotFound {
+                event_id: ticket.event_id,
+            })?;
+            if event.status.is_final() {
+                return Err(Error::InvalidEventStatus {
+                    event_id: event.id,
+                    status: event.status,
+                });
+            }
+
+            ticket.user_id = to.id;
+            store(&TICKETS_STORAGE, ticket_id, &ticket)?;
+            TICKETS_BY_USER.with(|index| {
+                let mut index = index.borrow_mut();
+                index.remove(&IndexKey {
+                    parent_id: from.id,
+                    record_id: ticket_id,
+                });
+                index.insert(
+                    IndexKey {
+                        parent_id: to.id,
+                        record_id: ticket_id,
+                    },
+                    (),
+                );
+            });
+            record_block(
+                BLOCK_TYPE_TRANSFER,
+                vec![
+                    ("tid".to_string(), nat_value(ticket_id)),
+                    ("from".to_string(), account_value(from.owner)),
+                    ("to".to_string(), account_value(to.owner)),
+                    ("from_user".to_string(), nat_value(from.id)),
+                    ("to_user".to_string(), nat_value(to.id)),
+                ],
+                time(),
+            );
+            Ok(ticket)
+        },
+    )
+}
+
+// Function to check a ticket in at the door. Restricted to the event's organizer.
+#[ic_cdk::update]
+fn check_in_ticket(ticket_id: u64) -> Result<Ticket, Error> {
+    audited("check_in_ticket", AuditEntity::Ticket, &[ticket_id], || {
+        let mut ticket =
+            load(&TICKETS_STORAGE, ticket_id)?.ok_or(Error::TicketNotFound { ticket_id })?;
+        let event = load(&EVENTS_STORAGE, ticket.event_id)?.ok_or(Error::EventNotFound {
+            event_id: ticket.event_id,
+        })?;
+        require_organizer(&event)?;
+        if event.status.is_final() || event.status == EventStatus::Draft {
+            return Err(Error::InvalidEventStatus {
+                event_id: event.id,
+                status: event.status,
+            });
+        }
+        if ticket.checked_in_at.is_some() {
+            return Err(Error::TicketCheckedIn { ticket_id });
+        }
+
+        let now = time();
+        ticket.checked_in_at = Some(now);
+        let mut sales = event_sales(event.id)?;
+        sales.tickets_checked_in += 1;
+        let stored_sales = Stored::encode(&sales)?;
+        store(&TICKETS_STORAGE, ticket_id, &ticket)?;
+        save(&EVENT_SALES_STORAGE, event.id, stored_sales);
+        record_block(
+            BLOCK_TYPE_CHECK_IN,
+            vec![
+                ("tid".to_string(), nat_value(ticket_id)),
+                ("from".to_string(), account_value(holder(ticket.user_id))),
+                ("user".to_string(), nat_value(ticket.user_id)),
+                ("event".to_string(), nat_value(ticket.event_id)),
+            ],
+            now,
+        );
+        Ok(ticket)
+    })
+}
+
+// Function to read blocks of the ticket transaction log, as ICRC-3 describes
+#[ic_cdk::query]
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    BLOCK_LOG.with(|log| {
+        let log = log.borrow();
+        let mut blocks = Vec::new();
+        for range in args {
+            let start = nat_to_u64(&range.start);
+            let length = nat_to_u64(&range.length);
+            let end = start.saturating_add(length).min(log.len());
+            for id in start..end {
+                if blocks.len() as u64 == MAX_BLOCKS_PER_CALL {
+                    break;
+                }
+                // Blocks are only ever written by this canister, so they always decode
+                if let Some(block) = log.get(id).and_then(|stored| stored.decode().ok()) {
+                    blocks.push(BlockWithId {
+                        id: candid::Nat::from(id),
+                        block,
+                    });
+                }
+            }
+        }
+        GetBlocksResult {
+            log_length: candid::Nat::from(log.len()),
+            blocks,
+            archived_blocks: Vec::new(),
+        }
+    })
+}
+
+// Function to get the certified hash and index of the last block of the transaction log
+#[ic_cdk::query]
+fn icrc3_get_tip_certificate() -> Option<DataCertificate> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let (last_index, last_hash) = block_tip()?;
+    Some(DataCertificate {
+        certificate,
+        hash_tree: tip_witness(last_index, last_hash).to_cbor(),
+    })
+}
+
+// Function to list the canisters archiving old blocks; the log is never archived
+#[ic_cdk::query]
+fn icrc3_get_archives(_args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+    Vec::new()
+}
+
+// Function to list the block types the transaction log contains
+#[ic_cdk::query]
+fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    vec![
+        SupportedBlockType {
+            block_type: BLOCK_TYPE_MINT.to_string(),
+            url: ICRC7_URL.to_string(),
+        },
+        SupportedBlockType {
+            block_type: BLOCK_TYPE_TRANSFER.to_string(),
+            url: ICRC7_URL.to_string(),
+        },
+        SupportedBlockType {
+            block_type: BLOCK_TYPE_CHECK_IN.to_string(),
+            url: ICRC3_URL.to_string(),
+        },
+    ]
+}
+
+// Function to answer HTTP requests from the gateway. Pages rendered since the last update
+// call are served with a certificate; the others are upgraded to http_request_update.
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method == "GET" {
+        if let Some(response) = certified_response(&request_path(&request.url)) {
+            return response;
+        }
+    }
+    HttpResponse {
+        status_code: 200,
+        headers: Vec::new(),
+        body: Vec::new(),
+        upgrade: Some(true),
+    }
+}
+
+// Function to render an HTTP response through consensus and keep it, so that later
+// requests for the same path are answered by certified queries. Not audited: it changes
+// nothing but the kept responses, which audited calls empty.
+#[ic_cdk::update]
+fn http_request_update(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        let mut response = text_response(405, "Only GET requests are served");
+        response
+            .headers
+            .push(("Allow".to_string(), "GET".to_string()));
+        return response;
+    }
+
+    let path = request_path(&request.url);
+    let response = render_path(&path);
+    if response.status_code < 500 {
+        keep_response(path, &response);
+    }
+    response
+}
+
+// Function to list the ICRC standards the canister implements
+#[ic_cdk::query]
+fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-3".to_string(),
+            url: "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-3/README.md".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-10/ICRC-10.md".to_string(),
+        },
+    ]
+}
+
+// Function for controllers to award points for a purchase made elsewhere; tickets bought
+// here earn their points in the purchase pipeline. Open to any caller before API version 2.
+#[ic_cdk::update]
+fn award_loyalty_points(user_id: u64, purchase_amount: u64) -> Result<LoyaltyPoints, Error> {
+    audited(
+        "award_loyalty_points",
+        AuditEntity::Loyalty,
+        &[user_id],
+        || {
+            require_admin()?;
+
+            let points_earned = calculate_points(purchase_amount);
+
+            add_loyalty_points(
+                user_id,
+                points_earned,
+                format!("Points earned from purchase: {}", purchase_amount),
+            )
+        },
+    )
+}
+
+// Helper function to credit points to a user's loyalty account
+fn add_loyalty_points(
+    user_id: u64,
+    points_earned: u64,
+    description: String,
+) -> Result<LoyaltyPoints, Error> {
+    let credit = credit_loyalty_points(user_id, vec![(points_earned, description)])?;
+    Ok(save_loyalty_credit(credit))
+}
+
+// Helper function to work out a loyalty account credited with awards of points, each
+// with its own history entry, without writing anything
+fn credit_loyalty_points(user_id: u64, awards: Vec<(u64, String)>) -> Result<LoyaltyCredit, Error> {
+    let mut loyalty = load(&LOYALTY_STORAGE, user_id)?.unwrap_or_default();
+    loyalty.user_id = user_id;
+
+    let mut entries = Vec::with_capacity(awards.len());
+    for (seq, (points, description)) in (next_points_seq(user_id)..).zip(awards) {
+        loyalty.points += points;
+        entries.push(encode_points_entry(
+            user_id,
+            seq,
+            points as i64,
+            description,
+        )?);
+    }
+
+    // Update tier based on total points
+    loyalty.tier = match loyalty.points {
+        points if points >= 10000 => LoyaltyTier::Platinum,
+        points if points >= 5000 => LoyaltyTier::Gold,
+        points if points >= 2000 => LoyaltyTier::Silver,
+        _ => LoyaltyTier::Bronze,
+    };
+
+    Ok(LoyaltyCredit {
+        stored_loyalty: Stored::encode(&loyalty)?,
+        loyalty,
+        entries,
+    })
+}
+
+// Helper function to write a loyalty credit, returning the account with its recent history
+fn save_loyalty_credit(credit: LoyaltyCredit) -> LoyaltyPoints {
+    save(
+        &LOYALTY_STORAGE,
+        credit.loyalty.user_id,
+        credit.stored_loyalty,
+    );
+    save_points_entries(credit.entries);
+    with_recent_history(credit.loyalty)
+}
+
+// Helper function to key entry number seq of a user's points history
+fn points_key(user_id: u64, seq: u64) -> IndexKey {
+    IndexKey {
+        parent_id: user_id,
+        record_id: u64::MAX - seq,
+    }
+}
+
+// Helper function to get the sequence number of the next entry of a user's points history
+fn next_points_seq(user_id: u64) -> u64 {
+    // The newest entry comes first in the user's range
+    POINTS_HISTORY.with(|history| {
+        history
+            .borrow()
+            .range(IndexKey::range_of(user_id))
+            .next()
+            .map_or(0, |(key, _)| u64::MAX - key.record_id + 1)
+    })
+}
+
+// Helper function to encode entry number seq of a user's points history
+fn encode_points_entry(
+    user_id: u64,
+    seq: u64,
+    points: i64,
+    description: String,
+) -> Result<(IndexKey, Stored<PointsTransaction>), Error> {
+    let entry = PointsTransaction {
+        timestamp: time(),
+        points,
+        description,
+    };
+    Ok((points_key(user_id, seq), Stored::encode(&entry)?))
+}
+
+fn save_points_entries(entries: Vec<(IndexKey, Stored<PointsTransaction>)>) {
+    POINTS_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        for (key, stored) in entries {
+            history.insert(key, stored);
+        }
+    });
+}
+
+// Helper function to list a user's points history, newest first, from a cursor on.
+// Returns the cursor of the entry after the last one listed, if there is one.
+fn points_history(
+    user_id: u64,
+    cursor: u64,
+    limit: usize,
+) -> (Vec<PointsTransaction>, Option<u64>) {
+    POINTS_HISTORY.with(|history| {
+        let mut entries = Vec::new();
+        for (key, stored) in history
+            .borrow()
+            .range(IndexKey::range_from(user_id, cursor))
+        {
+            if entries.len() == limit {
+                return (entries, Some(key.record_id));
+            }
+            // Like load_all, entries that fail to decode are left out
+            if let Ok(entry) = stored.decode() {
+                entries.push(entry);
+            }
+        }
+        (entries, None)
+    })
+}
+
+// Helper function to fill in the newest entries of a loyalty account's history
+fn with_recent_history(mut loyalty: LoyaltyPoints) -> LoyaltyPoints {
+    loyalty.points_history = points_history(loyalty.user_id, 0, RECENT_POINTS_HISTORY).0;
+    loyalty
+}
+
+// Function for an account's owner to redeem its points for rewards. Open to any caller
+// before API version 2.
+#[ic_cdk::update]
+fn redeem_points(user_id: u64, points_to_redeem: u64) -> Result<String, Error> {
+    audited("redeem_points", AuditEntity::Loyalty, &[user_id], || {
+        require_account_owner(user_id)?;
+
+        let mut loyalty =
+            load(&LOYALTY_STORAGE, user_id)?.ok_or(Error::LoyaltyAccountNotFound { user_id })?;
+
+        if loyalty.points < points_to_redeem {
+            return Err(Error::InsufficientPoints {
+                have: loyalty.points,
+                need: points_to_redeem,
+            });
+        }
+
+        loyalty.points -= points_to_redeem;
+        let entry = encode_points_entry(
+            user_id,
+            next_points_seq(user_id),
+            -(points_to_redeem as i64),
+            "Points redemption".to_string(),
+        )?;
+
+        store(&LOYALTY_STORAGE, user_id, &loyalty)?;
+        save_points_entries(vec![entry]);
+        Ok("Points successfully redeemed!".to_string())
+    })
+}
+
+// Function for an account's owner to page through its points history, newest first
+#[ic_cdk::query]
+fn get_points_history(
+    user_id: u64,
+    cursor: Option<u64>,
+    limit: Option<u32>,
+) -> Result<PointsHistoryPage, Error> {
+    require_account_owner(user_id)?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+    let (entries, next_cursor) = points_history(user_id, cursor.unwrap_or(0), limit);
+    Ok(PointsHistoryPage {
+        entries,
+        next_cursor,
+    })
+}
+
+// Kept for existing clients; the event's pricing mode decides the price either way
+#[ic_cdk::update]
+async fn purchase_ticket_with_dynamic_pricing(
+    payload: PurchaseTicketPayload,
+) -> Result<Ticket, Error> {
+    let target_ids = [payload.event_id, payload.user_id];
+    let result = process_purchase(payload).await;
+    record_audit(
+        "purchase_ticket_with_dynamic_pricing",
+        AuditEntity::Ticket,
+        &target_ids,
+        &result,
+    );
+    result
+}
+
+// Function to buy several seats of one class, picking the best seats still free: the
+// adjacent seats nearest the middle of the best row that has enough of them, where
+// sections and rows rank in the order the seat map lists them
+#[ic_cdk::update]
+async fn purchase_best_available(payload: BestAvailablePayload) -> Result<Vec<Ticket>, Error> {
+    let target_ids = [payload.event_id, payload.user_id];
+    let result = buy_best_available(payload).await;
+    record_audit(
+        "purchase_best_available",
+        AuditEntity::Ticket,
+        &target_ids,
+        &result,
+    );
+    result
+}
+
+// Helper function to pick the seats of a best-available order and buy them
+async fn buy_best_available(payload: BestAvailablePayload) -> Result<Vec<Ticket>, Error> {
+    if payload.quantity == 0 || payload.quantity > MAX_TICKETS_PER_ORDER {
+        return Err(invalid_field(
+            "quantity",
+            &format!("must be between 1 and {}", MAX_TICKETS_PER_ORDER),
+        ));
+    }
+
+    let event = load(&EVENTS_STORAGE, payload.event_id)?.ok_or(Error::EventNotFound {
+        event_id: payload.event_id,
+    })?;
+    let seat_map = load(&EVENT_SEAT_MAPS_STORAGE, event.id)?
+        .ok_or_else(|| invalid_field("event_id", "the event has no seat map"))?;
+
+    // On events with zones, seats can only come from reserved zones
+    let zone_sections: Option<Vec<String>> = match load(&EVENT_ZONES_STORAGE, event.id)? {
+        Some(zones) => {
+            let reserved = zones
+                .zones
+                .into_iter()
+                .filter(|zone| zone.kind == ZoneKind::Reserved);
+            match payload.zone.as_deref().map(str::trim) {
+                Some(name) => {
+                    let zone = reserved
+                        .into_iter()
+                        .find(|zone| zone.name == name)
+                        .ok_or_else(|| Error::UnknownZone {
+                            zone: name.to_string(),
+                        })?;
+                    Some(zone.sections)
+                }
+                None => Some(reserved.flat_map(|zone| zone.sections).collect()),
+            }
+        }
+        None => None,
+    };
+
+    let remaining = event.total_tickets.saturating_sub(event.tickets_sold);
+    if remaining < u64::from(payload.quantity) {
+        return Err(Error::InsufficientSeats {
+            requested: payload.quantity,
+            available: remaining,
+        });
+    }
+
+    let seats = allocate_seats(
+        &seat_map,
+        zone_sections.as_deref(),
+        payload.seat_class,
+        payload.quantity,
+        payload.allow_split.unwrap_or(false),
+    )?;
+
+    process_order(
+        seats
+            .into_iter()
+            .map(|seat| PurchaseTicketPayload {
+                event_id: event.id,
+                user_id: payload.user_id,
+                seat_number: seat.label(),
+                seat: Some(seat),
+                zone: None,
+                token: payload.token.clone(),
+            })
+            .collect(),
+    )
+    .await
+}
+
+// Function to price a ticket without buying it, item by item
+#[ic_cdk::query]
+fn quote_ticket(mut payload: PurchaseTicketPayload) -> Result<PriceBreakdown, Error> {
+    if let Some(seat) = &payload.seat {
+        payload.seat_number = seat.label();
+    }
+    let (event, _, seat_class) = validate_purchase(&payload)?;
+    price_ticket(&payload, &event, seat_class)
+}
+
+// Helper function to buy a single ticket through process_order
+async fn process_purchase(payload: PurchaseTicketPayload) -> Result<Ticket, Error> {
+    let mut tickets = process_order(vec![payload]).await?;
+    Ok(tickets.remove(0))
+}
+
+// Single purchase pipeline shared by every purchase endpoint:
+// validation -> pricing -> discounts -> fees and tax -> payment -> mint -> loyalty.
+// Every ticket of an order is for the same event and buyer. Nothing is written unless the
+// whole order goes through, and a payment taken for an order that then fails is refunded.
+async fn process_order(payloads: Vec<PurchaseTicketPayload>) -> Result<Vec<Ticket>, Error> {
+    let order = prepare_order(payloads.clone())?;
+    let Some(payment) = payment_for(&order)? else {
+        return commit_order(order, None);
+    };
+    collect_payment(&payment).await?;
+
+    // Other calls ran while the payment was collected, so the order is checked again.
+    // The buyer keeps the prices they paid.
+    let target_ids = [order.event.id, order.user_id];
+    let result = prepare_order(payloads).and_then(|mut checked| {
+        // The payment went to the subaccount of the organizer the event had before
+        if checked.event.organizer_id != order.event.organizer_id {
+            return Err(Error::PaymentFailed {
+                reason: "the event moved to another organizer during payment".to_string(),
+            });
+        }
+        for (ticket, (_, _, paid)) in checked.tickets.iter_mut().zip(order.tickets) {
+            ticket.2 = paid;
+        }
+        commit_order(checked, Some(payment.from.owner))
+    });
+    if result.is_err() {
+        let refund = refund_payment(&payment).await;
+        record_audit("refund_payment", AuditEntity::Ticket, &target_ids, &refund);
+    }
+    result
+}
+
+// Helper function to validate and price every ticket of an order
+fn prepare_order(payloads: Vec<PurchaseTicketPayload>) -> Result<PreparedOrder, Error> {
+    let mut tickets = Vec::new();
+    let mut first_event = None;
+    let mut seats = BTreeSet::new();
+    let mut zone_counts: BTreeMap<String, u64> = BTreeMap::new();
+    for mut payload in payloads {
+        if let Some(seat) = &payload.seat {
+            payload.seat_number = seat.label();
+        }
+        let (mut event, zone, seat_class) = validate_purchase(&payload)?;
+
+        // Each ticket is checked against what is stored; the order as a whole must also
+        // fit the event and its zones, and not take a seat twice
+        let requested = tickets.len() as u64 + 1;
+        let available = event.total_tickets.saturating_sub(event.tickets_sold);
+        if requested > available {
+            return Err(Error::InsufficientSeats {
+                requested: requested as u32,
+                available,
+            });
+        }
+        let seat_number = payload.seat_number.trim().to_string();
+        if !seat_number.is_empty() && !seats.insert(seat_number.clone()) {
+            return Err(Error::SeatTaken { seat: seat_number });
+        }
+        if let Some(name) = &zone {
+            let count = zone_counts.entry(name.clone()).or_default();
+            *count += 1;
+            let zone_full = load(&EVENT_ZONES_STORAGE, event.id)?
+                .and_then(|zones| zones.zones.into_iter().find(|zone| zone.name == *name))
+                .is_some_and(|zone| zone.tickets_sold + *count > zone.capacity);
+            if zone_full {
+                return Err(Error::ZoneSoldOut { zone: name.clone() });
+            }
+        }
+
+        // Tickets earlier in the order count towards demand-based prices
+        event.tickets_sold += tickets.len() as u64;
+        let breakdown = price_ticket(&payload, &event, seat_class)?;
+        event.tickets_sold -= tickets.len() as u64;
+        first_event.get_or_insert(event);
+        tickets.push((payload, zone, breakdown));
+    }
+
+    let (Some(event), Some((first, _, _))) = (first_event, tickets.first()) else {
+        return Err(missing_field("seat_number"));
+    };
+    let user_id = first.user_id;
+    require_verified_email(user_id)?;
+    Ok(PreparedOrder {
+        user_id,
+        event,
+        tickets,
+    })
+}
+
+// Helper function to mint the tickets of a paid order and credit its loyalty points and
+// referral reward. The payer is whoever the order's payment was collected from; free
+// orders have none.
+fn commit_order(order: PreparedOrder, payer: Option<Principal>) -> Result<Vec<Ticket>, Error> {
+    // Points and referral rewards are worked out before the tickets are minted, so a
+    // failure in either fails the purchase before anything is written
+    let awards = order
+        .tickets
+        .iter()
+        .map(|(_, _, breakdown)| {
+            let description = match &breakdown.token {
+                Some(token) => format!(
+                    "Points earned from purchase: {} {}",
+                    breakdown.ticket_amount(),
+                    token
+                ),
+                None => format!("Points earned from purchase: {}", breakdown.ticket_amount()),
+            };
+            (loyalty_points_for(breakdown), description)
+        })
+        .collect();
+    let credit = credit_loyalty_points(order.user_id, awards)?;
+    let referral_reward = reward_referral_on_first_purchase(order.user_id, payer)?;
+
+    let tickets = mint_tickets(order.event, order.tickets)?;
+    save_loyalty_credit(credit);
+    if let Some(referral_reward) = referral_reward {
+        save_referral_reward(referral_reward);
+    }
+
+    Ok(tickets)
+}
+
+// Helper function to work out what an order collects from the caller, and on which
+// ledger. Free orders collect nothing.
+fn payment_for(order: &PreparedOrder) -> Result<Option<Payment>, Error> {
+    let amount = order
+        .tickets
+        .iter()
+        .try_fold(0u64, |amount, (_, _, breakdown)| {
+            amount.checked_add(breakdown.total)
+        })
+        .ok_or_else(price_too_large)?;
+    if amount == 0 {
+        return Ok(None);
+    }
+    let token = order.tickets[0].2.token.clone();
+    if order
+        .tickets
+        .iter()
+        .any(|(_, _, breakdown)| breakdown.token != token)
+    {
+        return Err(invalid_field("token", "an order is paid in a single token"));
+    }
+
+    let ledger = payment_ledger(token.as_deref())?.ok_or_else(|| Error::PaymentFailed {
+        reason: "no payment ledger is configured".to_string(),
+    })?;
+    Ok(Some(Payment {
+        ledger,
+        from: Account {
+            owner: caller(),
+            subaccount: None,
+        },
+        to_subaccount: order
+            .event
+            .organizer_id
+            .map(|organizer_id| organizer_subaccount(organizer_id, token.as_deref())),
+        amount,
+        memo: next_id()?,
+    }))
+}
+
+// Helper function to find the ledger payments in a token, or in the default currency,
+// are made on
+fn payment_ledger(token: Option<&str>) -> Result<Option<Principal>, Error> {
+    match token {
+        Some(symbol) => Ok(Some(
+            find_token(symbol)
+                .ok_or_else(|| Error::UnknownToken {
+                    token: symbol.to_string(),
+                })?
+                .ledger,
+        )),
+        None => Ok(settings()?.payment_ledger),
+    }
+}
+
+// Helper function to get the subaccount of the canister's account that an organizer's
+// ticket sales in a token are paid into and paid out from. Each token has its own, so
+// tokens that share a ledger never pay out each other's sales. Sales of events without
+// an organizer go to the default account.
+fn organizer_subaccount(organizer_id: u64, token: Option<&str>) -> Vec<u8> {
+    // The leading 1 keeps organizer 0 apart from the default account; the default
+    // currency leaves the token bytes zero
+    let mut subaccount = vec![0; ICRC1_SUBACCOUNT_LENGTH];
+    subaccount[0] = 1;
+    if let Some(token) = token {
+        let hash = Sha256::digest(token);
+        subaccount[1..ICRC1_SUBACCOUNT_LENGTH - 8]
+            .copy_from_slice(&hash[..ICRC1_SUBACCOUNT_LENGTH - 9]);
+    }
+    subaccount[ICRC1_SUBACCOUNT_LENGTH - 8..].copy_from_slice(&organizer_id.to_be_bytes());
+    subaccount
+}
+
+// Helper function to take an order's payment from the buyer, who must have approved the
+// canister to spend the amount plus the ledger's fee
+async fn collect_payment(payment: &Payment) -> Result<(), Error> {
+    let transfer = TransferFromArgs {
+        spender_subaccount: None,
+        from: payment.from.clone(),
+        to: Account {
+            owner: canister_id(),
+            subaccount: payment.to_subaccount.clone(),
+        },
+        amount: candid::Nat::from(payment.amount),
+        fee: None,
+        memo: Some(payment.memo.to_be_bytes().to_vec()),
+        created_at_time: Some(time()),
+    };
+    let result: ic_cdk::api::call::CallResult<(Result<candid::Nat, TransferFromError>,)> =
+        call(payment.ledger, "icrc2_transfer_from", (transfer,)).await;
+
+    let reason = match result {
+        Ok((Ok(_),)) => return Ok(()),
+        Ok((Err(err),)) => transfer_from_error_reason(&err),
+        Err((code, message)) => rejection_reason(code, &message),
+    };
+    Err(Error::PaymentFailed { reason })
+}
+
+// Helper function to send a collected payment back to the buyer, less the ledger's fee
+// for the transfer
+async fn refund_payment(payment: &Payment) -> Result<(), Error> {
+    let fee = ledger_fee(payment.ledger)
+        .await
+        .map_err(|reason| Error::PaymentFailed { reason })?;
+
+    let transfer = TransferArg {
+        from_subaccount: payment.to_subaccount.clone(),
+        to: payment.from.clone(),
+        amount: candid::Nat::from(payment.amount.saturating_sub(nat_to_u64(&fee))),
+        fee: Some(fee),
+        memo: Some(payment.memo.to_be_bytes().to_vec()),
+        created_at_time: Some(time()),
+    };
+    let result: ic_cdk::api::call::CallResult<(Result<candid::Nat, TransferError>,)> =
+        call(payment.ledger, "icrc1_transfer", (transfer,)).await;
+
+    let reason = match result {
+        Ok((Ok(_),)) => return Ok(()),
+        Ok((Err(err),)) => transfer_error_reason(&err),
+        Err((code, message)) => rejection_reason(code, &message),
+    };
+    Err(Error::PaymentFailed { reason })
+}
+
+// Helper function to check a purchase request against the buyer, event and seating.
+// Only the buyer's owner can buy, or quote, on their account.
+// Returns the event, the zone and the class of the seat, where there are any.
+fn validate_purchase(
+    payload: &PurchaseTicketPayload,
+) -> Result<(Event, Option<String>, Option<SeatClass>), Error> {
+    let seat_number = payload.seat_number.trim();
+    check_length("seat_number", seat_number, MAX_SEAT_NUMBER_LENGTH)?;
+
+    require_account_owner(payload.user_id)?;
+
+    let event = load(&EVENTS_STORAGE, payload.event_id)?.ok_or(Error::EventNotFound {
+        event_id: payload.event_id,
+    })?;
+
+    match event.status {
+        EventStatus::OnSale if event.tickets_sold < event.total_tickets => {}
+        EventStatus::OnSale | EventStatus::SoldOut => {
+            return Err(Error::SoldOut { event_id: event.id });
+        }
+        status => {
+            return Err(Error::InvalidEventStatus {
+                event_id: event.id,
+                status,
+            })
+        }
+    }
+
+    let zone = match load(&EVENT_ZONES_STORAGE, event.id)? {
+        Some(zones) => Some(select_zone(zones, payload.zone.as_deref(), seat_number)?),
+        None => None,
+    };
+    if let Some(zone) = &zone {
+        if zone.tickets_sold >= zone.capacity {
+            return Err(Error::ZoneSoldOut {
+                zone: zone.name.clone(),
+            });
+        }
+        if zone.kind == ZoneKind::GeneralAdmission {
+            if !seat_number.is_empty() {
+                return Err(invalid_field(
+                    "seat_number",
+                    "general-admission zones have no seats",
+                ));
+            }
+            return Ok((event, Some(zone.name.clone()), None));
+        }
+    }
+
+    if seat_number.is_empty() {
+        return Err(missing_field("seat_number"));
+    }
+
+    // Events without a seat map or seating plan accept any seat label
+    let unknown_seat = || Error::UnknownSeat {
+        seat: seat_number.to_string(),
+    };
+    let mut seat_class = None;
+    if let Some(seat_map) = load(&EVENT_SEAT_MAPS_STORAGE, event.id)? {
+        let (section, _, _) =
+            find_seat(&seat_map.sections, seat_number).ok_or_else(unknown_seat)?;
+        seat_class = Some(section.seat_class);
+        if seat_map
+            .blocked_seats
+            .iter()
+            .any(|seat| seat == seat_number)
+        {
+            return Err(Error::SeatNotForSale {
+                seat: seat_number.to_string(),
+            });
+        }
+    } else if let Some(seating) = load(&SEATING_STORAGE, event.id)? {
+        let class = [
+            (SeatClass::Vip, &seating.vip_seats),
+            (SeatClass::Premium, &seating.premium_seats),
+            (SeatClass::Standard, &seating.standard_seats),
+        ]
+        .into_iter()
+        .find(|(_, seats)| seats.iter().any(|seat| seat == seat_number))
+        .map(|(class, _)| class)
+        .ok_or_else(unknown_seat)?;
+        seat_class = Some(class);
+    }
+
+    if is_seat_taken(event.id, seat_number) {
+        return Err(Error::SeatTaken {
+            seat: seat_number.to_string(),
+        });
+    }
+
+    Ok((event, zone.map(|zone| zone.name), seat_class))
+}
+
+// Helper function to pick the zone a purchase is for: the one it names, or for a
+// reserved seat the zone holding the seat's section
+fn select_zone(
+    zones: EventZones,
+    requested: Option<&str>,
+    seat_number: &str,
+) -> Result<EventZone, Error> {
+    let section = SeatId::parse(seat_number).map(|seat| seat.section);
+    let in_zone = |zone: &EventZone| {
+        section
+            .as_ref()
+            .is_some_and(|section| zone.sections.contains(section))
+    };
+
+    let zone = match requested.map(str::trim) {
+        Some(name) => zones
+            .zones
+            .into_iter()
+            .find(|zone| zone.name == name)
+            .ok_or_else(|| Error::UnknownZone {
+                zone: name.to_string(),
+            })?,
+        None => zones
+            .zones
+            .into_iter()
+            .find(|zone| zone.kind == ZoneKind::Reserved && in_zone(zone))
+            .ok_or_else(|| missing_field("zone"))?,
+    };
+
+    if zone.kind == ZoneKind::Reserved && !seat_number.is_empty() && !in_zone(&zone) {
+        return Err(Error::UnknownSeat {
+            seat: seat_number.to_string(),
+        });
+    }
+    Ok(zone)
+}
+
+// Helper function to itemize the price of a ticket in the token it is paid with: base
+// price and class premium, adjusted for demand, less discounts, then the service fee and tax
+fn price_ticket(
+    payload: &PurchaseTicketPayload,
+    event: &Event,
+    seat_class: Option<SeatClass>,
+) -> Result<PriceBreakdown, Error> {
+    let premium = seat_class
+        .and_then(|class| {
+            event
+                .class_premiums
+                .iter()
+                .flatten()
+                .find(|premium| premium.seat_class == class)
+        })
+        .map_or(0, |premium| premium.premium);
+
+    let requested = payload
+        .token
+        .as_deref()
+        .map(token_symbol)
+        .filter(|token| !token.is_empty() && Some(token) != event.token.as_ref());
+    let (token, base, class_premium) = match requested {
+        None => (event.token.clone(), event.ticket_price, premium),
+        Some(token) => {
+            let price = event
+                .token_prices
+                .iter()
+                .flatten()
+                .find(|price| price.token == token)
+                .ok_or_else(|| Error::TokenNotAccepted {
+                    token: token.clone(),
+                })?
+                .price;
+            // Class premiums are set in the event's own token and scale with the price
+            let premium = (premium as u128 * price as u128)
+                .checked_div(event.ticket_price as u128)
+                .map_or(Ok(0), u64::try_from)
+                .map_err(|_| price_too_large())?;
+            (Some(token), price, premium)
+        }
+    };
+
+    let list_price = base
+        .checked_add(class_premium)
+        .ok_or_else(price_too_large)?;
+    let adjusted_price = adjust_for_demand(event, list_price);
+    let loyalty_tier = load(&LOYALTY_STORAGE, payload.user_id)?.map(|loyalty| loyalty.tier);
+    let price = apply_loyalty_discount(loyalty_tier, adjusted_price);
+
+    // Fees are charged on the price before tax, and are not taxed themselves. The flat
+    // part of the fee is set per token.
+    let fee = service_fee()?;
+    let flat_fee = match &token {
+        Some(token) => find_token(token).map_or(0, |token| token.flat_fee),
+        None => fee.flat,
+    };
+    let fees = flat_fee
+        .checked_add(basis_points_of(price, fee.basis_points))
+        .ok_or_else(price_too_large)?;
+    let (tax, tax_inclusive) = match tax_rate_for(event)? {
+        Some(rate) if rate.inclusive => {
+            let untaxed = price as u128 * MAX_BASIS_POINTS as u128
+                / (MAX_BASIS_POINTS + rate.basis_points) as u128;
+            (price - untaxed as u64, true)
+        }
+        Some(rate) => (basis_points_of(price, rate.basis_points), false),
+        None => (0, false),
+    };
+    let added_tax = if tax_inclusive { 0 } else { tax };
+    let total = price
+        .checked_add(fees)
+        .and_then(|total| total.checked_add(added_tax))
+        .ok_or_else(price_too_large)?;
+    let dynamic_adjustment = i64::try_from(adjusted_price as i128 - list_price as i128)
+        .map_err(|_| price_too_large())?;
+
+    Ok(PriceBreakdown {
+        token,
+        base,
+        seat_class,
+        class_premium,
+        dynamic_adjustment,
+        loyalty_tier,
+        loyalty_discount: adjusted_price - price,
+        fees,
+        tax,
+        tax_inclusive,
+        total,
+    })
+}
+
+// Helper function to reject a price whose items add up to more than can be charged
+fn price_too_large() -> Error {
+    invalid_field(
+        "ticket_price",
+        "the price and its premium, fees and tax are too large to charge",
+    )
+}
+
+// Helper function to price a ticket according to the event's pricing mode
+fn quote_price(event: &Event) -> u64 {
+    adjust_for_demand(event, event.ticket_price)
+}
+
+// Helper function to adjust a list price according to the event's pricing mode
+fn adjust_for_demand(event: &Event, list_price: u64) -> u64 {
+    match event.pricing_mode {
+        PricingMode::Fixed => list_price,
+        PricingMode::Dynamic => {
+            // Calculate dynamic price based on demand
+            let demand_multiplier = (event.tickets_sold as f64 / event.total_tickets as f64) + 0.5;
+            (list_price as f64 * demand_multiplier) as u64
+        }
+    }
+}
+
+// Helper function to load the service fee, which defaults to PLATFORM_FEE_BPS
+fn service_fee() -> Result<ServiceFee, Error> {
+    Ok(settings()?.service_fee.unwrap_or(ServiceFee {
+        flat: 0,
+        basis_points: PLATFORM_FEE_BPS,
+    }))
+}
+
+// Helper function to find the tax rate of the jurisdiction an event's organizer is in
+fn tax_rate_for(event: &Event) -> Result<Option<TaxRate>, Error> {
+    let organizer = match event.organizer_id {
+        Some(organizer_id) => load(&ORGANIZERS_STORAGE, organizer_id)?,
+        None => None,
+    };
+    Ok(organizer
+        .and_then(|organizer| organizer.jurisdiction)
+        .and_then(|jurisdiction| find_tax_rate(&jurisdiction)))
+}
+
+// Helper function to look up the tax rate of a jurisdiction
+fn find_tax_rate(jurisdiction: &str) -> Option<TaxRate> {
+    let id = first_indexed(&TAX_RATES_BY_JURISDICTION, jurisdiction)?;
+    load(&TAX_RATES_STORAGE, id).ok().flatten()
+}
+
+// Helper function to apply the buyer's loyalty tier discount
+fn apply_loyalty_discount(tier: Option<LoyaltyTier>, price: u64) -> u64 {
+    match tier {
+        Some(tier) => match tier {
+            LoyaltyTier::Platinum => percent_of(price, 80), // 20% discount
+            LoyaltyTier::Gold => percent_of(price, 85),     // 15% discount
+            LoyaltyTier::Silver => percent_of(price, 90),   // 10% discount
+            LoyaltyTier::Bronze => percent_of(price, 95),   // 5% discount
+        },
+        None => price,
+    }
+}
+
+// Helper function to take a percentage of a price, which never exceeds the price
+fn percent_of(price: u64, percent: u64) -> u64 {
+    (price as u128 * percent as u128 / 100) as u64
+}
+
+// Helper function to issue an order's tickets and record the sales on the event. Every
+// record is encoded before any is written, so a failure leaves nothing but used-up ids.
+fn mint_tickets(
+    mut event: Event,
+    order: Vec<(PurchaseTicketPayload, Option<String>, PriceBreakdown)>,
+) -> Result<Vec<Ticket>, Error> {
+    // Only seats validated against a seat map are recorded as structured seats
+    let has_seat_map =
+        EVENT_SEAT_MAPS_STORAGE.with(|storage| storage.borrow().contains_key(&event.id));
+    let mut zones = load(&EVENT_ZONES_STORAGE, event.id)?;
+    let mut ledgers = LedgerBook::new();
+    let mut sales = event_sales(event.id)?;
+
+    let mut tickets = Vec::new();
+    for (payload, zone, breakdown) in order {
+        let seat_number = payload.seat_number.trim().to_string();
+        let seat = if has_seat_map {
+            SeatId::parse(&seat_number)
+        } else {
+            None
+        };
+
+        let ticket = Ticket {
+            id: next_id()?,
+            event_id: event.id,
+            user_id: payload.user_id,
+            purchase_date: time(),
+            seat_number,
+            price: breakdown.total,
+            seat,
+            zone,
+            breakdown: Some(breakdown),
+            checked_in_at: None,
+        };
+
+        event.tickets_sold += 1;
+        if let (Some(name), Some(zones)) = (&ticket.zone, zones.as_mut()) {
+            if let Some(zone) = zones.zones.iter_mut().find(|zone| zone.name == *name) {
+                zone.tickets_sold += 1;
+            }
+        }
+        if let Some(organizer_id) = event.organizer_id {
+            book_sale(
+                ledger_in(&mut ledgers, organizer_id, ticket_token(&ticket))?,
+                &ticket,
+            );
+        }
+        record_sale(&mut sales, &ticket);
+        tickets.push(ticket);
+    }
+    sync_sold_out(&mut event);
+
+    let stored_zones = match zones {
+        Some(zones) if tickets.iter().any(|ticket| ticket.zone.is_some()) => {
+            Some(Stored::encode(&zones)?)
+        }
+        _ => None,
+    };
+    let stored_ledgers = encode_ledgers(ledgers)?;
+    let stored_sales = Stored::encode(&sales)?;
+    let stored_tickets = tickets
+        .iter()
+        .map(|ticket| Ok((ticket.id, Stored::encode(ticket)?)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    let stored_event = Stored::encode(&event)?;
+    save(&EVENTS_STORAGE, event.id, stored_event);
+    save(&EVENT_SALES_STORAGE, event.id, stored_sales);
+    if let Some(stored_zones) = stored_zones {
+        save(&EVENT_ZONES_STORAGE, event.id, stored_zones);
+    }
+    if let Some(organizer_id) = event.organizer_id {
+        save_ledgers(organizer_id, stored_ledgers);
+    }
+    for (ticket_id, stored_ticket) in stored_tickets {
+        save(&TICKETS_STORAGE, ticket_id, stored_ticket);
+    }
+    for ticket in &tickets {
+        index_ticket(ticket);
+        count_sale_in_buckets(ticket);
+        record_block(
+            BLOCK_TYPE_MINT,
+            mint_transaction(ticket),
+            ticket.purchase_date,
+        );
+    }
+
+    Ok(tickets)
+}
+
+// Helper function to add a ticket to the by-user, by-event and by-seat indexes. Anything
+// that mints a ticket or changes its owner must keep these in step with TICKETS_STORAGE.
+fn index_ticket(ticket: &Ticket) {
+    TICKETS_BY_USER.with(|index| {
+        index.borrow_mut().insert(
+            IndexKey {
+                parent_id: ticket.user_id,
+                record_id: ticket.id,
+            },
+            (),
+        );
+    });
+    TICKETS_BY_EVENT.with(|index| {
+        index.borrow_mut().insert(
+            IndexKey {
+                parent_id: ticket.event_id,
+                record_id: ticket.id,
+            },
+            (),
+        );
+    });
+    // Tickets from before seat labels were checked can have labels over the key's bound;
+    // no purchase can ask for those seats, so they are left out
+    let seat = ticket.seat_number.as_str();
+    if !seat.is_empty() && seat.len() <= MAX_SEAT_NUMBER_LENGTH as usize {
+        TICKETS_BY_SEAT.with(|index| {
+            index
+                .borrow_mut()
+                .insert(seat_key(ticket.event_id, seat), ticket.id);
+        });
+    }
+}
+
+// Helper function to key a seat of an event in the index of taken seats
+fn seat_key(event_id: u64, seat: &str) -> SeatKey {
+    SeatKey {
+        event_id,
+        seat: seat.to_string(),
+    }
+}
+
+// Helper function to tell whether a seat of an event is taken
+fn is_seat_taken(event_id: u64, seat: &str) -> bool {
+    TICKETS_BY_SEAT.with(|index| index.borrow().contains_key(&seat_key(event_id, seat)))
+}
+
+// Helper function to list the taken seats of an event
+fn taken_seats(event_id: u64) -> BTreeSet<String> {
+    TICKETS_BY_SEAT.with(|index| {
+        index
+            .borrow()
+            .range(seat_key(event_id, "")..)
+            .take_while(|(key, _)| key.event_id == event_id)
+            .map(|(key, _)| key.seat)
+            .collect()
+    })
+}
+
+// Helper function to add an event to the date and location indexes
+fn index_event(event: &Event) {
+    EVENTS_BY_DATE.with(|index| {
+        index.borrow_mut().insert(
+            IndexKey {
+                parent_id: event.date,
+                record_id: event.id,
+            },
+            (),
+        );
+    });
+    EVENTS_BY_DATE_DESC.with(|index| {
+        index.borrow_mut().insert(
+            IndexKey {
+                parent_id: u64::MAX - event.date,
+                record_id: event.id,
+            },
+            (),
+        );
+    });
+    EVENTS_BY_LOCATION.with(|index| {
+        index.borrow_mut().insert(
+            TextIndexKey {
+                text: index_text(&event.location),
+                record_id: event.id,
+            },
+            (),
+        );
+    });
+    if let Some(organizer) = event.organizer {
+        EVENTS_BY_ORGANIZER.with(|index| {
+            index.borrow_mut().insert(
+                TextIndexKey {
+                    text: organizer.to_text(),
+                    record_id: event.id,
+                },
+                (),
+            );
+        });
+    }
+}
+
+// Helper function to remove an event from the date and location indexes
+fn unindex_event(event: &Event) {
+    EVENTS_BY_DATE.with(|index| {
+        index.borrow_mut().remove(&IndexKey {
+            parent_id: event.date,
+            record_id: event.id,
+        });
+    });
+    EVENTS_BY_DATE_DESC.with(|index| {
+        index.borrow_mut().remove(&IndexKey {
+            parent_id: u64::MAX - event.date,
+            record_id: event.id,
+        });
+    });
+    EVENTS_BY_LOCATION.with(|index| {
+        index.borrow_mut().remove(&TextIndexKey {
+            text: index_text(&event.location),
+            record_id: event.id,
+        });
+    });
+}
+
+// Helper function to move an event to a new status, and to a new date when postponed
+fn change_event_status(
+    event_id: u64,
+    status: EventStatus,
+    new_date: Option<u64>,
+) -> Result<Event, Error> {
+    let old_event = load(&EVENTS_STORAGE, event_id)?.ok_or(Error::EventNotFound { event_id })?;
+    require_organizer(&old_event)?;
+    if !old_event.status.can_become(status) {
+        return Err(Error::InvalidStatusTransition {
+            from: old_event.status,
+            to: status,
+        });
+    }
+
+    let mut event = old_event.clone();
+    event.status = status;
+    if let Some(date) = new_date {
+        event.date = date;
+    }
+    sync_sold_out(&mut event);
+
+    // An event's sales settle, and can be paid out, once it has taken place
+    let stored_ledgers = match (status, event.organizer_id) {
+        (EventStatus::Completed, Some(organizer_id)) => {
+            let mut ledgers = LedgerBook::new();
+            for ticket in load_indexed_tickets(&TICKETS_BY_EVENT, event_id) {
+                if !collected_for(&ticket, organizer_id) {
+                    continue;
+                }
+                settle_sale(
+                    ledger_in(&mut ledgers, organizer_id, ticket_token(&ticket))?,
+                    &ticket,
+                );
+            }
+            Some((organizer_id, encode_ledgers(ledgers)?))
+        }
+        _ => None,
+    };
+
+    if event.date == old_event.date {
+        store(&EVENTS_STORAGE, event_id, &event)?;
+        if let Some((organizer_id, stored_ledgers)) = stored_ledgers {
+            save_ledgers(organizer_id, stored_ledgers);
+        }
+        return Ok(event);
+    }
+
+    // Doors-open and end times move with the date so they keep their distance from it
+    let mut details = event_details(event_id)?;
+    let shift = |time: u64| shift_time(time, old_event.date, event.date);
+    details.doors_open = details.doors_open.map(shift);
+    details.end_time = details.end_time.map(shift);
+
+    let stored_event = Stored::encode(&event)?;
+    let stored_details = Stored::encode(&details)?;
+    save(&EVENTS_STORAGE, event_id, stored_event);
+    save(&EVENT_DETAILS_STORAGE, event_id, stored_details);
+    unindex_event(&old_event);
+    index_event(&event);
+
+    Ok(event)
+}
+
+// Helper function to find the organizer profile a principal owns
+fn organizer_of(owner: Principal) -> Result<Option<Organizer>, Error> {
+    let text = owner.to_text();
+    let organizer_id = ORGANIZERS_BY_OWNER.with(|index| {
+        index
+            .borrow()
+            .range(
+                TextIndexKey {
+                    text: text.clone(),
+                    record_id: 0,
+                }..,
+            )
+            .next()
+            .filter(|(key, _)| key.text == text)
+            .map(|(key, _)| key.record_id)
+    });
+
+    match organizer_id {
+        Some(organizer_id) => load(&ORGANIZERS_STORAGE, organizer_id),
+        None => Ok(None),
+    }
+}
+
+// Helper function to list the events a principal created, in id order
+fn events_organized_by(owner: Principal) -> Vec<u64> {
+    let text = owner.to_text();
+    EVENTS_BY_ORGANIZER.with(|index| {
+        index
+            .borrow()
+            .range(
+                TextIndexKey {
+                    text: text.clone(),
+                    record_id: 0,
+                }..,
+            )
+            .take_while(|(key, _)| key.text == text)
+            .map(|(key, _)| key.record_id)
+            .collect()
+    })
+}
+
+// Helper function to load an organizer's ledger, which every organizer has from creation
+fn organizer_ledger(organizer_id: u64) -> Result<OrganizerLedger, Error> {
+    load(&ORGANIZER_LEDGERS_STORAGE, organizer_id)?.ok_or(Error::OrganizerNotFound { organizer_id })
+}
+
+// Helper function to load the canister settings, which start out empty
+fn settings() -> Result<Settings, Error> {
+    Ok(load(&SETTINGS_STORAGE, SETTINGS_KEY)?.unwrap_or_default())
+}
+
+// Helper function to find an organizer's ledger in a token. A token ledger that has
+// never been booked to comes back empty, without the id it will be stored under.
+fn find_ledger(
+    organizer_id: u64,
+    token: Option<&str>,
+) -> Result<(Option<u64>, OrganizerLedger), Error> {
+    let token = match token {
+        Some(token) => token,
+        None => return Ok((Some(organizer_id), organizer_ledger(organizer_id)?)),
+    };
+
+    let ledger_ids: Vec<u64> = ORGANIZER_TOKEN_LEDGERS.with(|index| {
+        index
+            .borrow()
+            .range(IndexKey::range_of(organizer_id))
+            .map(|(key, _)| key.record_id)
+            .collect()
+    });
+    for ledger_id in ledger_ids {
+        if let Some(ledger) = load(&ORGANIZER_LEDGERS_STORAGE, ledger_id)? {
+            if ledger.token.as_deref() == Some(token) {
+                return Ok((Some(ledger_id), ledger));
+            }
+        }
+    }
+
+    Ok((
+        None,
+        OrganizerLedger {
+            organizer_id,
+            token: Some(token.to_string()),
+            ..Default::default()
+        },
+    ))
+}
+
+// Helper function to get an organizer's ledger in a token out of a book, loading it,
+// or giving it an id, on first use
+fn ledger_in<'a>(
+    ledgers: &'a mut LedgerBook,
+    organizer_id: u64,
+    token: Option<&String>,
+) -> Result<&'a mut OrganizerLedger, Error> {
+    let entry = match ledgers.entry(token.cloned()) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let (ledger_id, ledger) = find_ledger(organizer_id, token.map(String::as_str))?;
+            let ledger_id = match ledger_id {
+                Some(ledger_id) => ledger_id,
+                None => next_id()?,
+            };
+            entry.insert((ledger_id, ledger))
+        }
+    };
+    Ok(&mut entry.1)
+}
+
+// Helper function to encode every ledger of a book before any of them is written
+fn encode_ledgers(ledgers: LedgerBook) -> Result<Vec<(u64, Stored<OrganizerLedger>)>, Error> {
+    ledgers
+        .into_values()
+        .map(|(ledger_id, ledger)| Ok((ledger_id, Stored::encode(&ledger)?)))
+        .collect()
+}
+
+// Helper function to write encoded ledgers, indexing those in a token under their organizer
+fn save_ledgers(organizer_id: u64, ledgers: Vec<(u64, Stored<OrganizerLedger>)>) {
+    for (ledger_id, stored_ledger) in ledgers {
+        save(&ORGANIZER_LEDGERS_STORAGE, ledger_id, stored_ledger);
+        if ledger_id != organizer_id {
+            ORGANIZER_TOKEN_LEDGERS.with(|index| {
+                index.borrow_mut().insert(
+                    IndexKey {
+                        parent_id: organizer_id,
+                        record_id: ledger_id,
+                    },
+                    (),
+                );
+            });
+        }
+    }
+}
+
+// Helper function to export a chunk of an event's tickets, oldest first, one row per ticket
+fn export_tickets(
+    event_id: u64,
+    options: ExportOptions,
+    columns: &[&str],
+    to_row: impl Fn(&Ticket, &[ExportField]) -> Vec<serde_json::Value>,
+) -> Result<ExportChunk, Error> {
+    let event = load(&EVENTS_STORAGE, event_id)?.ok_or(Error::EventNotFound { event_id })?;
+    require_organizer(&event)?;
+    let limit = options
+        .limit
+        .unwrap_or(MAX_EXPORT_ROWS)
+        .clamp(1, MAX_EXPORT_ROWS) as usize;
+    let redact = options.redact.unwrap_or_default();
+
+    // One id past the page tells whether there is another chunk
+    let ticket_ids: Vec<u64> = TICKETS_BY_EVENT.with(|index| {
+        index
+            .borrow()
+            .range(IndexKey::range_from(event_id, options.cursor.unwrap_or(0)))
+            .take(limit + 1)
+            .map(|(key, _)| key.record_id)
+            .collect()
+    });
+    let next_cursor = ticket_ids.get(limit).copied();
+    // Like load_all, undecodable tickets are left out of the export
+    let rows: Vec<Vec<serde_json::Value>> = ticket_ids
+        .into_iter()
+        .take(limit)
+        .filter_map(|ticket_id| load(&TICKETS_STORAGE, ticket_id).ok().flatten())
+        .map(|ticket| to_row(&ticket, &redact))
+        .collect();
+
+    let data = match options.format {
+        ExportFormat::Csv => {
+            let mut lines = Vec::with_capacity(rows.len() + 1);
+            if options.cursor.is_none() {
+                lines.push(columns.join(","));
+            }
+            for row in rows {
+                let cells: Vec<String> = row.iter().map(csv_cell).collect();
+                lines.push(cells.join(","));
+            }
+            lines.into_iter().map(|line| line + "\n").collect()
+        }
+        ExportFormat::Json => serde_json::Value::Array(
+            rows.into_iter()
+                .map(|row| {
+                    let fields = columns.iter().map(|column| column.to_string()).zip(row);
+                    serde_json::Value::Object(fields.collect())
+                })
+                .collect(),
+        )
+        .to_string(),
+    };
+
+    Ok(ExportChunk { data, next_cursor })
+}
+
+// Helper function to blank a value out of an export if its field is redacted
+fn redacted(
+    redact: &[ExportField],
+    field: ExportField,
+    value: serde_json::Value,
+) -> serde_json::Value {
+    if redact.contains(&field) {
+        serde_json::Value::Null
+    } else {
+        value
+    }
+}
+
+// Helper function to write a value as a CSV cell, quoted if it holds a separator or a quote
+fn csv_cell(value: &serde_json::Value) -> String {
+    let text = match value {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(text) => text.clone(),
+        value => value.to_string(),
+    };
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+// Helper function to load an event's sales totals, which start empty
+fn event_sales(event_id: u64) -> Result<EventSales, Error> {
+    Ok(
+        load(&EVENT_SALES_STORAGE, event_id)?.unwrap_or_else(|| EventSales {
+            event_id,
+            ..Default::default()
+        }),
+    )
+}
+
+// Helper function to add a ticket to its event's sales totals
+fn record_sale(sales: &mut EventSales, ticket: &Ticket) {
+    let breakdown = ticket.breakdown.clone().unwrap_or_default();
+    sales.tickets_sold += 1;
+    if breakdown.loyalty_discount > 0 {
+        sales.loyalty_discounted_tickets += 1;
+    }
+
+    let tier = breakdown.loyalty_tier;
+    match sales
+        .buyer_tiers
+        .iter_mut()
+        .find(|tier_sales| tier_sales.tier == tier)
+    {
+        Some(tier_sales) => tier_sales.tickets += 1,
+        None => sales.buyer_tiers.push(TierSales { tier, tickets: 1 }),
+    }
+
+    let currency = match sales
+        .currencies
+        .iter()
+        .position(|currency| currency.token == breakdown.token)
+    {
+        Some(index) => &mut sales.currencies[index],
+        None => {
+            sales.currencies.push(CurrencySales {
+                token: breakdown.token.clone(),
+                ..Default::default()
+            });
+            sales
+                .currencies
+                .last_mut()
+                .expect("a currency was just added")
+        }
+    };
+    currency.tickets += 1;
+    currency.revenue += ticket.price;
+    currency.loyalty_discounts += breakdown.loyalty_discount;
+    currency.dynamic_adjustments += breakdown.dynamic_adjustment;
+
+    let seat_class = breakdown.seat_class;
+    match currency
+        .classes
+        .iter_mut()
+        .find(|class| class.seat_class == seat_class)
+    {
+        Some(class) => {
+            class.tickets += 1;
+            class.revenue += ticket.price;
+        }
+        None => currency.classes.push(ClassSales {
+            seat_class,
+            tickets: 1,
+            revenue: ticket.price,
+        }),
+    }
+}
+
+// Helper function to count a ticket in the hour and day it was bought in
+fn count_sale_in_buckets(ticket: &Ticket) {
+    for (buckets, length) in [
+        (&SALES_BY_HOUR, NANOS_PER_HOUR),
+        (&SALES_BY_DAY, NANOS_PER_DAY),
+    ] {
+        let key = IndexKey {
+            parent_id: ticket.event_id,
+            record_id: ticket.purchase_date - ticket.purchase_date % length,
+        };
+        buckets.with(|buckets| {
+            let mut buckets = buckets.borrow_mut();
+            let tickets = buckets.get(&key).unwrap_or(0);
+            buckets.insert(key, tickets + 1);
+        });
+    }
+}
+
+// Helper function to express part of a whole in basis points
+fn share_of(part: u64, whole: u64) -> u32 {
+    if whole == 0 {
+        return 0;
+    }
+    (part as u128 * MAX_BASIS_POINTS as u128 / whole as u128) as u32
+}
+
+// Helper function to book a ticket sale to an organizer's pending balance
+fn book_sale(ledger: &mut OrganizerLedger, ticket: &Ticket) {
+    let fee = platform_fee(ticket);
+    ledger.gross_sales += ticket.price;
+    ledger.platform_fees += fee;
+    ledger.pending_balance += ticket.price - fee;
+}
+
+// Helper function to book a sale the platform collected before the event's organizer had
+// a profile. It is reported, but its earnings are not the organizer's to pay out.
+fn book_uncollected_sale(ledger: &mut OrganizerLedger, ticket: &Ticket) {
+    ledger.gross_sales += ticket.price;
+    ledger.platform_fees += platform_fee(ticket);
+}
+
+// Helper function to tell whether a ticket was paid into its organizer's subaccounts.
+// Ids only grow, so tickets with a lower id than the profile were sold before it.
+fn collected_for(ticket: &Ticket, organizer_id: u64) -> bool {
+    ticket.id > organizer_id
+}
+
+// Helper function to move a ticket's earnings from the pending to the settled balance
+// once its event has taken place
+fn settle_sale(ledger: &mut OrganizerLedger, ticket: &Ticket) {
+    let earnings = ticket.price - platform_fee(ticket);
+    ledger.pending_balance = ledger.pending_balance.saturating_sub(earnings);
+    ledger.settled_balance += earnings;
+}
+
+// Helper function to get the token a ticket was paid in; None for the default currency
+fn ticket_token(ticket: &Ticket) -> Option<&String> {
+    ticket
+        .breakdown
+        .as_ref()
+        .and_then(|breakdown| breakdown.token.as_ref())
+}
+
+// Helper function to look up a registered token by symbol
+fn find_token(symbol: &str) -> Option<Token> {
+    let id = first_indexed(&TOKENS_BY_SYMBOL, symbol)?;
+    load(&TOKENS_STORAGE, id).ok().flatten()
+}
+
+// Helper function to normalize a token symbol for lookups
+fn token_symbol(symbol: &str) -> String {
+    symbol.trim().to_ascii_uppercase()
+}
+
+// Helper function to work out the loyalty points a purchase earns. Amounts in a token
+// count as many default-currency units as its loyalty_rate says a whole token is worth.
+fn loyalty_points_for(breakdown: &PriceBreakdown) -> u64 {
+    let amount = breakdown.ticket_amount();
+    let value = match &breakdown.token {
+        Some(token) => find_token(token).map_or(0, |token| {
+            (amount as u128 * token.loyalty_rate as u128 / 10u128.pow(token.decimals as u32)) as u64
+        }),
+        None => amount,
+    };
+    calculate_points(value)
+}
+
+// Helper function to work out the platform's share of a ticket sale
+fn platform_fee(ticket: &Ticket) -> u64 {
+    match &ticket.breakdown {
+        Some(breakdown) => breakdown.fees,
+        None => basis_points_of(ticket.price, PLATFORM_FEE_BPS),
+    }
+}
+
+// Helper function to take a share, given in basis points, of an amount
+fn basis_points_of(amount: u64, basis_points: u32) -> u64 {
+    (amount as u128 * basis_points as u128 / MAX_BASIS_POINTS as u128) as u64
+}
+
+// Helper function to describe why the payment ledger rejected a transfer
+fn transfer_error_reason(error: &TransferError) -> String {
+    let reason = match error {
+        TransferError::BadFee { expected_fee } => {
+            format!("the ledger expects a fee of {}", expected_fee)
+        }
+        TransferError::BadBurn { min_burn_amount } => {
+            format!("burns must be at least {}", min_burn_amount)
+        }
+        TransferError::InsufficientFunds { balance } => {
+            format!("the platform account only holds {}", balance)
+        }
+        TransferError::TooOld => "the transfer is too old".to_string(),
+        TransferError::CreatedInFuture { ledger_time } => {
+            format!("the transfer is ahead of the ledger time {}", ledger_time)
+        }
+        TransferError::Duplicate { duplicate_of } => {
+            format!("the transfer duplicates block {}", duplicate_of)
+        }
+        TransferError::TemporarilyUnavailable => "the ledger is unavailable".to_string(),
+        TransferError::GenericError {
+            error_code,
+            message,
+        } => format!("error {}: {}", error_code, message),
+    };
+    reason.chars().take(MAX_PAYOUT_REASON_LENGTH).collect()
+}
+
+// Helper function to describe why the ledger rejected a payment from a buyer
+fn transfer_from_error_reason(error: &TransferFromError) -> String {
+    let reason = match error {
+        TransferFromError::BadFee { expected_fee } => {
+            format!("the ledger expects a fee of {}", expected_fee)
+        }
+        TransferFromError::BadBurn { min_burn_amount } => {
+            format!("burns must be at least {}", min_burn_amount)
+        }
+        TransferFromError::InsufficientFunds { balance } => {
+            format!("the buyer's account only holds {}", balance)
+        }
+        TransferFromError::InsufficientAllowance { allowance } => {
+            format!("the buyer has only approved {}", allowance)
+        }
+        TransferFromError::TooOld => "the transfer is too old".to_string(),
+        TransferFromError::CreatedInFuture { ledger_time } => {
+            format!("the transfer is ahead of the ledger time {}", ledger_time)
+        }
+        TransferFromError::Duplicate { duplicate_of } => {
+            format!("the transfer duplicates block {}", duplicate_of)
+        }
+        TransferFromError::TemporarilyUnavailable => "the ledger is unavailable".to_string(),
+        TransferFromError::GenericError {
+            error_code,
+            message,
+        } => format!("error {}: {}", error_code, message),
+    };
+    reason.chars().take(MAX_PAYOUT_REASON_LENGTH).collect()
+}
+
+// Helper function to describe why a call to a ledger did not go through
+fn rejection_reason(code: ic_cdk::api::call::RejectionCode, message: &str) -> String {
+    format!("{:?}: {}", code, message)
+        .chars()
+        .take(MAX_PAYOUT_REASON_LENGTH)
+        .collect()
+}
+
+// Helper function to move a time by as much as a date moves from `from` to `to`
+fn shift_time(time: u64, from: u64, to: u64) -> u64 {
+    if to >= from {
+        time.saturating_add(to - from)
+    } else {
+        time.saturating_sub(from - to)
+    }
+}
+
+// Helper function to load the occurrences of a series
+fn series_occurrences(series_id: u64) -> Vec<Event> {
+    let event_ids: Vec<u64> = SERIES_OCCURRENCES.with(|index| {
+        index
+            .borrow()
+            .range(IndexKey::range_of(series_id))
+            .map(|(key, _)| key.record_id)
+            .collect()
+    });
+
+    event_ids
+        .into_iter()
+        .filter_map(|event_id| load(&EVENTS_STORAGE, event_id).ok().flatten())
+        .collect()
+}
+
+// Helper function to work out the dates of a schedule's occurrences, in order
+fn schedule_dates(start: u64, schedule: &Schedule) -> Result<Vec<u64>, Error> {
+    let too_many = || {
+        invalid_field(
+            "schedule",
+            &format!("must have at most {} occurrences", MAX_OCCURRENCES),
+        )
+    };
+    let limit = match schedule.count {
+        Some(0) => return Err(invalid_field("schedule", "count must be at least 1")),
+        Some(count) if count as usize > MAX_OCCURRENCES => return Err(too_many()),
+        Some(count) => count as usize,
+        None => MAX_OCCURRENCES,
+    };
+    if !matches!(schedule.recurrence, Recurrence::Custom { .. })
+        && schedule.until.is_none()
+        && schedule.count.is_none()
+    {
+        return Err(invalid_field("schedule", "needs an until date or a count"));
+    }
+    let until = schedule.until.unwrap_or(u64::MAX);
+    if until < start {
+        return Err(invalid_field(
+            "schedule",
+            "until must not be before the first date",
+        ));
+    }
+    let check_interval = |interval: u32| {
+        if interval == 0 || interval > MAX_RECURRENCE_INTERVAL {
+            Err(invalid_field(
+                "schedule",
+                &format!("interval must be between 1 and {}", MAX_RECURRENCE_INTERVAL),
+            ))
+        } else {
+            Ok(u64::from(interval))
+        }
+    };
+
+    let mut dates: Vec<u64> = match &schedule.recurrence {
+        Recurrence::Custom { dates } => {
+            let mut dates = dates.clone();
+            dates.sort_unstable();
+            dates.dedup();
+            if dates.is_empty() || dates.contains(&0) {
+                return Err(invalid_field("schedule", "custom dates must be non-zero"));
+            }
+            dates.retain(|date| *date <= until);
+            if dates.len() > limit {
+                if schedule.count.is_none() {
+                    return Err(too_many());
+                }
+                dates.truncate(limit);
+            }
+            dates
+        }
+        Recurrence::Daily { interval } => {
+            let step = check_interval(*interval)? * NANOS_PER_DAY;
+            recurring_dates(start, until, limit, schedule.count.is_some(), |date| {
+                date.checked_add(step)
+            })?
+        }
+        Recurrence::Weekly { interval, weekdays } => {
+            let weeks = check_interval(*interval)?;
+            let weekdays = if weekdays.is_empty() {
+                vec![weekday_of(start)]
+            } else {
+                weekdays.clone()
+            };
+            // Walk day by day, keeping the chosen weekdays of every `interval`th week
+            let first_week = week_of(start);
+            let mut date = start;
+            recurring_dates(start, until, limit, schedule.count.is_some(), |_| loop {
+                date = date.checked_add(NANOS_PER_DAY)?;
+                if (week_of(date) - first_week) % weeks == 0 && weekdays.contains(&weekday_of(date))
+                {
+                    return Some(date);
+                }
+            })?
+        }
+    };
+
+    dates.retain(|date| !schedule.exceptions.contains(date));
+    if dates.is_empty() {
+        return Err(invalid_field("schedule", "has no occurrences"));
+    }
+    Ok(dates)
+}
+
+// Helper function to list the dates of a Daily or Weekly schedule, from `start` to
+// `until` or until `limit` dates are found. Running past the limit without a count
+// means the schedule is too long.
+fn recurring_dates(
+    start: u64,
+    until: u64,
+    limit: usize,
+    counted: bool,
+    mut next: impl FnMut(u64) -> Option<u64>,
+) -> Result<Vec<u64>, Error> {
+    let mut dates = vec![start];
+    let mut date = start;
+    while let Some(following) = next(date).filter(|following| *following <= until) {
+        if dates.len() == limit {
+            if counted {
+                break;
+            }
+            return Err(invalid_field(
+                "schedule",
+                &format!("must have at most {} occurrences", MAX_OCCURRENCES),
+            ));
+        }
+        dates.push(following);
+        date = following;
+    }
+    Ok(dates)
+}
+
+// Helper function to number the Monday-based week a date falls in, counting from 1970
+fn week_of(date: u64) -> u64 {
+    // 1 January 1970 was a Thursday, three days after the Monday that starts its week
+    (date / NANOS_PER_DAY + 3) / 7
+}
+
+// Helper function to get the weekday of a date, in UTC
+fn weekday_of(date: u64) -> Weekday {
+    match (date / NANOS_PER_DAY + 3) % 7 {
+        0 => Weekday::Monday,
+        1 => Weekday::Tuesday,
+        2 => Weekday::Wednesday,
+        3 => Weekday::Thursday,
+        4 => Weekday::Friday,
+        5 => Weekday::Saturday,
+        _ => Weekday::Sunday,
+    }
+}
+
+// Helper function to load an event's details, which events created before details
+// existed don't have
+fn event_details(event_id: u64) -> Result<EventDetails, Error> {
+    Ok(
+        load(&EVENT_DETAILS_STORAGE, event_id)?.unwrap_or(EventDetails {
+            event_id,
+            ..Default::default()
+        }),
+    )
+}
+
+// Helper function to switch an event between OnSale and SoldOut as its capacity fills up
+// or grows
+fn sync_sold_out(event: &mut Event) {
+    let full = event.tickets_sold >= event.total_tickets;
+    match event.status {
+        EventStatus::OnSale if full => event.status = EventStatus::SoldOut,
+        EventStatus::SoldOut if !full => event.status = EventStatus::OnSale,
+        _ => {}
+    }
+}
+
+// Helper function to tell whether an event is shown in searches by default
+fn is_listed(event: &Event) -> bool {
+    !matches!(event.status, EventStatus::Draft | EventStatus::Archived)
+}
+
+// Helper function to add an event's words to the full-text index
+fn index_event_text(event: &Event, details: &EventDetails) {
+    EVENT_TOKENS.with(|index| {
+        let mut index = index.borrow_mut();
+        for (token, weight) in event_tokens(event, details) {
+            index.insert(
+                TextIndexKey {
+                    text: token,
+                    record_id: event.id,
+                },
+                weight,
+            );
+        }
+    });
+}
+
+// Helper function to remove an event's words from the full-text index
+fn unindex_event_text(event: &Event, details: &EventDetails) {
+    EVENT_TOKENS.with(|index| {
+        let mut index = index.borrow_mut();
+        for token in event_tokens(event, details).into_keys() {
+            index.remove(&TextIndexKey {
+                text: token,
+                record_id: event.id,
+            });
+        }
+    });
+}
+
+// Helper function to collect the searchable words of an event with their weights
+fn event_tokens(event: &Event, details: &EventDetails) -> BTreeMap<String, u32> {
+    let mut fields: Vec<(&str, u32)> = vec![
+        (&event.name, NAME_TOKEN_WEIGHT),
+        (&event.location, LOCATION_TOKEN_WEIGHT),
+        (&details.description, DESCRIPTION_TOKEN_WEIGHT),
+    ];
+    if let Some(category) = &event.category {
+        fields.push((category, TAG_TOKEN_WEIGHT));
+    }
+    for tag in &details.tags {
+        fields.push((tag, TAG_TOKEN_WEIGHT));
+    }
+
+    // A word that appears in several fields gets the sum of their weights
+    let mut tokens: BTreeMap<String, u32> = BTreeMap::new();
+    for (text, weight) in fields {
+        for token in tokenize(text) {
+            *tokens.entry(token).or_default() += weight;
+        }
+    }
+    tokens
+}
+
+// Helper function to split text into distinct lowercase words
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    for word in text.split(|c: char| !c.is_alphanumeric()) {
+        let token: String = word.to_lowercase().chars().take(MAX_TOKEN_LENGTH).collect();
+        if !token.is_empty() && !tokens.contains(&token) {
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+// Helper function to list the events whose normalized location starts with a prefix,
+// stopping one past MAX_SEARCH_SCAN
+fn events_by_location_prefix(prefix: &str) -> Vec<u64> {
+    EVENTS_BY_LOCATION.with(|index| {
+        index
+            .borrow()
+            .range(
+                TextIndexKey {
+                    text: prefix.to_string(),
+                    record_id: 0,
+                }..,
+            )
+            .take_while(|(key, _)| key.text.starts_with(prefix))
+            .take(MAX_SEARCH_SCAN + 1)
+            .map(|(key, _)| key.record_id)
+            .collect()
+    })
+}
+
+// Helper function to list the events dated within a range, stopping one past MAX_SEARCH_SCAN
+fn events_by_date_range(from: u64, to: u64) -> Vec<u64> {
+    let start = IndexKey {
+        parent_id: from,
+        record_id: 0,
+    };
+    let end = IndexKey {
+        parent_id: to,
+        record_id: u64::MAX,
+    };
+    EVENTS_BY_DATE.with(|index| {
+        index
+            .borrow()
+            .range(start..=end)
+            .take(MAX_SEARCH_SCAN + 1)
+            .map(|(key, _)| key.record_id)
+            .collect()
+    })
+}
+
+// Helper function to normalize text for index keys: trimmed, lowercased and cut to fit
+fn index_text(value: &str) -> String {
+    let mut text = value.trim().to_lowercase();
+    let mut end = text.len().min(MAX_INDEX_TEXT_LENGTH as usize);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    text
+}
+
+// Helper function to get the value an event is sorted by
+fn sort_value(order: &EventSortOrder, event: &Event) -> u64 {
+    match order {
+        EventSortOrder::DateAscending | EventSortOrder::DateDescending => event.date,
+        EventSortOrder::PriceAscending | EventSortOrder::PriceDescending => quote_price(event),
+        EventSortOrder::Popularity => event.tickets_sold,
+    }
+}
+
+// Helper function to order two (sort value, event id) positions; ties break on the id
+// so every event has a unique position a cursor can point at
+fn compare_positions(
+    order: &EventSortOrder,
+    (a_value, a_id): (u64, u64),
+    (b_value, b_id): (u64, u64),
+) -> std::cmp::Ordering {
+    let by_value = match order {
+        EventSortOrder::DateAscending | EventSortOrder::PriceAscending => a_value.cmp(&b_value),
+        EventSortOrder::DateDescending
+        | EventSortOrder::PriceDescending
+        | EventSortOrder::Popularity => b_value.cmp(&a_value),
+    };
+    by_value.then(a_id.cmp(&b_id))
+}
+
+// Helper function to read a search cursor of the form "<sort value>-<event id>"
+fn parse_cursor(cursor: &str) -> Result<(u64, u64), Error> {
+    cursor
+        .split_once('-')
+        .and_then(|(value, event_id)| Some((value.parse().ok()?, event_id.parse().ok()?)))
+        .ok_or_else(|| invalid_field("cursor", "not a cursor returned by search_events"))
+}
+
+// Helper function to load the tickets an index lists under a parent id
+fn load_indexed_tickets(index: &'static IndexStore, parent_id: u64) -> Vec<Ticket> {
+    let ticket_ids: Vec<u64> = index.with(|index| {
+        index
+            .borrow()
+            .range(IndexKey::range_of(parent_id))
+            .map(|(key, _)| key.record_id)
+            .collect()
+    });
+
+    // Like load_all, undecodable tickets are left out of the listing
+    ticket_ids
+        .into_iter()
+        .filter_map(|ticket_id| load(&TICKETS_STORAGE, ticket_id).ok().flatten())
+        .collect()
+}
+
+// Helper function to allocate the next id from the shared counter
+fn next_id() -> Result<u64, Error> {
+    let current_value = ID_COUNTER.with(|counter| *counter.borrow().get());
+    let next_value = current_value.checked_add(1).ok_or(Error::StorageFailure {
+        reason: "Id counter exhausted".to_string(),
+    })?;
+
+    ID_COUNTER
+        .with(|counter| counter.borrow_mut().set(next_value))
+        .map_err(|err| Error::StorageFailure {
+            reason: format!("Cannot increment the id counter: {:?}", err),
+        })?;
+
+    Ok(current_value)
+}
+
+// Helper function to load a record by id
+fn load<T: Record>(storage: &'static RecordStore<T>, id: u64) -> Result<Option<T>, Error> {
+    storage
+        .with(|storage| storage.borrow().get(&id))
+        .map(|stored| stored.decode())
+        .transpose()
+}
+
+// Helper function to load every record of a map. Records that fail to decode are
+// left out so one bad value cannot take a whole listing down; fetching them by id
+// still reports the corruption.
+fn load_all<T: Record>(storage: &'static RecordStore<T>) -> Vec<T> {
+    storage.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter_map(|(_, stored)| stored.decode().ok())
+            .collect()
+    })
+}
+
+// Helper function to write a record that has already been encoded
+fn save<T: Record>(storage: &'static RecordStore<T>, id: u64, stored: Stored<T>) {
+    storage.with(|storage| {
+        storage.borrow_mut().insert(id, stored);
+    });
+}
+
+// Helper function to encode and write a record
+fn store<T: Record>(storage: &'static RecordStore<T>, id: u64, record: &T) -> Result<(), Error> {
+    save(storage, id, Stored::encode(record)?);
+    Ok(())
+}
+
+// Helper function to run an update endpoint and record the call in the audit log
+fn audited<T: AuditTargets>(
+    operation: &str,
+    entity: AuditEntity,
+    target_ids: &[u64],
+    endpoint: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let result = endpoint();
+    record_audit(operation, entity, target_ids, &result);
+    result
+}
+
+// Helper function to append a call to the audit log. A call that cannot be recorded
+// still goes through, so a full log never blocks refunds or payouts; list_audit_log
+// reports how many calls went unrecorded.
+fn record_audit<T: AuditTargets>(
+    operation: &str,
+    entity: AuditEntity,
+    target_ids: &[u64],
+    result: &Result<T, Error>,
+) {
+    let mut target_ids = target_ids.to_vec();
+    let outcome = match result {
+        Ok(value) => {
+            for target_id in value.audit_targets() {
+                if !target_ids.contains(&target_id) {
+                    target_ids.push(target_id);
+                }
+            }
+            AuditOutcome::Succeeded
+        }
+        Err(error) => AuditOutcome::Failed {
+            error: error.clone(),
+        },
+    };
+
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let entry = AuditEntry {
+            id: log.len(),
+            caller: caller(),
+            timestamp: time(),
+            operation: operation.to_string(),
+            entity,
+            target_ids,
+            outcome,
+        };
+        let recorded = Stored::encode(&entry)
+            .ok()
+            .and_then(|stored| log.append(&stored).ok());
+        if recorded.is_none() {
+            UNRECORDED_CALLS.with(|count| *count.borrow_mut() += 1);
+        }
+    });
+    // Any update can change what a page shows
+    forget_http_responses();
+}
+
+// Ids of the records an endpoint returns, recorded as targets of the call
+trait AuditTargets {
+    fn audit_targets(&self) -> Vec<u64> {
+        Vec::new()
+    }
+}
+
+impl AuditTargets for () {}
+
+impl AuditTargets for String {}
+
+impl AuditTargets for EventSeatMap {}
+
+impl AuditTargets for EventZones {}
+
+impl<T: AuditTargets> AuditTargets for Vec<T> {
+    fn audit_targets(&self) -> Vec<u64> {
+        self.iter().flat_map(AuditTargets::audit_targets).collect()
+    }
+}
+
+impl AuditTargets for ImportSummary {
+    fn audit_targets(&self) -> Vec<u64> {
+        [self.venue_ids.as_slice(), &self.event_ids].concat()
+    }
+}
+
+impl AuditTargets for LoyaltyPoints {
+    fn audit_targets(&self) -> Vec<u64> {
+        vec![self.user_id]
+    }
+}
+
+impl AuditTargets for User {
+    fn audit_targets(&self) -> Vec<u64> {
+        vec![self.id]
+    }
+}
+
+impl AuditTargets for Event {
+    fn audit_targets(&self) -> Vec<u64> {
+        vec![self.id]
+    }
+}
+
+impl AuditTargets for EventSeries {
+    fn audit_targets(&self) -> Vec<u64> {
+        vec![self.id]
+    }
+}
+
+impl AuditTargets for Venue {
+    fn audit_targets(&self) -> Vec<u64> {
+        vec![self.id]
+    }
+}
+
+impl AuditTargets for Organizer {
+    fn audit_targets(&self) -> Vec<u64> {
+        vec![self.id]
+    }
+}
+
+impl AuditTargets for TaxRate {
+    fn audit_targets(&self) -> Vec<u64> {
+        vec![self.id]
+    }
+}
+
+impl AuditTargets for Token {
+    fn audit_targets(&self) -> Vec<u64> {
+        vec![self.id]
+    }
+}
+
+impl AuditTargets for Payout {
+    fn audit_targets(&self) -> Vec<u64> {
+        vec![self.id]
+    }
+}
+
+impl AuditTargets for Ticket {
+    fn audit_targets(&self) -> Vec<u64> {
+        vec![self.id]
+    }
+}
+
+// Helper function to append a block to the ticket transaction log, chained to the one
+// before it, and certify the new tip. A change that cannot be recorded traps, which
+// rolls it back.
+fn record_block(block_type: &str, transaction: Vec<(String, Icrc3Value)>, timestamp: u64) {
+    let mut block = vec![
+        (
+            "btype".to_string(),
+            Icrc3Value::Text(block_type.to_string()),
+        ),
+        ("ts".to_string(), nat_value(timestamp)),
+        ("tx".to_string(), Icrc3Value::Map(transaction)),
+    ];
+    if let Some((_, last_hash)) = block_tip() {
+        block.push(("phash".to_string(), Icrc3Value::Blob(last_hash.to_vec())));
+    }
+
+    BLOCK_LOG.with(|log| {
+        Stored::encode(&Icrc3Value::Map(block))
+            .and_then(|stored| {
+                log.borrow()
+                    .append(&stored)
+                    .map_err(|err| Error::StorageFailure {
+                        reason: format!("Cannot grow the transaction log: {:?}", err),
+                    })
+            })
+            .unwrap_or_else(|err| {
+                ic_cdk::trap(&format!("Cannot record a {} block: {}", block_type, err))
+            });
+    });
+    certify_data();
+}
+
+// Helper function to describe a ticket's mint, as recorded in the transaction log
+fn mint_transaction(ticket: &Ticket) -> Vec<(String, Icrc3Value)> {
+    vec![
+        ("tid".to_string(), nat_value(ticket.id)),
+        ("to".to_string(), account_value(holder(ticket.user_id))),
+        ("user".to_string(), nat_value(ticket.user_id)),
+        ("event".to_string(), nat_value(ticket.event_id)),
+        (
+            "seat".to_string(),
+            Icrc3Value::Text(ticket.seat_number.clone()),
+        ),
+        ("price".to_string(), nat_value(ticket.price)),
+    ]
+}
+
+// Helper function to get the principal that registered a user, which holds their tickets.
+// Accounts registered anonymously are held by the anonymous principal.
+fn holder(user_id: u64) -> Principal {
+    load(&USERS_STORAGE, user_id)
+        .ok()
+        .flatten()
+        .map_or(Principal::anonymous(), |user| user.owner)
+}
+
+// Helper function to get the index and hash of the last block of the transaction log
+fn block_tip() -> Option<(u64, [u8; 32])> {
+    BLOCK_LOG.with(|log| {
+        let log = log.borrow();
+        let last_index = log.len().checked_sub(1)?;
+        let block = log.get(last_index)?.decode().ok()?;
+        Some((last_index, value_hash(&block)))
+    })
+}
+
+// Helper function to certify the tip of the transaction log and the responses kept for
+// http_request, so that icrc3_get_tip_certificate and http_request can prove them
+fn certify_data() {
+    let root = HashTree::Fork(Box::new(http_assets_tree(None)), Box::new(tip_subtree()));
+    set_certified_data(&root.digest());
+}
+
+// Helper function to build the certified subtree for the transaction log, empty until
+// its first block
+fn tip_subtree() -> HashTree {
+    block_tip()
+        .map(|(last_index, last_hash)| tip_tree(last_index, last_hash))
+        .unwrap_or(HashTree::Empty)
+}
+
+// Helper function to build the witness for the tip of the transaction log. The labels
+// last_block_hash and last_block_index sit at the root of the certified tree next to
+// http_assets, which is pruned, so ICRC-3 clients can look them up directly.
+fn tip_witness(last_index: u64, last_hash: [u8; 32]) -> HashTree {
+    HashTree::Fork(
+        Box::new(HashTree::Pruned(http_assets_tree(None).digest())),
+        Box::new(tip_tree(last_index, last_hash)),
+    )
+}
+
+// Helper function to build the certified subtree of response hashes, in the layout the
+// HTTP gateway checks. Given a path, every response but that one is pruned.
+fn http_assets_tree(path: Option<&str>) -> HashTree {
+    let responses: Vec<(String, [u8; 32])> = HTTP_RESPONSES.with(|responses| {
+        responses
+            .borrow()
+            .iter()
+            .map(|(path, cached)| (path.clone(), cached.body_hash))
+            .collect()
+    });
+    HashTree::Labeled(
+        b"http_assets".to_vec(),
+        Box::new(response_tree(&responses, path)),
+    )
+}
+
+// Helper function to build a balanced tree over responses sorted by path
+fn response_tree(responses: &[(String, [u8; 32])], path: Option<&str>) -> HashTree {
+    let tree = match responses {
+        [] => return HashTree::Empty,
+        [(label, body_hash)] => HashTree::Labeled(
+            label.as_bytes().to_vec(),
+            Box::new(HashTree::Leaf(body_hash.to_vec())),
+        ),
+        _ => {
+            let (left, right) = responses.split_at(responses.len() / 2);
+            HashTree::Fork(
+                Box::new(response_tree(left, path)),
+                Box::new(response_tree(right, path)),
+            )
+        }
+    };
+    // A witness only needs the hash of subtrees without the path
+    if path.is_some_and(|path| responses.iter().all(|(label, _)| label != path)) {
+        HashTree::Pruned(tree.digest())
+    } else {
+        tree
+    }
+}
+
+// Helper function to build the tree certifying the tip of the transaction log
+fn tip_tree(last_index: u64, last_hash: [u8; 32]) -> HashTree {
+    let mut index = Vec::new();
+    candid::Nat::from(last_index)
+        .encode(&mut index)
+        .expect("Cannot encode a block index");
+    HashTree::Fork(
+        Box::new(HashTree::Labeled(
+            b"last_block_hash".to_vec(),
+            Box::new(HashTree::Leaf(last_hash.to_vec())),
+        )),
+        Box::new(HashTree::Labeled(
+            b"last_block_index".to_vec(),
+            Box::new(HashTree::Leaf(index)),
+        )),
+    )
+}
+
+impl HashTree {
+    // Root hash of the tree, as the IC computes it
+    fn digest(&self) -> [u8; 32] {
+        match self {
+            HashTree::Empty => domain_hash("ic-hashtree-empty", &[]),
+            HashTree::Fork(left, right) => {
+                domain_hash("ic-hashtree-fork", &[&left.digest(), &right.digest()])
+            }
+            HashTree::Labeled(label, subtree) => {
+                domain_hash("ic-hashtree-labeled", &[label, &subtree.digest()])
+            }
+            HashTree::Leaf(contents) => domain_hash("ic-hashtree-leaf", &[contents]),
+            HashTree::Pruned(digest) => *digest,
+        }
+    }
+
+    // The tree in the self-describing CBOR encoding agents expect
+    fn to_cbor(&self) -> Vec<u8> {
+        let mut bytes = vec![0xd9, 0xd9, 0xf7];
+        self.write_cbor(&mut bytes);
+        bytes
+    }
+
+    fn write_cbor(&self, bytes: &mut Vec<u8>) {
+        match self {
+            HashTree::Empty => bytes.extend([0x81, 0x00]),
+            HashTree::Fork(left, right) => {
+                bytes.extend([0x83, 0x01]);
+                left.write_cbor(bytes);
+                right.write_cbor(bytes);
+            }
+            HashTree::Labeled(label, subtree) => {
+                bytes.extend([0x83, 0x02]);
+                write_cbor_bytes(bytes, label);
+                subtree.write_cbor(bytes);
+            }
+            HashTree::Leaf(contents) => {
+                bytes.extend([0x82, 0x03]);
+                write_cbor_bytes(bytes, contents);
+            }
+            HashTree::Pruned(digest) => {
+                bytes.extend([0x82, 0x04]);
+                write_cbor_bytes(bytes, digest);
+            }
+        }
+    }
+}
+
+// Helper function to write a CBOR byte string
+fn write_cbor_bytes(bytes: &mut Vec<u8>, value: &[u8]) {
+    let length = value.len();
+    if length < 24 {
+        bytes.push(0x40 | length as u8);
+    } else if length <= u8::MAX as usize {
+        bytes.extend([0x58, length as u8]);
+    } else if length <= u16::MAX as usize {
+        bytes.push(0x59);
+        bytes.extend((length as u16).to_be_bytes());
+    } else {
+        bytes.push(0x5a);
+        bytes.extend((length as u32).to_be_bytes());
+    }
+    bytes.extend(value);
+}
+
+// Helper function to take the path of a request URL, without its query string or fragment
+fn request_path(url: &str) -> String {
+    let end = url.find(['?', '#']).unwrap_or(url.len());
+    let path = &url[..end];
+    if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    }
+}
+
+// Helper function to build the response for a path of the HTTP interface: JSON under
+// /api, HTML pages elsewhere
+fn render_path(path: &str) -> HttpResponse {
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    let rendered = match segments.as_slice() {
+        ["api", "events"] => Some(event_list_json(None)),
+        ["api", "events", "page", cursor] => Some(event_list_json(Some(cursor))),
+        ["api", "events", event_id] => event_id.parse().ok().map(event_json),
+        ["api", "events", event_id, "availability"] => event_id.parse().ok().map(availability_json),
+        ["api", "tickets", ticket_id] => ticket_id.parse().ok().map(ticket_json),
+        ["events", event_id] => event_id.parse().ok().map(event_page),
+        ["tickets", ticket_id] => ticket_id.parse().ok().map(ticket_page),
+        _ => None,
+    };
+
+    let api = segments.first() == Some(&"api");
+    match rendered {
+        Some(Ok(response)) => response,
+        Some(Err(error)) => {
+            let status_code = match Message::from(error.clone()) {
+                Message::NotFound(_) => 404,
+                Message::InvalidPayload(_) => 400,
+                _ => 500,
+            };
+            error_response(api, status_code, &error.to_string())
+        }
+        None => error_response(api, 404, &format!("No page at {}", path)),
+    }
+}
+
+// Helper function to list listed events, a page at a time, as JSON
+fn event_list_json(cursor: Option<&str>) -> Result<HttpResponse, Error> {
+    let page = search_events(SearchEventsPayload {
+        cursor: cursor.map(str::to_string),
+        limit: Some(MAX_PAGE_SIZE),
+        ..Default::default()
+    })?;
+    let events: Vec<serde_json::Value> = page
+        .events
+        .iter()
+        .map(|listing| event_value(&listing.event, &listing.details))
+        .collect();
+    Ok(json_response(
+        200,
+        json!({
+            "events": events,
+            "next": page.next_cursor.map(|cursor| format!("/api/events/page/{}", cursor)),
+        }),
+    ))
+}
+
+// Helper function to describe an event as JSON
+fn event_json(event_id: u64) -> Result<HttpResponse, Error> {
+    let event = public_event(event_id)?;
+    let details = event_details(event_id)?;
+    Ok(json_response(200, event_value(&event, &details)))
+}
+
+// Helper function to describe what is left to buy for an event as JSON
+fn availability_json(event_id: u64) -> Result<HttpResponse, Error> {
+    let event = public_event(event_id)?;
+    let zones: Vec<serde_json::Value> = load(&EVENT_ZONES_STORAGE, event_id)?
+        .map(|zones| zones.zones)
+        .unwrap_or_default()
+        .iter()
+        .map(|zone| {
+            json!({
+                "name": zone.name,
+                "kind": format!("{:?}", zone.kind),
+                "capacity": zone.capacity,
+                "tickets_available": zone.capacity.saturating_sub(zone.tickets_sold),
+            })
+        })
+        .collect();
+    let seat_map = load(&EVENT_SEAT_MAPS_STORAGE, event_id)?;
+    let taken_seats: Vec<String> = match seat_map {
+        Some(_) => taken_seats(event_id).into_iter().collect(),
+        None => Vec::new(),
+    };
+
+    Ok(json_response(
+        200,
+        json!({
+            "event_id": event.id,
+            "status": format!("{:?}", event.status),
+            "on_sale": event.status == EventStatus::OnSale,
+            "price": quote_price(&event),
+            "token": event.token,
+            "total_tickets": event.total_tickets,
+            "tickets_sold": event.tickets_sold,
+            "tickets_available": event.total_tickets.saturating_sub(event.tickets_sold),
+            "zones": zones,
+            "taken_seats": taken_seats,
+            "blocked_seats": seat_map.map(|seat_map| seat_map.blocked_seats).unwrap_or_default(),
+        }),
+    ))
+}
+
+// Helper function to verify a ticket as JSON, without its holder or what was paid
+fn ticket_json(ticket_id: u64) -> Result<HttpResponse, Error> {
+    let (ticket, event) = ticket_with_event(ticket_id)?;
+    Ok(json_response(
+        200,
+        json!({
+            "ticket_id": ticket.id,
+            "valid": admits_entry(&ticket, &event),
+            "event_id": event.id,
+            "event_name": event.name,
+            "event_status": format!("{:?}", event.status),
+            "date": event.date,
+            "location": event.location,
+            "seat": Some(ticket.seat_number).filter(|seat| !seat.is_empty()),
+            "zone": ticket.zone,
+            "checked_in_at": ticket.checked_in_at,
+            "page": format!("/tickets/{}", ticket.id),
+        }),
+    ))
+}
+
+// Helper function to render the public page of an event
+fn event_page(event_id: u64) -> Result<HttpResponse, Error> {
+    let event = public_event(event_id)?;
+    let details = event_details(event_id)?;
+    let mut rows = vec![
+        ("Date", event.date.to_string()),
+        ("Location", event.location.clone()),
+        ("Status", format!("{:?}", event.status)),
+        ("Price", price_text(quote_price(&event), &event.token)),
+        (
+            "Tickets left",
+            event
+                .total_tickets
+                .saturating_sub(event.tickets_sold)
+                .to_string(),
+        ),
+    ];
+    if let Some(doors_open) = details.doors_open {
+        rows.push(("Doors open", doors_open.to_string()));
+    }
+    if let Some(minimum_age) = details.minimum_age {
+        rows.push(("Minimum age", minimum_age.to_string()));
+    }
+
+    let body = format!(
+        "{}<p>{}</p>\n<p><a href=\"/api/events/{}\">JSON</a></p>\n",
+        definition_list(&rows),
+        escape_html(&details.description),
+        event.id
+    );
+    Ok(html_response(200, html_page(&event.name, &body)))
+}
+
+// Helper function to render the page a ticket's QR code or link points to
+fn ticket_page(ticket_id: u64) -> Result<HttpResponse, Error> {
+    let (ticket, event) = ticket_with_event(ticket_id)?;
+    let status = match ticket.checked_in_at {
+        Some(checked_in_at) => format!("Checked in at {}", checked_in_at),
+        None if admits_entry(&ticket, &event) => "Valid".to_string(),
+        None => format!("Not valid: the event is {:?}", event.status),
+    };
+    let mut rows = vec![
+        ("Event", event.name.clone()),
+        ("Date", event.date.to_string()),
+        ("Location", event.location.clone()),
+    ];
+    if !ticket.seat_number.is_empty() {
+        rows.push(("Seat", ticket.seat_number.clone()));
+    }
+    if let Some(zone) = &ticket.zone {
+        rows.push(("Zone", zone.clone()));
+    }
+    rows.push(("Status", status));
+
+    let body = format!(
+        "{}<p><a href=\"/events/{}\">Event page</a> | <a href=\"/api/tickets/{}\">JSON</a></p>\n",
+        definition_list(&rows),
+        event.id,
+        ticket.id
+    );
+    Ok(html_response(
+        200,
+        html_page(&format!("Ticket {}", ticket.id), &body),
+    ))
+}
+
+// Helper function to load an event the public may see; drafts are reported as missing
+fn public_event(event_id: u64) -> Result<Event, Error> {
+    match load(&EVENTS_STORAGE, event_id)? {
+        Some(event) if event.status != EventStatus::Draft => Ok(event),
+        _ => Err(Error::EventNotFound { event_id }),
+    }
+}
+
+// Helper function to load a ticket together with its event
+fn ticket_with_event(ticket_id: u64) -> Result<(Ticket, Event), Error> {
+    let ticket = load(&TICKETS_STORAGE, ticket_id)?.ok_or(Error::TicketNotFound { ticket_id })?;
+    let event = load(&EVENTS_STORAGE, ticket.event_id)?.ok_or(Error::EventNotFound {
+        event_id: ticket.event_id,
+    })?;
+    Ok((ticket, event))
+}
+
+// Helper function to tell whether a ticket still gets its holder in
+fn admits_entry(ticket: &Ticket, event: &Event) -> bool {
+    ticket.checked_in_at.is_none() && !event.status.is_final() && event.status != EventStatus::Draft
+}
+
+// Helper function to describe an event's public fields as JSON
+fn event_value(event: &Event, details: &EventDetails) -> serde_json::Value {
+    json!({
+        "id": event.id,
+        "name": event.name,
+        "location": event.location,
+        "date": event.date,
+        "category": event.category,
+        "status": format!("{:?}", event.status),
+        "price": quote_price(event),
+        "token": event.token,
+        "tickets_available": event.total_tickets.saturating_sub(event.tickets_sold),
+        "description": details.description,
+        "tags": details.tags,
+        "media_urls": details.media_urls,
+        "doors_open": details.doors_open,
+        "end_time": details.end_time,
+        "timezone": details.timezone,
+        "minimum_age": details.minimum_age,
+        "availability": format!("/api/events/{}/availability", event.id),
+        "page": format!("/events/{}", event.id),
+    })
+}
+
+// Helper function to write a price with its token, if it is not in the default currency
+fn price_text(price: u64, token: &Option<String>) -> String {
+    match token {
+        Some(token) => format!("{} {}", price, token),
+        None => price.to_string(),
+    }
+}
+
+// Helper function to render label and value pairs as an HTML definition list
+fn definition_list(rows: &[(&str, String)]) -> String {
+    let mut html = String::from("<dl>\n");
+    for (label, value) in rows {
+        html.push_str(&format!(
+            "<dt>{}</dt><dd>{}</dd>\n",
+            label,
+            escape_html(value)
+        ));
+    }
+    html.push_str("</dl>\n");
+    html
+}
+
+// Helper function to wrap the body of a page in a complete HTML document
+fn html_page(title: &str, body: &str) -> String {
+    let title = escape_html(title);
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n{}</body>\n</html>\n",
+        title, title, body
+    )
+}
+
+// Helper function to escape text for use in HTML
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Helper function to report an error as JSON to API clients and as a page to browsers
+fn error_response(api: bool, status_code: u16, message: &str) -> HttpResponse {
+    if api {
+        json_response(status_code, json!({ "error": message }))
+    } else {
+        let body = format!("<p>{}</p>\n", escape_html(message));
+        html_response(status_code, html_page("Not available", &body))
+    }
+}
+
+// Helper function to build a JSON response
+fn json_response(status_code: u16, value: serde_json::Value) -> HttpResponse {
+    http_response(
+        status_code,
+        "application/json",
+        value.to_string().into_bytes(),
+    )
+}
+
+// Helper function to build an HTML response
+fn html_response(status_code: u16, html: String) -> HttpResponse {
+    http_response(status_code, "text/html; charset=utf-8", html.into_bytes())
+}
+
+// Helper function to build a plain text response
+fn text_response(status_code: u16, text: &str) -> HttpResponse {
+    http_response(
+        status_code,
+        "text/plain; charset=utf-8",
+        text.as_bytes().to_vec(),
+    )
+}
+
+fn http_response(status_code: u16, content_type: &str, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), content_type.to_string())],
+        body,
+        upgrade: None,
+    }
+}
+
+// Helper function to get the kept response for a path, with the certificate proving it
+fn certified_response(path: &str) -> Option<HttpResponse> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let mut response = HTTP_RESPONSES.with(|responses| {
+        responses
+            .borrow()
+            .get(path)
+            .map(|cached| cached.response.clone())
+    })?;
+    let witness = HashTree::Fork(
+        Box::new(http_assets_tree(Some(path))),
+        Box::new(HashTree::Pruned(tip_subtree().digest())),
+    );
+    response.headers.push((
+        "IC-Certificate".to_string(),
+        format!(
+            "certificate=:{}:, tree=:{}:",
+            BASE64.encode(certificate),
+            BASE64.encode(witness.to_cbor())
+        ),
+    ));
+    Some(response)
+}
+
+// Helper function to keep a response for http_request and certify it. The oldest path
+// makes room once the limit is reached.
+fn keep_response(path: String, response: &HttpResponse) {
+    HTTP_RESPONSES.with(|responses| {
+        let mut responses = responses.borrow_mut();
+        if responses.len() >= MAX_CACHED_RESPONSES && !responses.contains_key(&path) {
+            responses.pop_first();
+        }
+        responses.insert(
+            path,
+            CachedResponse {
+                response: response.clone(),
+                body_hash: Sha256::digest(&response.body).into(),
+            },
+        );
+    });
+    certify_data();
+}
+
+// Helper function to drop every kept response, so pages are rendered again from the
+// current state
+fn forget_http_responses() {
+    let forgotten = HTTP_RESPONSES.with(|responses| {
+        let mut responses = responses.borrow_mut();
+        let forgotten = !responses.is_empty();
+        responses.clear();
+        forgotten
+    });
+    if forgotten {
+        certify_data();
+    }
+}
+
+// Helper function to hash parts of a hash tree node under its domain separator
+fn domain_hash(domain: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([domain.len() as u8]);
+    hasher.update(domain);
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+// Helper function to compute the representation-independent hash ICRC-3 chains blocks with
+fn value_hash(value: &Icrc3Value) -> [u8; 32] {
+    match value {
+        Icrc3Value::Blob(bytes) => Sha256::digest(bytes).into(),
+        Icrc3Value::Text(text) => Sha256::digest(text).into(),
+        Icrc3Value::Nat(nat) => {
+            let mut bytes = Vec::new();
+            nat.encode(&mut bytes).expect("Cannot encode a Nat");
+            Sha256::digest(bytes).into()
+        }
+        Icrc3Value::Int(int) => {
+            let mut bytes = Vec::new();
+            int.encode(&mut bytes).expect("Cannot encode an Int");
+            Sha256::digest(bytes).into()
+        }
+        Icrc3Value::Array(items) => {
+            let mut hasher = Sha256::new();
+            for item in items {
+                hasher.update(value_hash(item));
+            }
+            hasher.finalize().into()
+        }
+        Icrc3Value::Map(entries) => {
+            let mut fields: Vec<Vec<u8>> = entries
+                .iter()
+                .map(|(key, value)| [Sha256::digest(key).as_slice(), &value_hash(value)].concat())
+                .collect();
+            fields.sort();
+            Sha256::digest(fields.concat()).into()
+        }
+    }
+}
+
+fn nat_value(value: u64) -> Icrc3Value {
+    Icrc3Value::Nat(candid::Nat::from(value))
+}
+
+// Helper function to represent an ICRC-1 account without a subaccount
+fn account_value(owner: Principal) -> Icrc3Value {
+    Icrc3Value::Array(vec![Icrc3Value::Blob(owner.as_slice().to_vec())])
+}
+
+// Helper function to read a Nat argument, saturating at u64::MAX
+fn nat_to_u64(value: &candid::Nat) -> u64 {
+    u64::try_from(&value.0).unwrap_or(u64::MAX)
+}
+
+// Function to page through the audit log, newest calls first. Restricted to controllers.
+#[ic_cdk::query]
+fn list_audit_log(query: AuditLogQuery) -> Result<AuditPage, Error> {
+    require_admin()?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE) as usize;
+    let operation = query.operation.as_deref().map(str::trim);
+
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let end = query
+            .cursor
+            .map_or(log.len(), |cursor| cursor.min(log.len()));
+        let mut entries = Vec::new();
+        let mut next_cursor = None;
+        for (scanned, id) in (0..end).rev().enumerate() {
+            if scanned == MAX_AUDIT_SCAN {
+                next_cursor = Some(id + 1);
+                break;
+            }
+            // Like load_all, entries that fail to decode are left out
+            let Some(entry) = log.get(id).and_then(|stored| stored.decode().ok()) else {
+                continue;
+            };
+            let matches = query.actor.map_or(true, |actor| entry.caller == actor)
+                && query.entity.map_or(true, |entity| entry.entity == entity)
+                && query
+                    .target_id
+                    .map_or(true, |target_id| entry.target_ids.contains(&target_id))
+                && operation.map_or(true, |operation| entry.operation == operation);
+            if !matches {
+                continue;
+            }
+            if entries.len() == limit {
+                next_cursor = Some(id + 1);
+                break;
+            }
+            entries.push(entry);
+        }
+        Ok(AuditPage {
+            entries,
+            next_cursor,
+            unrecorded_calls: UNRECORDED_CALLS.with(|count| *count.borrow()),
+        })
+    })
+}
+
+// Function to report the API version clients are talking to
+#[ic_cdk::query]
+fn api_version() -> u32 {
+    API_VERSION
+}
+
+// Function to translate a structured error into its API version 1 form
+#[ic_cdk::query]
+fn to_legacy_message(error: Error) -> Message {
+    Message::from(error)
+}
+
+// Helper function to reject callers that are not canister controllers
+fn require_admin() -> Result<(), Error> {
+    if is_controller(&caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            required_role: Role::Admin,
+        })
+    }
+}
+
+// Helper function to reject callers that neither created the event nor control the canister.
+// Events created before organizers were recorded can only be managed by a controller.
+fn require_organizer(event: &Event) -> Result<(), Error> {
+    let caller = caller();
+    let is_organizer = caller != Principal::anonymous() && event.organizer == Some(caller);
+    if is_organizer || is_controller(&caller) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            required_role: Role::Organizer,
+        })
+    }
+}
+
+// Helper function to reject callers that neither own the organizer profile nor control the canister
+fn require_organizer_owner(organizer_id: u64) -> Result<Organizer, Error> {
+    let organizer = load(&ORGANIZERS_STORAGE, organizer_id)?
+        .ok_or(Error::OrganizerNotFound { organizer_id })?;
+    let caller = caller();
+    if organizer.owner == caller || is_controller(&caller) {
+        Ok(organizer)
+    } else {
+        Err(Error::Unauthorized {
+            required_role: Role::Organizer,
+        })
+    }
+}
+
+// Helper function to reject callers that did not register the given user
+fn require_account_owner(user_id: u64) -> Result<User, Error> {
+    let user = load(&USERS_STORAGE, user_id)?.ok_or(Error::UserNotFound { user_id })?;
+
+    // Anonymous registrations, including accounts migrated from before owners
+    // were recorded, belong to nobody until a controller assigns them an owner
+    let caller = caller();
+    if user.owner != Principal::anonymous() && user.owner == caller {
+        Ok(user)
+    } else {
+        Err(Error::Unauthorized {
+            required_role: Role::AccountOwner,
+        })
+    }
+}
+
+fn field_locked(field: &str) -> Error {
+    Error::FieldLocked {
+        field: field.to_string(),
+    }
+}
+
+fn missing_field(field: &str) -> Error {
+    Error::MissingField {
+        field: field.to_string(),
+    }
+}
+
+fn invalid_field(field: &str, reason: &str) -> Error {
+    Error::InvalidField {
+        field: field.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+fn check_length(field: &str, value: &str, max_length: u32) -> Result<(), Error> {
+    if value.len() > max_length as usize {
+        return Err(Error::FieldTooLong {
+            field: field.to_string(),
+            max_length,
+        });
+    }
+    Ok(())
+}
+
+// Helper function to trim an optional category, treating a blank one as none
+fn normalize_category(category: Option<String>) -> Result<Option<String>, Error> {
+    let category = category
+        .map(|category| category.trim().to_string())
+        .filter(|category| !category.is_empty());
+    if let Some(category) = &category {
+        check_length("category", category, MAX_CATEGORY_LENGTH)?;
+    }
+    Ok(category)
+}
+
+// Helper function to trim and length-check an event description
+fn normalize_description(description: String) -> Result<String, Error> {
+    let description = description.trim().to_string();
+    check_length("description", &description, MAX_DESCRIPTION_LENGTH)?;
+    Ok(description)
+}
+
+// Helper function to check and trim media URLs, dropping blanks
+fn normalize_media_urls(media_urls: Vec<String>) -> Result<Vec<String>, Error> {
+    let mut normalized: Vec<String> = Vec::new();
+    for url in media_urls {
+        let url = url.trim().to_string();
+        if url.is_empty() {
+            continue;
+        }
+        check_length("media_urls", &url, MAX_URL_LENGTH)?;
+        if !url.starts_with("https://") {
+            return Err(invalid_field("media_urls", "must be https URLs"));
+        }
+        normalized.push(url);
+    }
+    if normalized.len() > MAX_MEDIA_URLS {
+        return Err(invalid_field(
+            "media_urls",
+            &format!("at most {} URLs are allowed", MAX_MEDIA_URLS),
+        ));
+    }
+    Ok(normalized)
+}
+
+// Helper function to trim organizer contact details, treating blank ones as none
+fn normalize_contact(contact: String) -> Result<Option<String>, Error> {
+    let contact = contact.trim().to_string();
+    check_length("organizer_contact", &contact, MAX_CONTACT_LENGTH)?;
+    Ok(Some(contact).filter(|contact| !contact.is_empty()))
+}
+
+// Helper function to check that a time zone looks like an IANA name, treating a blank one as none
+fn normalize_timezone(timezone: String) -> Result<Option<String>, Error> {
+    let timezone = timezone.trim().to_string();
+    check_length("timezone", &timezone, MAX_TIMEZONE_LENGTH)?;
+    let valid = timezone
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "/_+-".contains(c));
+    if !valid {
+        return Err(invalid_field("timezone", "must be an IANA time zone name"));
+    }
+    Ok(Some(timezone).filter(|timezone| !timezone.is_empty()))
+}
+
+// Helper function to check a minimum age, where zero means there is none
+fn normalize_minimum_age(minimum_age: u8) -> Result<Option<u8>, Error> {
+    if minimum_age > MAX_MINIMUM_AGE {
+        return Err(invalid_field(
+            "minimum_age",
+            &format!("must be at most {}", MAX_MINIMUM_AGE),
+        ));
+    }
+    Ok(Some(minimum_age).filter(|age| *age != 0))
+}
+
+// Helper function to check that doors open no later than the event starts, and that
+// it ends after it starts
+fn check_schedule(date: u64, doors_open: Option<u64>, end_time: Option<u64>) -> Result<(), Error> {
+    if doors_open.is_some_and(|doors_open| doors_open > date) {
+        return Err(invalid_field(
+            "doors_open",
+            "must not be after the event's date",
+        ));
+    }
+    if end_time.is_some_and(|end_time| end_time <= date) {
+        return Err(invalid_field("end_time", "must be after the event's date"));
+    }
+    Ok(())
+}
+
+// Helper function to lowercase event tags, dropping blanks and duplicates
+fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>, Error> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() || normalized.contains(&tag) {
+            continue;
+        }
+        check_length("tags", &tag, MAX_TAG_LENGTH)?;
+        normalized.push(tag);
+    }
+    if normalized.len() > MAX_TAGS {
+        return Err(invalid_field(
+            "tags",
+            &format!("at most {} tags are allowed", MAX_TAGS),
+        ));
+    }
+    Ok(normalized)
+}
+
+// Helper function to validate an organizer's display name
+fn check_organizer_name(name: &str) -> Result<String, Error> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(missing_field("name"));
+    }
+    check_length("name", name, MAX_ORGANIZER_NAME_LENGTH)?;
+    Ok(name.to_string())
+}
+
+// Helper function to validate an organizer's contact address; empty means none
+fn normalize_contact_email(email: String) -> Result<Option<String>, Error> {
+    let email = email.trim();
+    if email.is_empty() {
+        return Ok(None);
+    }
+    check_length("contact_email", email, MAX_EMAIL_LENGTH)?;
+    if !email.contains('@') {
+        return Err(invalid_field("contact_email", "must be an email address"));
+    }
+    Ok(Some(email.to_string()))
+}
+
+// Helper function to validate an organizer's tax jurisdiction; empty means none
+fn normalize_jurisdiction(jurisdiction: String) -> Result<Option<String>, Error> {
+    let jurisdiction = jurisdiction.trim().to_ascii_uppercase();
+    if jurisdiction.is_empty() {
+        return Ok(None);
+    }
+    if find_tax_rate(&jurisdiction).is_none() {
+        return Err(Error::TaxRateNotFound { jurisdiction });
+    }
+    Ok(Some(jurisdiction))
+}
+
+// Helper function to validate the token an event is priced in; empty means the default currency
+fn normalize_token(token: String) -> Result<Option<String>, Error> {
+    let token = token_symbol(&token);
+    if token.is_empty() {
+        return Ok(None);
+    }
+    if find_token(&token).is_none() {
+        return Err(Error::UnknownToken { token });
+    }
+    Ok(Some(token))
+}
+
+// Helper function to validate the other tokens an event accepts and their prices
+fn normalize_token_prices(
+    token_prices: Vec<TokenPrice>,
+    event_token: &Option<String>,
+) -> Result<Option<Vec<TokenPrice>>, Error> {
+    if token_prices.len() > MAX_ACCEPTED_TOKENS {
+        return Err(invalid_field(
+            "token_prices",
+            &format!("at most {} tokens can be accepted", MAX_ACCEPTED_TOKENS),
+        ));
+    }
+
+    let mut normalized: Vec<TokenPrice> = Vec::with_capacity(token_prices.len());
+    for token_price in token_prices {
+        let token = normalize_token(token_price.token)?.ok_or_else(|| missing_field("token"))?;
+        if Some(&token) == event_token.as_ref() {
+            return Err(invalid_field(
+                "token_prices",
+                "must not list the event's own token",
+            ));
+        }
+        if normalized.iter().any(|other| other.token == token) {
+            return Err(invalid_field(
+                "token_prices",
+                &format!("{} is listed more than once", token),
+            ));
+        }
+        if token_price.price == 0 {
+            return Err(invalid_field(
+                "token_prices",
+                "prices must be greater than zero",
+            ));
+        }
+        normalized.push(TokenPrice {
+            token,
+            price: token_price.price,
+        });
+    }
+    Ok(Some(normalized).filter(|prices| !prices.is_empty()))
+}
+
+// Helper function to check that a rate is at most 100%
+fn check_basis_points(field: &str, basis_points: u32) -> Result<(), Error> {
+    if basis_points > MAX_BASIS_POINTS {
+        return Err(invalid_field(
+            field,
+            &format!("must be at most {} basis points", MAX_BASIS_POINTS),
+        ));
+    }
+    Ok(())
+}
+
+// Helper function to validate class premiums; one per seat class at most
+fn normalize_class_premiums(
+    class_premiums: Vec<ClassPremium>,
+) -> Result<Option<Vec<ClassPremium>>, Error> {
+    for (position, premium) in class_premiums.iter().enumerate() {
+        if class_premiums[..position]
+            .iter()
+            .any(|other| other.seat_class == premium.seat_class)
+        {
+            return Err(invalid_field(
+                "class_premiums",
+                &format!("{:?} is listed more than once", premium.seat_class),
+            ));
+        }
+    }
+    Ok(Some(class_premiums).filter(|premiums| !premiums.is_empty()))
+}
+
+// Helper function to validate an ICRC-1 account payouts can be sent to
+fn check_account(account: &Account) -> Result<(), Error> {
+    if account.owner == Principal::anonymous() {
+        return Err(invalid_field(
+            "payout_account",
+            "must not belong to the anonymous principal",
+        ));
+    }
+    match &account.subaccount {
+        Some(subaccount) if subaccount.len() != ICRC1_SUBACCOUNT_LENGTH => Err(invalid_field(
+            "payout_account",
+            "subaccounts must be 32 bytes long",
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Helper function to check a venue's fields and seat map
+fn check_venue(payload: &CreateVenuePayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() {
+        return Err(missing_field("name"));
+    }
+    if payload.address.trim().is_empty() {
+        return Err(missing_field("address"));
+    }
+    check_length("name", &payload.name, MAX_VENUE_NAME_LENGTH)?;
+    check_length("address", &payload.address, MAX_ADDRESS_LENGTH)?;
+    if let Some(coordinates) = payload.coordinates {
+        if !(-90.0..=90.0).contains(&coordinates.latitude) {
+            return Err(invalid_field(
+                "coordinates",
+                "latitude must be between -90 and 90",
+            ));
+        }
+        if !(-180.0..=180.0).contains(&coordinates.longitude) {
+            return Err(invalid_field(
+                "coordinates",
+                "longitude must be between -180 and 180",
+            ));
+        }
+    }
+    if payload.capacity == 0 {
+        return Err(invalid_field("capacity", "must be greater than zero"));
+    }
+
+    let seats = check_sections(&payload.sections)?;
+    if seats > payload.capacity {
+        return Err(invalid_field(
+            "capacity",
+            &format!("must be at least the {} seats in the seat map", seats),
+        ));
+    }
+    Ok(())
+}
+
+// Helper function to check a seat map and count its seats. Names may not contain '-'
+// so that seat labels split back into section, row and number unambiguously.
+fn check_sections(sections: &[VenueSection]) -> Result<u64, Error> {
+    if sections.len() > MAX_SECTIONS {
+        return Err(invalid_field(
+            "sections",
+            &format!("at most {} sections are allowed", MAX_SECTIONS),
+        ));
+    }
+
+    let mut seats: u64 = 0;
+    for (position, section) in sections.iter().enumerate() {
+        check_seat_map_name("section name", &section.name, MAX_SECTION_NAME_LENGTH)?;
+        if sections[..position]
+            .iter()
+            .any(|other| other.name == section.name)
+        {
+            return Err(invalid_field(
+                "sections",
+                &format!("section {} appears twice", section.name),
+            ));
+        }
+        if section.rows.len() > MAX_ROWS_PER_SECTION {
+            return Err(invalid_field(
+                "sections",
+                &format!(
+                    "at most {} rows are allowed per section",
+                    MAX_ROWS_PER_SECTION
+                ),
+            ));
+        }
+
+        for (row_position, row) in section.rows.iter().enumerate() {
+            check_seat_map_name("row name", &row.name, MAX_ROW_NAME_LENGTH)?;
+            if section.rows[..row_position]
+                .iter()
+                .any(|other| other.name == row.name)
+            {
+                return Err(invalid_field(
+                    "sections",
+                    &format!("row {} appears twice in section {}", row.name, section.name),
+                ));
+            }
+            if row.seats == 0 || row.seats > MAX_SEATS_PER_ROW {
+                return Err(invalid_field(
+                    "sections",
+                    &format!("rows must have between 1 and {} seats", MAX_SEATS_PER_ROW),
+                ));
+            }
+            if let Some(seat) = row
+                .accessible_seats
+                .iter()
+                .find(|seat| seat.number == 0 || seat.number > row.seats)
+            {
+                return Err(Error::UnknownSeat {
+                    seat: seat_label(&section.name, &row.name, seat.number),
+                });
+            }
+            seats += u64::from(row.seats);
+        }
+    }
+    Ok(seats)
+}
+
+// Helper function to check the name of a section or row
+fn check_seat_map_name(field: &str, name: &str, max_length: u32) -> Result<(), Error> {
+    if name.is_empty() {
+        return Err(missing_field(field));
+    }
+    check_length(field, name, max_length)?;
+    if name.contains('-') || name.trim() != name {
+        return Err(invalid_field(
+            field,
+            "must not contain '-' or surrounding spaces",
+        ));
+    }
+    Ok(())
+}
+
+// Helper function to build the label of a seat, such as "Stalls-C-12"
+fn seat_label(section: &str, row: &str, number: u32) -> String {
+    format!("{}-{}-{}", section, row, number)
+}
+
+// Helper function to count the seats of a section that are not blocked from sale
+fn seats_for_sale(seat_map: Option<&EventSeatMap>, section: &VenueSection) -> u64 {
+    let seats: u64 = section.rows.iter().map(|row| u64::from(row.seats)).sum();
+    let blocked = seat_map.map_or(0, |seat_map| {
+        seat_map
+            .blocked_seats
+            .iter()
+            .filter(|seat| {
+                find_seat(&seat_map.sections, seat)
+                    .is_some_and(|(blocked_section, _, _)| blocked_section.name == section.name)
+            })
+            .count()
+    });
+    seats.saturating_sub(blocked as u64)
+}
+
+// Helper function to look up a seat label in a seat map
+fn find_seat<'a>(
+    sections: &'a [VenueSection],
+    label: &str,
+) -> Option<(&'a VenueSection, &'a VenueRow, u32)> {
+    let seat = SeatId::parse(label)?;
+
+    let section = sections
+        .iter()
+        .find(|section| section.name == seat.section)?;
+    let row = section.rows.iter().find(|row| row.name == seat.row)?;
+    (1..=row.seats)
+        .contains(&seat.number)
+        .then_some((section, row, seat.number))
+}
+
+// Helper function to choose free seats of a class for purchase_best_available
+fn allocate_seats(
+    seat_map: &EventSeatMap,
+    zone_sections: Option<&[String]>,
+    seat_class: SeatClass,
+    quantity: u32,
+    allow_split: bool,
+) -> Result<Vec<SeatId>, Error> {
+    let mut unavailable = taken_seats(seat_map.event_id);
+    unavailable.extend(seat_map.blocked_seats.iter().cloned());
+
+    // Free seat numbers of every row of the class, best rows first
+    let rows: Vec<(&VenueSection, &VenueRow, Vec<u32>)> = seat_map
+        .sections
+        .iter()
+        .filter(|section| section.seat_class == seat_class)
+        .filter(|section| zone_sections.map_or(true, |names| names.contains(&section.name)))
+        .flat_map(|section| section.rows.iter().map(move |row| (section, row)))
+        .map(|(section, row)| {
+            let free = (1..=row.seats)
+                .filter(|number| {
+                    !unavailable.contains(&seat_label(&section.name, &row.name, *number))
+                })
+                .collect();
+            (section, row, free)
+        })
+        .collect();
+    let seat_id = |section: &VenueSection, row: &VenueRow, number: u32| SeatId {
+        section: section.name.clone(),
+        row: row.name.clone(),
+        number,
+    };
+
+    let available: u64 = rows.iter().map(|(_, _, free)| free.len() as u64).sum();
+    if available < u64::from(quantity) {
+        return Err(Error::InsufficientSeats {
+            requested: quantity,
+            available,
+        });
+    }
+
+    for (section, row, free) in &rows {
+        if let Some(start) = best_block(free, row.seats, quantity) {
+            return Ok((start..start + quantity)
+                .map(|number| seat_id(section, row, number))
+                .collect());
+        }
+    }
+
+    if !allow_split {
+        return Err(Error::NoAdjacentSeats {
+            requested: quantity,
+        });
+    }
+    Ok(rows
+        .iter()
+        .flat_map(|(section, row, free)| free.iter().map(|number| seat_id(section, row, *number)))
+        .take(quantity as usize)
+        .collect())
+}
+
+// Helper function to find the first seat of the run of `quantity` adjacent free seats
+// closest to the middle of a row, given the row's free seat numbers in ascending order
+fn best_block(free: &[u32], seats: u32, quantity: u32) -> Option<u32> {
+    // Distance from the middle of the row, doubled to stay in whole numbers
+    let distance = |start: u32| (i64::from(2 * start + quantity) - i64::from(seats + 2)).abs();
+    let ideal_start = (seats.saturating_sub(quantity)) / 2 + 1;
+
+    let mut best: Option<u32> = None;
+    let mut run_start = 0;
+    for (position, number) in free.iter().enumerate() {
+        if position > 0 && free[position - 1] + 1 != *number {
+            run_start = position;
+        }
+        let run_length = (position - run_start + 1) as u32;
+        let run_ends_here = free.get(position + 1) != Some(&(number + 1));
+        if !run_ends_here || run_length < quantity {
+            continue;
+        }
+
+        // Within this run, start as close to the ideal start as the run allows
+        let first = free[run_start];
+        let last_start = number + 1 - quantity;
+        let start = ideal_start.clamp(first, last_start);
+        if best.map_or(true, |best| distance(start) < distance(best)) {
+            best = Some(start);
+        }
+    }
+    best
+}
+
+// Helper function to derive a user's referral code from their id
+fn referral_code_for(user_id: u64) -> String {
+    const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+    let mut value = user_id;
+    let mut digits = Vec::new();
+    loop {
+        digits.push(DIGITS[(value % 36) as usize] as char);
+        value /= 36;
+        if value == 0 {
+            break;
+        }
+    }
+
+    let encoded: String = digits.into_iter().rev().collect();
+    format!("{}{}", REFERRAL_CODE_PREFIX, encoded)
+}
+
+// Helper function to check the format of an email address, returning it trimmed
+fn check_email(email: &str) -> Result<String, Error> {
+    let email = email.trim();
+    if email.is_empty() {
+        return Err(missing_field("email"));
+    }
+    check_length("email", email, MAX_EMAIL_LENGTH)?;
+
+    let (local, domain) = email
+        .rsplit_once('@')
+        .ok_or_else(|| invalid_field("email", "must contain an @"))?;
+    let valid_local = !local.is_empty()
+        && local.len() <= 64
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..")
+        && local
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(c));
+    if !valid_local {
+        return Err(invalid_field("email", "the part before the @ is not valid"));
+    }
+
+    let labels: Vec<&str> = domain.split('.').collect();
+    let valid_label = |label: &&str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    let top_level = labels.last().copied().unwrap_or_default();
+    let valid_domain = labels.len() >= 2
+        && labels.iter().all(valid_label)
+        && top_level.len() >= 2
+        && top_level.chars().all(|c| c.is_ascii_alphabetic());
+    if !valid_domain {
+        return Err(invalid_field("email", "the domain is not valid"));
+    }
+
+    Ok(email.to_string())
+}
+
+// Helper function to normalize an email address for USERS_BY_EMAIL
+fn email_key(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+// Helper function to check a username for a new user, or a renamed one, returning it
+// trimmed. Usernames are unique, ignoring case.
+fn check_username(username: &str, user_id: Option<u64>) -> Result<String, Error> {
+    let username = username.trim();
+    if username.is_empty() {
+        return Err(missing_field("username"));
+    }
+    check_length("username", username, MAX_USERNAME_LENGTH)?;
+    if find_user_in(&USERS_BY_USERNAME, &username_key(username))?
+        .is_some_and(|other| Some(other.id) != user_id)
+    {
+        return Err(Error::UsernameTaken {
+            username: username.to_string(),
+        });
+    }
+    Ok(username.to_string())
+}
+
+// Helper function to normalize a username for USERS_BY_USERNAME
+fn username_key(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+// Helper function to show a user's profile, with the email only to the account's owner
+fn user_profile(user: User) -> UserProfile {
+    let caller = caller();
+    let is_owner = caller != Principal::anonymous() && user.owner == caller;
+    UserProfile {
+        id: user.id,
+        username: user.username,
+        referral_code: user.referral_code,
+        email: Some(user.email).filter(|_| is_owner),
+        email_verified_at: user.email_verified_at.filter(|_| is_owner),
+    }
+}
+
+// Helper function to find the first user a text index lists under a key
+fn find_user_in(index: &'static TextIndexStore, text: &str) -> Result<Option<User>, Error> {
+    match first_indexed(index, text) {
+        Some(user_id) => load(&USERS_STORAGE, user_id),
+        None => Ok(None),
+    }
+}
+
+// Helper function to list a user under their email address, username and owner
+fn index_user_fields(user: &User) {
+    index_user(&USERS_BY_EMAIL, email_key(&user.email), user.id);
+    index_user(&USERS_BY_USERNAME, username_key(&user.username), user.id);
+    if user.owner != Principal::anonymous() {
+        index_user(&USERS_BY_OWNER, user.owner.to_text(), user.id);
+    }
+}
+
+fn unindex_user_fields(user: &User) {
+    for (index, text) in [
+        (&USERS_BY_EMAIL, email_key(&user.email)),
+        (&USERS_BY_USERNAME, username_key(&user.username)),
+        (&USERS_BY_OWNER, user.owner.to_text()),
+    ] {
+        index.with(|index| {
+            index.borrow_mut().remove(&TextIndexKey {
+                text,
+                record_id: user.id,
+            });
+        });
+    }
+}
+
+// Helper function to add a user to a text index. Keys too long for the index, which only
+// accounts from before emails were checked can have, are left out.
+fn index_user(index: &'static TextIndexStore, text: String, user_id: u64) {
+    if text.len() > MAX_INDEX_TEXT_LENGTH as usize {
+        return;
+    }
+    index_text_key(index, text, user_id);
+}
+
+// Helper function to list a record under a text key
+fn index_text_key(index: &'static TextIndexStore, text: String, record_id: u64) {
+    index.with(|index| {
+        index
+            .borrow_mut()
+            .insert(TextIndexKey { text, record_id }, ());
+    });
+}
+
+// Helper function to find the first record listed under a text key
+fn first_indexed(index: &'static TextIndexStore, text: &str) -> Option<u64> {
+    index.with(|index| {
+        index
+            .borrow()
+            .range(
+                TextIndexKey {
+                    text: text.to_string(),
+                    record_id: 0,
+                }..,
+            )
+            .next()
+            .filter(|(key, _)| key.text == text)
+            .map(|(key, _)| key.record_id)
+    })
+}
+
+// Helper function to load the notification hook, if a controller has set one
+fn notification_hook() -> Result<Option<NotificationHook>, Error> {
+    load(&NOTIFICATION_HOOK_STORAGE, SETTINGS_KEY)
+}
+
+// Helper function to reject buyers whose email address is not confirmed. Codes can only
+// be delivered through a notification hook, so nothing is required until one is set.
+// Accounts nobody owns yet can only confirm theirs after assign_owner.
+fn require_verified_email(user_id: u64) -> Result<(), Error> {
+    let user = load(&USERS_STORAGE, user_id)?.ok_or(Error::UserNotFound { user_id })?;
+    if !email_verified(&user)? {
+        return Err(Error::EmailNotVerified { user_id });
+    }
+    Ok(())
+}
+
+// Helper function to tell whether a user's email address counts as confirmed. Every
+// address does while no notification hook is set, since none could be confirmed.
+fn email_verified(user: &User) -> Result<bool, Error> {
+    Ok(user.email_verified_at.is_some() || notification_hook()?.is_none())
+}
+
+// Helper function to issue a verification code and post it to the notification hook
+async fn send_verification_code(user_id: u64) -> Result<(), Error> {
+    let user = require_account_owner(user_id)?;
+    if user.email_verified_at.is_some() {
+        return Err(invalid_field(
+            "user_id",
+            "the email address is already confirmed",
+        ));
+    }
+    let hook = notification_hook()?.ok_or_else(|| Error::NotificationFailed {
+        reason: "no notification hook is set".to_string(),
+    })?;
+    if let Some(pending) = load(&EMAIL_VERIFICATIONS_STORAGE, user_id)? {
+        if time()
+            < pending
+                .issued_at
+                .saturating_add(VERIFICATION_RESEND_INTERVAL)
+        {
+            return Err(invalid_field(
+                "user_id",
+                "a code was sent less than a minute ago",
+            ));
+        }
+    }
+
+    let (random,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, message)| Error::NotificationFailed {
+            reason: format!("cannot draw a code: {:?} {}", code, message),
+        })?;
+    let mut seed = [0; 4];
+    seed.copy_from_slice(&random[..4]);
+    let code = format!("{:06}", u32::from_be_bytes(seed) % 1_000_000);
+
+    let issued_at = time();
+    let verification = EmailVerification {
+        user_id,
+        email: user.email.clone(),
+        code_hash: verification_hash(user_id, &code),
+        issued_at,
+        expires_at: issued_at + VERIFICATION_CODE_TTL,
+        attempts: 0,
+    };
+    store(&EMAIL_VERIFICATIONS_STORAGE, user_id, &verification)?;
+
+    let notification = json!({
+        "kind": "email_verification",
+        "user_id": user_id,
+        "username": user.username,
+        "email": user.email,
+        "code": code,
+        "expires_at": verification.expires_at,
+    });
+    let idempotency_key = format!("email-verification-{}-{}", user_id, issued_at);
+    if let Err(err) = notify(&hook, &idempotency_key, &notification).await {
+        // A code that never arrived should not hold back asking for another one
+        EMAIL_VERIFICATIONS_STORAGE.with(|storage| storage.borrow_mut().remove(&user_id));
+        return Err(err);
+    }
+    Ok(())
+}
+
+// Helper function to hash a verification code, so that stored codes cannot be read back
+fn verification_hash(user_id: u64, code: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(user_id.to_be_bytes());
+    hasher.update(code);
+    hasher.finalize().to_vec()
+}
+
+// Helper function to post a notification to the hook with an HTTPS outcall. Every replica
+// sends the request, so the idempotency key lets the hook deliver it only once.
+async fn notify(
+    hook: &NotificationHook,
+    idempotency_key: &str,
+    notification: &serde_json::Value,
+) -> Result<(), Error> {
+    let request = CanisterHttpRequestArgument {
+        url: hook.url.clone(),
+        max_response_bytes: Some(MAX_NOTIFICATION_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader {
+                name: "Content-Type".to_string(),
+                value: "application/json".to_string(),
+            },
+            HttpHeader {
+                name: "Idempotency-Key".to_string(),
+                value: idempotency_key.to_string(),
+            },
+        ],
+        body: Some(notification.to_string().into_bytes()),
+        transform: Some(TransformContext::from_name(
+            "transform_notification_response".to_string(),
+            Vec::new(),
+        )),
+    };
+
+    match outcall::http_request(request, NOTIFICATION_CYCLES).await {
+        Ok((response,)) if (200..300).contains(&nat_to_u64(&response.status)) => Ok(()),
+        Ok((response,)) => Err(Error::NotificationFailed {
+            reason: format!("the hook answered with status {}", response.status),
+        }),
+        Err((code, message)) => Err(Error::NotificationFailed {
+            reason: format!("{:?} {}", code, message),
+        }),
+    }
+}
+
+// Helper function to look up the owner of a referral code
+fn find_user_by_referral_code(code: &str) -> Result<Option<User>, Error> {
+    let code = code.to_ascii_uppercase();
+    let user_id = match code
+        .strip_prefix(REFERRAL_CODE_PREFIX)
+        .and_then(|encoded| u64::from_str_radix(encoded, 36).ok())
+    {
+        Some(user_id) => user_id,
+        None => return Ok(None),
+    };
+
+    Ok(load(&USERS_STORAGE, user_id)?.filter(|user| user.referral_code == code))
+}
+
+// Helper function to load referral statistics, defaulting for users with none yet
+fn referral_stats_for(user: &User) -> Result<ReferralStats, Error> {
+    Ok(
+        load(&REFERRAL_STATS_STORAGE, user.id)?.unwrap_or_else(|| ReferralStats {
+            user_id: user.id,
+            referral_code: user.referral_code.clone(),
+            ..Default::default()
+        }),
+    )
+}
+
+// Helper function to index a referral under its referrer
+fn index_referral(referral: &Referral) {
+    REFERRALS_BY_REFERRER.with(|index| {
+        index.borrow_mut().insert(
+            IndexKey {
+                parent_id: referral.referrer_id,
+                record_id: referral.referred_id,
+            },
+            (),
+        );
+    });
+}
+
+// Helper function to remove a referral from the referrer index
+fn unindex_referral(referral: &Referral) {
+    REFERRALS_BY_REFERRER.with(|index| {
+        index.borrow_mut().remove(&IndexKey {
+            parent_id: referral.referrer_id,
+            record_id: referral.referred_id,
+        });
+    });
+}
+
+// Helper function to list the users a user referred
+fn referred_by(referrer_id: u64) -> Vec<u64> {
+    REFERRALS_BY_REFERRER.with(|index| {
+        index
+            .borrow()
+            .range(IndexKey::range_of(referrer_id))
+            .map(|(key, _)| key.record_id)
+            .collect()
+    })
+}
+
+// Helper function to work out the payout of a pending referral once the referred user
+// makes their first qualifying purchase, without writing anything. A purchase qualifies
+// once the referred user's email address counts as verified and it was paid for, by
+// someone other than the referrer; until then the referral stays pending.
+fn reward_referral_on_first_purchase(
+    user_id: u64,
+    payer: Option<Principal>,
+) -> Result<Option<ReferralReward>, Error> {
+    let mut referral = match load(&REFERRALS_STORAGE, user_id)? {
+        Some(referral) if matches!(referral.status, ReferralStatus::Pending) => referral,
+        _ => return Ok(None),
+    };
+    let verified = match load(&USERS_STORAGE, user_id)? {
+        Some(user) => email_verified(&user)?,
+        None => false,
+    };
+    if !verified {
+        return Ok(None);
+    }
+
+    let (referrer, mut stats) = match load(&USERS_STORAGE, referral.referrer_id)? {
+        Some(referrer) => {
+            let stats = referral_stats_for(&referrer)?;
+            (referrer, stats)
+        }
+        None => return Ok(None),
+    };
+    if payer.map_or(true, |payer| payer == referrer.owner) {
+        return Ok(None);
+    }
+    stats.pending_referrals = stats.pending_referrals.saturating_sub(1);
+
+    let reward = stats.rewarded_referrals < MAX_REWARDED_REFERRALS;
+    if reward {
+        stats.rewarded_referrals += 1;
+        stats.points_earned += REFERRAL_REWARD_POINTS;
+        referral.status = ReferralStatus::Rewarded;
+        referral.rewarded_at = Some(time());
+    } else {
+        referral.status = ReferralStatus::CapReached;
+    }
+
+    let credit = if reward {
+        Some(credit_loyalty_points(
+            referral.referrer_id,
+            vec![(
+                REFERRAL_REWARD_POINTS,
+                format!("Referral reward for user {}", user_id),
+            )],
+        )?)
+    } else {
+        None
+    };
+
+    Ok(Some(ReferralReward {
+        user_id,
+        referrer_id: referral.referrer_id,
+        stored_referral: Stored::encode(&referral)?,
+        stored_stats: Stored::encode(&stats)?,
+        credit,
+    }))
+}
+
+// Helper function to write a referral payout
+fn save_referral_reward(reward: ReferralReward) {
+    if let Some(credit) = reward.credit {
+        save_loyalty_credit(credit);
+    }
+    save(
+        &REFERRAL_STATS_STORAGE,
+        reward.referrer_id,
+        reward.stored_stats,
+    );
+    save(&REFERRALS_STORAGE, reward.user_id, reward.stored_referral);
+}
+
+// Helper function to calculate points
+fn calculate_points(purchase_amount: u64) -> u64 {
+    // Base rate: 1 point per 10 units spent
+    let base_points = purchase_amount / 10;
+
+    // Bonus points for larger purchases
+    let bonus_points = match purchase_amount {
+        amount if amount >= 1000 => base_points / 2, // 50% bonus
+        amount if amount >= 500 => base_points / 4,  // 25% bonus
+        amount if amount >= 200 => base_points / 10, // 10% bonus
+        _ => 0,
+    };
+
+    base_points + bonus_points
+}
+
+// Exporting the candid interface
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stand-ins for the system API, which only exists inside a canister. Each test runs
+    // on its own thread, so starts from empty stable memory and an anonymous caller.
+    pub mod system {
+        use candid::utils::ArgumentDecoder;
+        use candid::{CandidType, Principal};
+        use ic_cdk::api::call::{CallResult, RejectionCode};
+        use serde::Serialize;
+        use std::cell::{Cell, RefCell};
+
+        thread_local! {
+            static CALLER: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+            static NOW: Cell<u64> = const { Cell::new(0) };
+            static REPLIES: RefCell<Vec<(String, Vec<u8>)>> = const { RefCell::new(Vec::new()) };
+        }
+
+        pub fn caller() -> Principal {
+            CALLER.with(Cell::get)
+        }
+
+        pub fn time() -> u64 {
+            NOW.with(Cell::get)
+        }
+
+        pub fn is_controller(principal: &Principal) -> bool {
+            *principal == controller()
+        }
+
+        pub fn set_certified_data(_data: &[u8]) {}
+
+        pub fn controller() -> Principal {
+            Principal::from_slice(&[0xC0])
+        }
+
+        pub fn set_caller(principal: Principal) {
+            CALLER.with(|caller| caller.set(principal));
+        }
+
+        pub fn set_time(now: u64) {
+            NOW.with(|time| time.set(now));
+        }
+
+        pub fn canister_id() -> Principal {
+            Principal::from_slice(&[0xCA])
+        }
+
+        // Answers calls to other canisters with the replies queued for their method, in
+        // order; methods without one are rejected
+        pub async fn call<T, R: for<'a> ArgumentDecoder<'a>>(
+            _canister: Principal,
+            method: &str,
+            _args: T,
+        ) -> CallResult<R> {
+            let reply = REPLIES.with(|replies| {
+                let mut replies = replies.borrow_mut();
+                let position = replies.iter().position(|(queued, _)| queued == method)?;
+                Some(replies.remove(position).1)
+            });
+            match reply {
+                Some(reply) => candid::decode_args(&reply)
+                    .map_err(|err| (RejectionCode::CanisterError, err.to_string())),
+                None => Err((
+                    RejectionCode::DestinationInvalid,
+                    format!("nothing answers {}", method),
+                )),
+            }
+        }
+
+        pub fn reply_to<T: CandidType + Serialize>(method: &str, reply: T) {
+            let reply = candid::encode_one(reply).unwrap();
+            REPLIES.with(|replies| replies.borrow_mut().push((method.to_string(), reply)));
+        }
+    }
+
+    use system::{controller, reply_to, set_caller, set_time};
+
+    // Drives an async endpoint to completion. The system shim answers calls to other
+    // canisters at once, so nothing ever has to wait.
+    fn run<F: std::future::Future>(future: F) -> F::Output {
+        use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+        fn noop_raw_waker() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker {
+                noop_raw_waker()
+            }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+
+        let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+        let mut future = std::pin::pin!(future);
+        match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("the future waited on a call"),
+        }
+    }
+
+    // Layouts the first builds wrote as bare Candid
+    #[derive(candid::CandidType, Serialize)]
+    struct LegacyUser {
+        id: u64,
+        username: String,
+        email: String,
+    }
+
+    #[derive(candid::CandidType, Serialize)]
+    struct LegacyEvent {
+        id: u64,
+        name: String,
+        location: String,
+        date: u64,
+        ticket_price: u64,
+        total_tickets: u64,
+        tickets_sold: u64,
+    }
+
+    // Event layout of schema version 2, before events had a status
+    #[derive(candid::CandidType, Serialize)]
+    struct StatuslessEvent {
+        id: u64,
+        name: String,
+        location: String,
+        date: u64,
+        ticket_price: u64,
+        total_tickets: u64,
+        tickets_sold: u64,
+        pricing_mode: PricingMode,
+        organizer: Option<Principal>,
+        category: Option<String>,
+    }
+
+    #[derive(candid::CandidType, Serialize)]
+    struct LegacyTicket {
+        id: u64,
+        event_id: u64,
+        user_id: u64,
+        purchase_date: u64,
+        seat_number: String,
+        price: u64,
+    }
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte])
+    }
+
+    fn legacy_user(id: u64, username: &str) -> LegacyUser {
+        LegacyUser {
+            id,
+            username: username.to_string(),
+            email: format!("{}@example.com", username),
+        }
+    }
+
+    fn legacy_event(id: u64, total_tickets: u64, tickets_sold: u64) -> LegacyEvent {
+        LegacyEvent {
+            id,
+            name: "Concert".to_string(),
+            location: "Main Hall".to_string(),
+            date: 1_000 * NANOS_PER_DAY,
+            ticket_price: 100,
+            total_tickets,
+            tickets_sold,
+        }
+    }
+
+    fn stored_raw<T: Record>(bytes: Vec<u8>) -> Stored<T> {
+        Stored::from_bytes(Cow::Owned(bytes))
+    }
+
+    fn bare_candid(record: &impl candid::CandidType) -> Vec<u8> {
+        Encode!(record).expect("Cannot encode a legacy record")
+    }
+
+    fn put_raw<T: Record>(storage: &'static RecordStore<T>, id: u64, bytes: Vec<u8>) {
+        storage.with(|storage| storage.borrow_mut().insert(id, stored_raw(bytes)));
+    }
+
+    fn raw_bytes<T: Record>(storage: &'static RecordStore<T>, id: u64) -> Vec<u8> {
+        storage.with(|storage| storage.borrow().get(&id).unwrap().to_bytes().into_owned())
+    }
+
+    #[test]
+    fn stored_records_carry_their_layout_version() {
+        let user = User {
+            id: 7,
+            owner: principal(1),
+            username: "alice".to_string(),
+            email: "alice@example.com".to_string(),
+            referral_code: referral_code_for(7),
+            referred_by: Some(2),
+            email_verified_at: Some(3),
+        };
+
+        let stored = Stored::encode(&user).unwrap();
+        assert_eq!(stored.to_bytes()[..2], [RECORD_MAGIC, User::VERSION]);
+
+        let decoded = stored.decode().unwrap();
+        assert_eq!(decoded.id, 7);
+        assert_eq!(decoded.owner, principal(1));
+        assert_eq!(decoded.username, "alice");
+        assert_eq!(decoded.referred_by, Some(2));
+        assert_eq!(decoded.email_verified_at, Some(3));
+    }
+
+    #[test]
+    fn records_over_their_bound_are_rejected() {
+        let event = Event {
+            name: "x".repeat(Event::MAX_SIZE as usize),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Stored::encode(&event),
+            Err(Error::RecordTooLarge { max_size, .. }) if max_size == Event::MAX_SIZE
+        ));
+    }
+
+    #[test]
+    fn unreadable_records_are_reported_as_corrupted() {
+        let stored = stored_raw::<User>(vec![RECORD_MAGIC, User::VERSION, 1, 2, 3]);
+        assert!(matches!(
+            stored.decode(),
+            Err(Error::CorruptedRecord { record }) if record == User::NAME
+        ));
+    }
+
+    #[test]
+    fn bare_candid_users_decode_without_an_owner() {
+        let stored = stored_raw::<User>(bare_candid(&legacy_user(3, "bob")));
+        let user = stored.decode().unwrap();
+
+        assert_eq!(user.id, 3);
+        assert_eq!(user.owner, Principal::anonymous());
+        assert_eq!(user.email, "bob@example.com");
+        assert_eq!(user.referral_code, referral_code_for(3));
+        assert_eq!(user.referred_by, None);
+        assert_eq!(user.email_verified_at, None);
+    }
+
+    #[test]
+    fn bare_candid_events_decode_as_fixed_price_events_on_sale() {
+        let on_sale = stored_raw::<Event>(bare_candid(&legacy_event(1, 10, 4)))
+            .decode()
+            .unwrap();
+        assert_eq!(on_sale.status, EventStatus::OnSale);
+        assert!(on_sale.pricing_mode == PricingMode::Fixed);
+        assert_eq!(on_sale.tickets_sold, 4);
+        assert_eq!(on_sale.organizer, None);
+
+        let sold_out = stored_raw::<Event>(bare_candid(&legacy_event(2, 10, 10)))
+            .decode()
+            .unwrap();
+        assert_eq!(sold_out.status, EventStatus::SoldOut);
+    }
+
+    #[test]
+    fn events_from_before_statuses_decode_through_their_own_layout() {
+        let event = StatuslessEvent {
+            id: 5,
+            name: "Play".to_string(),
+            location: "Theatre".to_string(),
+            date: 0,
+            ticket_price: 50,
+            total_tickets: 2,
+            tickets_sold: 1,
+            pricing_mode: PricingMode::Dynamic,
+            organizer: Some(principal(4)),
+            category: Some("theatre".to_string()),
+        };
+        let mut bytes = vec![RECORD_MAGIC, 2];
+        bytes.extend(bare_candid(&event));
+
+        let event = stored_raw::<Event>(bytes).decode().unwrap();
+        assert_eq!(event.status, EventStatus::OnSale);
+        assert!(event.pricing_mode == PricingMode::Dynamic);
+        assert_eq!(event.organizer, Some(principal(4)));
+        assert_eq!(event.category.as_deref(), Some("theatre"));
+        assert_eq!(event.venue_id, None);
+    }
+
+    #[test]
+    fn migration_rewrites_legacy_records_and_rebuilds_what_derives_from_them() {
+        let event = legacy_event(2, 10, 1);
+        let date = event.date;
+        put_raw(&USERS_STORAGE, 1, bare_candid(&legacy_user(1, "Carol")));
+        put_raw(&EVENTS_STORAGE, 2, bare_candid(&event));
+        put_raw(
+            &TICKETS_STORAGE,
+            3,
+            bare_candid(&LegacyTicket {
+                id: 3,
+                event_id: 2,
+                user_id: 1,
+                purchase_date: 5,
+                seat_number: "A1".to_string(),
+                price: 100,
+            }),
+        );
+
+        migrate_storage(LEGACY_SCHEMA_VERSION);
+
+        assert_eq!(
+            raw_bytes(&USERS_STORAGE, 1)[..2],
+            [RECORD_MAGIC, User::VERSION]
+        );
+        assert_eq!(
+            raw_bytes(&EVENTS_STORAGE, 2)[..2],
+            [RECORD_MAGIC, Event::VERSION]
+        );
+        assert_eq!(
+            raw_bytes(&TICKETS_STORAGE, 3)[..2],
+            [RECORD_MAGIC, Ticket::VERSION]
+        );
+
+        let user = load(&USERS_STORAGE, 1).unwrap().unwrap();
+        assert_eq!(user.owner, Principal::anonymous());
+        let by_email = find_user_in(&USERS_BY_EMAIL, &email_key("CAROL@example.com")).unwrap();
+        assert_eq!(by_email.map(|user| user.id), Some(1));
+        let by_username = find_user_in(&USERS_BY_USERNAME, &username_key("carol")).unwrap();
+        assert_eq!(by_username.map(|user| user.id), Some(1));
+        assert!(USERS_BY_OWNER.with(|index| index.borrow().is_empty()));
+
+        assert_eq!(load_indexed_tickets(&TICKETS_BY_USER, 1).len(), 1);
+        assert_eq!(load_indexed_tickets(&TICKETS_BY_EVENT, 2).len(), 1);
+        assert!(is_seat_taken(2, "A1"));
+        assert!(!is_seat_taken(2, "A2"));
+        assert!(
+            EVENTS_BY_DATE.with(|index| index.borrow().contains_key(&IndexKey {
+                parent_id: date,
+                record_id: 2,
+            }))
+        );
+        assert_eq!(event_sales(2).unwrap().tickets_sold, 1);
+        assert_eq!(BLOCK_LOG.with(|log| log.borrow().len()), 1);
+    }
+
+    #[test]
+    fn controllers_hand_unowned_accounts_to_their_users() {
+        put_raw(&USERS_STORAGE, 4, bare_candid(&legacy_user(4, "dave")));
+        let owner = principal(9);
+
+        set_caller(owner);
+        assert!(matches!(
+            require_account_owner(4),
+            Err(Error::Unauthorized {
+                required_role: Role::AccountOwner
+            })
+        ));
+        assert!(matches!(
+            assign_owner(4, owner),
+            Err(Error::Unauthorized {
+                required_role: Role::Admin
+            })
+        ));
+
+        set_caller(controller());
+        assert!(matches!(
+            assign_owner(4, Principal::anonymous()),
+            Err(Error::InvalidField { .. })
+        ));
+        assert_eq!(assign_owner(4, owner).unwrap().owner, owner);
+        assert!(matches!(
+            assign_owner(4, principal(10)),
+            Err(Error::InvalidField { .. })
+        ));
+
+        set_caller(owner);
+        assert_eq!(require_account_owner(4).unwrap().id, 4);
+        assert_eq!(get_my_profile().unwrap().id, 4);
+    }
+
+    fn register(owner: Principal, username: &str, referral_code: Option<String>) -> User {
+        set_caller(owner);
+        register_user(RegisterUserPayload {
+            username: username.to_string(),
+            email: format!("{}@example.com", username),
+            referral_code,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn deleting_an_account_removes_everything_kept_under_it() {
+        let referrer = register(principal(1), "frank", None);
+        let user = register(principal(2), "grace", Some(referrer.referral_code.clone()));
+        let other = register(principal(9), "oscar", Some(referrer.referral_code));
+        assert_eq!(
+            get_referral_stats(referrer.id).unwrap().pending_referrals,
+            2
+        );
+
+        // A ticket for an event that has since been removed counts as a past one
+        let ticket = Ticket {
+            id: 50,
+            event_id: 999,
+            user_id: user.id,
+            ..Default::default()
+        };
+        store(&TICKETS_STORAGE, ticket.id, &ticket).unwrap();
+        index_ticket(&ticket);
+        add_loyalty_points(user.id, 10, "Welcome bonus".to_string()).unwrap();
+        let stats = referral_stats_for(&user).unwrap();
+        store(&REFERRAL_STATS_STORAGE, user.id, &stats).unwrap();
+
+        set_caller(principal(2));
+        delete_account(user.id).unwrap();
+
+        assert!(load(&USERS_STORAGE, user.id).unwrap().is_none());
+        assert!(find_user_in(&USERS_BY_OWNER, &principal(2).to_text())
+            .unwrap()
+            .is_none());
+        assert!(load_indexed_tickets(&TICKETS_BY_USER, user.id).is_empty());
+        assert_eq!(load_indexed_tickets(&TICKETS_BY_EVENT, 999).len(), 1);
+        assert!(load(&LOYALTY_STORAGE, user.id).unwrap().is_none());
+        assert!(points_history(user.id, 0, 10).0.is_empty());
+        assert!(load(&REFERRALS_STORAGE, user.id).unwrap().is_none());
+        assert!(load(&REFERRAL_STATS_STORAGE, user.id).unwrap().is_none());
+        assert_eq!(
+            get_referral_stats(referrer.id).unwrap().pending_referrals,
+            1
+        );
+
+        // Referrals made by a deleted account go with it
+        set_caller(principal(1));
+        delete_account(referrer.id).unwrap();
+        assert!(load(&REFERRALS_STORAGE, other.id).unwrap().is_none());
+        assert!(referred_by(referrer.id).is_empty());
+    }
+
+    #[test]
+    fn points_histories_keep_every_entry_and_page_newest_first() {
+        let user = register(principal(3), "heidi", None);
+        for points in 1..=RECENT_POINTS_HISTORY as u64 + 3 {
+            add_loyalty_points(user.id, points, format!("Bonus {}", points)).unwrap();
+        }
+        let loyalty = add_loyalty_points(user.id, 100, "Bonus 100".to_string()).unwrap();
+        assert_eq!(loyalty.points_history.len(), RECENT_POINTS_HISTORY);
+        assert_eq!(loyalty.points_history[0].points, 100);
+
+        let first = get_points_history(user.id, None, Some(6)).unwrap();
+        assert_eq!(first.entries.len(), 6);
+        let rest = get_points_history(user.id, first.next_cursor, Some(6)).unwrap();
+        assert_eq!(rest.entries.len(), 3);
+        assert_eq!(rest.next_cursor, None);
+        assert_eq!(rest.entries[2].description, "Bonus 1");
+
+        set_caller(principal(4));
+        assert!(get_points_history(user.id, None, None).is_err());
+    }
+
+    #[test]
+    fn migration_moves_inline_points_histories_into_their_own_map() {
+        // The first builds never filled in the account's user id
+        let loyalty = LoyaltyPoints {
+            points: 30,
+            points_history: (1..=3)
+                .map(|points| PointsTransaction {
+                    timestamp: points,
+                    points: points as i64 * 10,
+                    description: "Purchase".to_string(),
+                })
+                .collect(),
+            ..Default::default()
+        };
+        store(&LOYALTY_STORAGE, 6, &loyalty).unwrap();
+
+        migrate_storage(9);
+
+        let migrated = load(&LOYALTY_STORAGE, 6).unwrap().unwrap();
+        assert_eq!(migrated.user_id, 6);
+        assert!(migrated.points_history.is_empty());
+        let (entries, next_cursor) = points_history(6, 0, 10);
+        let points: Vec<i64> = entries.iter().map(|entry| entry.points).collect();
+        assert_eq!(points, [30, 20, 10]);
+        assert_eq!(next_cursor, None);
+    }
+
+    fn listed_event(id: u64, date: u64, location: &str) -> Event {
+        let event = Event {
+            id,
+            name: "Concert".to_string(),
+            location: location.to_string(),
+            date,
+            total_tickets: 10,
+            status: EventStatus::OnSale,
+            ..Default::default()
+        };
+        store(&EVENTS_STORAGE, id, &event).unwrap();
+        index_event(&event);
+        event
+    }
+
+    fn search_ids(payload: SearchEventsPayload) -> (Vec<u64>, Option<String>) {
+        let page = search_events(payload).unwrap();
+        let ids = page.events.iter().map(|listing| listing.event.id).collect();
+        (ids, page.next_cursor)
+    }
+
+    #[test]
+    fn searches_walk_the_date_index_either_way() {
+        for (id, date) in [(1, 30), (2, 10), (3, 20), (4, 20)] {
+            listed_event(id, date, "Main Hall");
+        }
+
+        let latest_first = |cursor| SearchEventsPayload {
+            sort: Some(EventSortOrder::DateDescending),
+            date_to: Some(25),
+            cursor,
+            limit: Some(2),
+            ..Default::default()
+        };
+        let (ids, cursor) = search_ids(latest_first(None));
+        assert_eq!(ids, [3, 4]);
+        let (ids, cursor) = search_ids(latest_first(cursor));
+        assert_eq!(ids, [2]);
+        assert_eq!(cursor, None);
+
+        let (ids, _) = search_ids(SearchEventsPayload {
+            location: Some("main".to_string()),
+            sort: Some(EventSortOrder::DateDescending),
+            ..Default::default()
+        });
+        assert_eq!(ids, [1, 3, 4, 2]);
+    }
+
+    #[test]
+    fn searches_stop_at_their_scan_limit() {
+        for id in 1..=MAX_SEARCH_SCAN as u64 + 1 {
+            listed_event(id, id, "Main Hall");
+        }
+        // No event is sold out, so every entry is looked at and none is listed
+        let sold_out = |cursor| SearchEventsPayload {
+            has_availability: Some(false),
+            cursor,
+            ..Default::default()
+        };
+
+        let (ids, cursor) = search_ids(sold_out(None));
+        assert!(ids.is_empty());
+        assert_eq!(cursor.as_deref(), Some("2000-2000"));
+        let (ids, cursor) = search_ids(sold_out(cursor));
+        assert!(ids.is_empty());
+        assert_eq!(cursor, None);
+
+        let page = search_events(SearchEventsPayload {
+            sort: Some(EventSortOrder::Popularity),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(page.events.len(), DEFAULT_PAGE_SIZE as usize);
+        assert!(page.truncated);
+    }
+
+    #[test]
+    fn orders_write_nothing_unless_every_ticket_goes_through() {
+        let user = register(principal(5), "ivan", None);
+        listed_event(100, 10, "Main Hall");
+        let seat = |seat: &str| PurchaseTicketPayload {
+            event_id: 100,
+            user_id: user.id,
+            seat_number: seat.to_string(),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            run(process_order(vec![seat("A1"), seat("A2"), seat("A1")])),
+            Err(Error::SeatTaken { seat }) if seat == "A1"
+        ));
+        assert_eq!(load(&EVENTS_STORAGE, 100).unwrap().unwrap().tickets_sold, 0);
+        assert!(load_indexed_tickets(&TICKETS_BY_EVENT, 100).is_empty());
+        assert!(!is_seat_taken(100, "A2"));
+
+        set_caller(principal(6));
+        assert!(matches!(
+            run(process_order(vec![seat("A1")])),
+            Err(Error::Unauthorized {
+                required_role: Role::AccountOwner
+            })
+        ));
+
+        set_caller(principal(5));
+        let tickets = run(process_order(vec![seat("A1"), seat("A2")])).unwrap();
+        assert_eq!(tickets.len(), 2);
+        assert_eq!(load(&EVENTS_STORAGE, 100).unwrap().unwrap().tickets_sold, 2);
+        assert_eq!(event_sales(100).unwrap().tickets_sold, 2);
+        assert!(is_seat_taken(100, "A2"));
+    }
+
+    #[test]
+    fn referrers_are_rewarded_for_verified_buyers_they_did_not_pay_for() {
+        let referrer = register(principal(7), "judy", None);
+        let buyer = register(principal(8), "ken", Some(referrer.referral_code));
+        listed_event(200, 10, "Main Hall");
+        let seat = |seat: &str| PurchaseTicketPayload {
+            event_id: 200,
+            user_id: buyer.id,
+            seat_number: seat.to_string(),
+            ..Default::default()
+        };
+
+        // Free tickets earn the buyer points but leave the referral pending
+        run(process_order(vec![seat("B1"), seat("B2")])).unwrap();
+        assert_eq!(points_history(buyer.id, 0, 10).0.len(), 2);
+        assert!(load(&LOYALTY_STORAGE, referrer.id).unwrap().is_none());
+        assert_eq!(
+            get_referral_stats(referrer.id).unwrap().pending_referrals,
+            1
+        );
+
+        // Without a notification hook no address can be confirmed, so none has to be
+        let payer = Some(principal(8));
+        assert!(
+            reward_referral_on_first_purchase(buyer.id, Some(principal(7)))
+                .unwrap()
+                .is_none()
+        );
+        assert!(reward_referral_on_first_purchase(buyer.id, payer)
+            .unwrap()
+            .is_some());
+
+        set_caller(controller());
+        set_notification_hook("https://mail.example.com/notify".to_string()).unwrap();
+        assert!(reward_referral_on_first_purchase(buyer.id, payer)
+            .unwrap()
+            .is_none());
+        let mut user = load(&USERS_STORAGE, buyer.id).unwrap().unwrap();
+        user.email_verified_at = Some(1);
+        store(&USERS_STORAGE, buyer.id, &user).unwrap();
+
+        let reward = reward_referral_on_first_purchase(buyer.id, payer).unwrap();
+        save_referral_reward(reward.unwrap());
+        let points = load(&LOYALTY_STORAGE, referrer.id).unwrap().unwrap();
+        assert_eq!(points.points, REFERRAL_REWARD_POINTS);
+        let stats = get_referral_stats(referrer.id).unwrap();
+        assert_eq!(stats.rewarded_referrals, 1);
+        assert_eq!(stats.pending_referrals, 0);
+    }
+
+    // Finds a label among the subtrees at the root of a hash tree, as certificate
+    // lookups do, checking the labels are in order on the way
+    fn lookup_label<'a>(tree: &'a HashTree, label: &[u8]) -> Option<&'a HashTree> {
+        fn flatten<'a>(tree: &'a HashTree, labeled: &mut Vec<(&'a [u8], &'a HashTree)>) {
+            match tree {
+                HashTree::Fork(left, right) => {
+                    flatten(left, labeled);
+                    flatten(right, labeled);
+                }
+                HashTree::Labeled(label, subtree) => labeled.push((label, subtree)),
+                _ => {}
+            }
+        }
+
+        let mut labeled = Vec::new();
+        flatten(tree, &mut labeled);
+        assert!(labeled.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        labeled
+            .into_iter()
+            .find(|(found, _)| *found == label)
+            .map(|(_, subtree)| subtree)
+    }
+
+    #[test]
+    fn tip_witnesses_hold_the_tip_at_the_root_of_the_certified_tree() {
+        keep_response("/events/1".to_string(), &text_response(200, "Concert"));
+        let last_hash = [7; 32];
+        let witness = tip_witness(300, last_hash);
+
+        let certified = HashTree::Fork(
+            Box::new(http_assets_tree(None)),
+            Box::new(tip_tree(300, last_hash)),
+        );
+        assert_eq!(witness.digest(), certified.digest());
+
+        let mut index = Vec::new();
+        candid::Nat::from(300u64).encode(&mut index).unwrap();
+        assert!(matches!(
+            lookup_label(&witness, b"last_block_index"),
+            Some(HashTree::Leaf(leaf)) if *leaf == index
+        ));
+        assert!(matches!(
+            lookup_label(&witness, b"last_block_hash"),
+            Some(HashTree::Leaf(leaf)) if *leaf == last_hash
+        ));
+        assert!(lookup_label(&witness, b"http_assets").is_none());
+    }
+
+    #[test]
+    fn price_breakdowns_itemize_fees_and_either_kind_of_tax() {
+        set_caller(controller());
+        set_service_fee(ServiceFee {
+            flat: 5,
+            basis_points: 1000,
+        })
+        .unwrap();
+        let set_tax = |inclusive| {
+            set_caller(controller());
+            set_tax_rate(TaxRatePayload {
+                jurisdiction: "xx".to_string(),
+                basis_points: 2000,
+                inclusive,
+            })
+            .unwrap();
+        };
+        set_tax(false);
+
+        set_caller(principal(3));
+        let organizer = create_organizer(OrganizerPayload {
+            name: "Hall".to_string(),
+            jurisdiction: Some("XX".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut event = listed_event(100, 10, "Main Hall");
+        event.ticket_price = 100;
+        event.organizer_id = Some(organizer.id);
+        store(&EVENTS_STORAGE, 100, &event).unwrap();
+        let buyer = register(principal(4), "mia", None);
+        let quote = || {
+            set_caller(principal(4));
+            quote_ticket(PurchaseTicketPayload {
+                event_id: 100,
+                user_id: buyer.id,
+                seat_number: "A1".to_string(),
+                ..Default::default()
+            })
+        };
+
+        // Tax is added on top of the price, and fees are not taxed
+        let breakdown = quote().unwrap();
+        assert_eq!((breakdown.base, breakdown.fees), (100, 15));
+        assert_eq!((breakdown.tax, breakdown.tax_inclusive), (20, false));
+        assert_eq!(breakdown.total, 135);
+        assert_eq!(breakdown.ticket_amount(), 100);
+
+        // Inclusive tax is the part of the price that is tax
+        set_tax(true);
+        let breakdown = quote().unwrap();
+        assert_eq!((breakdown.tax, breakdown.tax_inclusive), (17, true));
+        assert_eq!(breakdown.total, 115);
+        assert_eq!(breakdown.ticket_amount(), 100);
+
+        event.ticket_price = u64::MAX;
+        store(&EVENTS_STORAGE, 100, &event).unwrap();
+        assert!(matches!(
+            quote(),
+            Err(Error::InvalidField { field, .. }) if field == "ticket_price"
+        ));
+    }
+
+    #[test]
+    fn tokens_and_tax_rates_are_found_by_their_key() {
+        set_caller(controller());
+        let token = |symbol: &str, decimals| TokenPayload {
+            symbol: symbol.to_string(),
+            ledger: principal(0x1E),
+            decimals,
+            flat_fee: 0,
+            loyalty_rate: 1,
+        };
+        let ckbtc = set_token(token(" ckbtc ", 8)).unwrap();
+        set_token(token("ckETH", 18)).unwrap();
+        assert_eq!(find_token("CKBTC").unwrap().id, ckbtc.id);
+        assert!(find_token("CK").is_none());
+
+        // Prices and balances are kept in the token's smallest unit
+        assert!(matches!(
+            set_token(token("ckBTC", 6)),
+            Err(Error::InvalidField { field, .. }) if field == "decimals"
+        ));
+        let updated = set_token(TokenPayload {
+            flat_fee: 10,
+            ..token("ckBTC", 8)
+        })
+        .unwrap();
+        assert_eq!(
+            (updated.id, find_token("CKBTC").unwrap().flat_fee),
+            (ckbtc.id, 10)
+        );
+
+        set_tax_rate(TaxRatePayload {
+            jurisdiction: "de".to_string(),
+            basis_points: 1900,
+            inclusive: true,
+        })
+        .unwrap();
+        assert_eq!(find_tax_rate("DE").unwrap().basis_points, 1900);
+        remove_tax_rate("DE".to_string()).unwrap();
+        assert!(find_tax_rate("DE").is_none());
+        assert!(matches!(
+            remove_tax_rate("DE".to_string()),
+            Err(Error::TaxRateNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn organizers_are_paid_out_only_what_was_collected_for_them() {
+        set_caller(controller());
+        set_payment_ledger(principal(0x1E)).unwrap();
+        let buyer = register(principal(4), "mia", None);
+        let event = Event {
+            id: 100,
+            name: "Concert".to_string(),
+            date: 10,
+            total_tickets: 10,
+            ticket_price: 100,
+            status: EventStatus::OnSale,
+            organizer: Some(principal(3)),
+            ..Default::default()
+        };
+        store(&EVENTS_STORAGE, 100, &event).unwrap();
+        index_event(&event);
+        let buy = |seat: &str| {
+            set_caller(principal(4));
+            reply_to(
+                "icrc2_transfer_from",
+                Ok::<_, TransferFromError>(candid::Nat::from(1u64)),
+            );
+            run(process_purchase(PurchaseTicketPayload {
+                event_id: 100,
+                user_id: buyer.id,
+                seat_number: seat.to_string(),
+                ..Default::default()
+            }))
+            .unwrap()
+        };
+
+        // Sold before the profile, so paid to the platform
+        let early = buy("A1");
+        set_caller(principal(3));
+        let organizer = create_organizer(OrganizerPayload {
+            name: "Hall".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let ledger = organizer_ledger(organizer.id).unwrap();
+        assert_eq!(ledger.gross_sales, early.price);
+        assert_eq!(ledger.pending_balance, 0);
+
+        let late = buy("A2");
+        let earnings = late.price - platform_fee(&late);
+        assert_eq!(
+            organizer_ledger(organizer.id).unwrap().pending_balance,
+            earnings
+        );
+
+        set_caller(principal(3));
+        complete_event(100).unwrap();
+        let ledger = organizer_ledger(organizer.id).unwrap();
+        assert_eq!(ledger.gross_sales, early.price + late.price);
+        assert_eq!(
+            (ledger.pending_balance, ledger.settled_balance),
+            (0, earnings)
+        );
+
+        // The subaccount must also cover the transfer fee
+        reply_to("icrc1_balance_of", candid::Nat::from(earnings));
+        reply_to("icrc1_fee", candid::Nat::from(10u64));
+        assert!(matches!(
+            run(request_payout(organizer.id, None)),
+            Err(Error::PayoutFailed { .. })
+        ));
+        assert_eq!(
+            organizer_ledger(organizer.id).unwrap().settled_balance,
+            earnings
+        );
+
+        reply_to("icrc1_balance_of", candid::Nat::from(earnings + 10));
+        reply_to("icrc1_fee", candid::Nat::from(10u64));
+        reply_to(
+            "icrc1_transfer",
+            Ok::<_, TransferError>(candid::Nat::from(7u64)),
+        );
+        let payout = run(request_payout(organizer.id, None)).unwrap();
+        assert_eq!(payout.amount, earnings);
+        assert!(matches!(payout.status, PayoutStatus::Completed { .. }));
+        let ledger = organizer_ledger(organizer.id).unwrap();
+        assert_eq!((ledger.settled_balance, ledger.paid_out), (0, earnings));
+    }
+
+    #[test]
+    fn paid_orders_write_nothing_unless_the_payment_is_collected() {
+        let user = register(principal(9), "lena", None);
+        let mut event = listed_event(300, 10, "Main Hall");
+        event.ticket_price = 50;
+        store(&EVENTS_STORAGE, 300, &event).unwrap();
+        let purchase = PurchaseTicketPayload {
+            event_id: 300,
+            user_id: user.id,
+            seat_number: "C1".to_string(),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            run(process_purchase(purchase.clone())),
+            Err(Error::PaymentFailed { reason }) if reason == "no payment ledger is configured"
+        ));
+
+        set_caller(controller());
+        set_payment_ledger(principal(0x1E)).unwrap();
+        set_caller(principal(9));
+        assert!(matches!(
+            run(process_purchase(purchase)),
+            Err(Error::PaymentFailed { .. })
+        ));
+        assert_eq!(load(&EVENTS_STORAGE, 300).unwrap().unwrap().tickets_sold, 0);
+        assert!(!is_seat_taken(300, "C1"));
+        assert!(points_history(user.id, 0, 10).0.is_empty());
+    }
+
+    #[test]
+    fn notification_hooks_must_use_https() {
+        set_caller(controller());
+        assert!(matches!(
+            set_notification_hook("http://localhost:8080/notify".to_string()),
+            Err(Error::InvalidField { field, .. }) if field == "url"
+        ));
+        assert!(notification_hook().unwrap().is_none());
+    }
+
+    #[test]
+    fn legacy_accounts_confirm_their_email_once_assigned_an_owner() {
+        put_raw(&USERS_STORAGE, 4, bare_candid(&legacy_user(4, "erin")));
+        set_caller(controller());
+        set_notification_hook("https://hooks.example.com/notify".to_string()).unwrap();
+        assert!(matches!(
+            require_verified_email(4),
+            Err(Error::EmailNotVerified { user_id: 4 })
+        ));
+
+        let owner = principal(9);
+        set_caller(owner);
+        assert!(matches!(
+            confirm_email(4, "123456".to_string()),
+            Err(Error::Unauthorized {
+                required_role: Role::AccountOwner
+            })
+        ));
+
+        set_caller(controller());
+        assign_owner(4, owner).unwrap();
+
+        // What request_email_verification leaves behind once the hook has taken the code
+        let verification = EmailVerification {
+            user_id: 4,
+            email: "erin@example.com".to_string(),
+            code_hash: verification_hash(4, "123456"),
+            issued_at: 0,
+            expires_at: VERIFICATION_CODE_TTL,
+            attempts: 0,
+        };
+        store(&EMAIL_VERIFICATIONS_STORAGE, 4, &verification).unwrap();
+
+        set_caller(owner);
+        assert!(matches!(
+            confirm_email(4, "654321".to_string()),
+            Err(Error::InvalidVerificationCode)
+        ));
+        assert!(confirm_email(4, "123456".to_string())
+            .unwrap()
+            .email_verified_at
+            .is_some());
+        assert!(require_verified_email(4).is_ok());
+    }
+
+    #[test]
+    fn events_follow_their_lifecycle_and_lock_once_published() {
+        set_caller(principal(3));
+        let event = create_event(CreateEventPayload {
+            name: "Concert".to_string(),
+            location: "Main Hall".to_string(),
+            date: 100,
+            ticket_price: 5,
+            total_tickets: 10,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(event.status, EventStatus::Draft);
+        assert!(matches!(
+            open_sales(event.id),
+            Err(Error::InvalidStatusTransition {
+                from: EventStatus::Draft,
+                to: EventStatus::OnSale
+            })
+        ));
+
+        // Drafts can change anything
+        let edited = update_event(
+            event.id,
+            UpdateEventPayload {
+                name: Some("Gala".to_string()),
+                date: Some(200),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!((edited.name.as_str(), edited.date), ("Gala", 200));
+        update_event_seating(
+            event.id,
+            UpdateEventSeatingPayload {
+                sections: Some(vec![VenueSection {
+                    name: "Stalls".to_string(),
+                    seat_class: SeatClass::Standard,
+                    rows: vec![VenueRow {
+                        name: "A".to_string(),
+                        seats: 6,
+                        ..Default::default()
+                    }],
+                }]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // Once published, only the capacity can change
+        publish_event(event.id).unwrap();
+        let capacity = |total_tickets| {
+            update_event(
+                event.id,
+                UpdateEventPayload {
+                    total_tickets: Some(total_tickets),
+                    ..Default::default()
+                },
+            )
+        };
+        assert!(matches!(
+            update_event(
+                event.id,
+                UpdateEventPayload {
+                    description: Some("Black tie".to_string()),
+                    ..Default::default()
+                },
+            ),
+            Err(Error::FieldLocked { field }) if field == "description"
+        ));
+        assert!(matches!(
+            capacity(5),
+            Err(Error::InvalidField { field, .. }) if field == "total_tickets"
+        ));
+        assert_eq!(capacity(8).unwrap().total_tickets, 8);
+
+        open_sales(event.id).unwrap();
+        let mut sold = load(&EVENTS_STORAGE, event.id).unwrap().unwrap();
+        sold.tickets_sold = 7;
+        store(&EVENTS_STORAGE, event.id, &sold).unwrap();
+        assert!(matches!(
+            capacity(6),
+            Err(Error::InvalidField { field, .. }) if field == "total_tickets"
+        ));
+        assert_eq!(capacity(7).unwrap().status, EventStatus::SoldOut);
+        assert_eq!(capacity(9).unwrap().status, EventStatus::OnSale);
+
+        cancel_event(event.id).unwrap();
+        assert!(matches!(
+            capacity(10),
+            Err(Error::InvalidEventStatus {
+                status: EventStatus::Cancelled,
+                ..
+            })
+        ));
+        assert!(matches!(
+            publish_event(event.id),
+            Err(Error::InvalidStatusTransition { .. })
+        ));
+        assert_eq!(
+            archive_event(event.id).unwrap().status,
+            EventStatus::Archived
+        );
+    }
+
+    #[test]
+    fn zones_sell_general_admission_and_only_open_reserved_seats() {
+        let user = register(principal(6), "noor", None);
+        listed_event(400, 10, "Arena");
+        set_caller(controller());
+        let row = |seats| VenueRow {
+            name: "A".to_string(),
+            seats,
+            ..Default::default()
+        };
+        let section = |name: &str, seats| VenueSection {
+            name: name.to_string(),
+            seat_class: SeatClass::Standard,
+            rows: vec![row(seats)],
+        };
+        let block = |seats: &[&str]| {
+            update_event_seating(
+                400,
+                UpdateEventSeatingPayload {
+                    blocked_seats: Some(seats.iter().map(|seat| seat.to_string()).collect()),
+                    ..Default::default()
+                },
+            )
+        };
+        update_event_seating(
+            400,
+            UpdateEventSeatingPayload {
+                sections: Some(vec![section("Stalls", 4), section("Balcony", 3)]),
+                blocked_seats: Some(vec!["Stalls-A-1".to_string()]),
+            },
+        )
+        .unwrap();
+        let zones = set_event_zones(
+            400,
+            vec![
+                EventZonePayload {
+                    name: "Floor".to_string(),
+                    kind: ZoneKind::GeneralAdmission,
+                    capacity: Some(5),
+                    sections: Vec::new(),
+                },
+                EventZonePayload {
+                    name: "Seats".to_string(),
+                    kind: ZoneKind::Reserved,
+                    capacity: None,
+                    sections: vec!["Stalls".to_string()],
+                },
+            ],
+        )
+        .unwrap();
+        let capacities: Vec<u64> = zones.zones.iter().map(|zone| zone.capacity).collect();
+        assert_eq!(capacities, [5, 3]);
+        assert_eq!(
+            load(&EVENTS_STORAGE, 400).unwrap().unwrap().total_tickets,
+            8
+        );
+
+        set_caller(principal(6));
+        let buy = |seat: &str, zone: Option<&str>| {
+            run(process_purchase(PurchaseTicketPayload {
+                event_id: 400,
+                user_id: user.id,
+                seat_number: seat.to_string(),
+                zone: zone.map(str::to_string),
+                ..Default::default()
+            }))
+        };
+        assert_eq!(
+            buy("", Some("Floor")).unwrap().zone.as_deref(),
+            Some("Floor")
+        );
+        assert!(matches!(
+            buy("Stalls-A-2", Some("Floor")),
+            Err(Error::InvalidField { field, .. }) if field == "seat_number"
+        ));
+        assert_eq!(
+            buy("Stalls-A-2", None).unwrap().zone.as_deref(),
+            Some("Seats")
+        );
+        assert!(matches!(
+            buy("Stalls-A-1", None),
+            Err(Error::SeatNotForSale { .. })
+        ));
+        assert!(matches!(
+            buy("Balcony-A-1", None),
+            Err(Error::MissingField { field }) if field == "zone"
+        ));
+
+        // Blocking and releasing seats resizes the reserved zone
+        set_caller(controller());
+        assert_eq!(block(&[]).unwrap().blocked_seats.len(), 0);
+        assert_eq!(get_event_zones(400).unwrap().zones[1].capacity, 4);
+        assert_eq!(
+            load(&EVENTS_STORAGE, 400).unwrap().unwrap().total_tickets,
+            9
+        );
+        block(&["Stalls-A-3", "Stalls-A-4"]).unwrap();
+        assert_eq!(get_event_zones(400).unwrap().zones[1].capacity, 2);
+        assert!(matches!(
+            block(&["Stalls-A-1", "Stalls-A-2", "Stalls-A-3", "Stalls-A-4"]),
+            Err(Error::InvalidField { field, .. }) if field == "blocked_seats"
+        ));
+    }
+
+    #[test]
+    fn the_audit_log_pages_through_filtered_calls_newest_first() {
+        set_caller(principal(3));
+        let event = create_event(CreateEventPayload {
+            name: "Concert".to_string(),
+            location: "Main Hall".to_string(),
+            date: 100,
+            ticket_price: 5,
+            total_tickets: 10,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(publish_event(event.id + 1).is_err());
+        publish_event(event.id).unwrap();
+        assert!(matches!(
+            list_audit_log(AuditLogQuery::default()),
+            Err(Error::Unauthorized {
+                required_role: Role::Admin
+            })
+        ));
+
+        set_caller(controller());
+        let operations = |query| {
+            let page = list_audit_log(query).unwrap();
+            let operations: Vec<String> = page
+                .entries
+                .iter()
+                .map(|entry| entry.operation.clone())
+                .collect();
+            (operations, page.next_cursor)
+        };
+        assert_eq!(
+            operations(AuditLogQuery::default()),
+            (
+                vec![
+                    "publish_event".to_string(),
+                    "publish_event".to_string(),
+                    "create_event".to_string()
+                ],
+                None
+            )
+        );
+        assert_eq!(
+            operations(AuditLogQuery {
+                target_id: Some(event.id),
+                ..Default::default()
+            })
+            .0,
+            ["publish_event", "create_event"]
+        );
+        assert!(operations(AuditLogQuery {
+            actor: Some(controller()),
+            ..Default::default()
+        })
+        .0
+        .is_empty());
+
+        let publishes = |cursor| {
+            list_audit_log(AuditLogQuery {
+                actor: Some(principal(3)),
+                entity: Some(AuditEntity::Event),
+                operation: Some(" publish_event ".to_string()),
+                cursor,
+                limit: Some(1),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let first = publishes(None);
+        assert!(matches!(first.entries[0].outcome, AuditOutcome::Succeeded));
+        assert_eq!(first.next_cursor, Some(2));
+        let second = publishes(first.next_cursor);
+        assert!(matches!(
+            second.entries[0].outcome,
+            AuditOutcome::Failed {
+                error: Error::EventNotFound { .. }
+            }
+        ));
+        assert_eq!(second.next_cursor, None);
+        assert_eq!(second.unrecorded_calls, 0);
+
+        // A rare operation is reached across pages that each scan a bounded stretch
+        for _ in 0..MAX_AUDIT_SCAN {
+            record_audit("get_event", AuditEntity::Event, &[], &Ok(()));
+        }
+        let created = |cursor| {
+            list_audit_log(AuditLogQuery {
+                operation: Some("create_event".to_string()),
+                cursor,
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let cut_short = created(None);
+        assert!(cut_short.entries.is_empty());
+        assert_eq!(cut_short.next_cursor, Some(3));
+        let found = created(cut_short.next_cursor);
+        assert_eq!(found.entries.len(), 1);
+        assert_eq!(found.next_cursor, None);
+    }
+
+    #[test]
+    fn endpoints_fail_with_structured_errors_that_old_clients_can_translate() {
+        let user = register(principal(2), "hana", None);
+        listed_event(50, 10, "Main Hall");
+        let buy = |seat: &str| {
+            run(process_purchase(PurchaseTicketPayload {
+                event_id: 50,
+                user_id: user.id,
+                seat_number: seat.to_string(),
+                ..Default::default()
+            }))
+        };
+        buy("A1").unwrap();
+        assert!(matches!(buy("A1"), Err(Error::SeatTaken { seat }) if seat == "A1"));
+        assert!(matches!(
+            get_user(999),
+            Err(Error::UserNotFound { user_id: 999 })
+        ));
+        assert!(matches!(
+            redeem_points(user.id, 1_000),
+            Err(Error::InsufficientPoints { need: 1_000, .. })
+        ));
+        set_caller(principal(5));
+        assert!(matches!(
+            redeem_points(user.id, 1),
+            Err(Error::Unauthorized {
+                required_role: Role::AccountOwner
+            })
+        ));
+
+        assert_eq!(api_version(), API_VERSION);
+        assert!(matches!(
+            to_legacy_message(Error::EventNotFound { event_id: 7 }),
+            Message::NotFound(text) if text == "Event 7 not found"
+        ));
+        assert!(matches!(
+            to_legacy_message(Error::SoldOut { event_id: 7 }),
+            Message::InsufficientTickets(text) if text == "No tickets available for event 7"
+        ));
+        assert!(matches!(
+            to_legacy_message(missing_field("name")),
+            Message::InvalidPayload(text) if text == "Missing required field name"
+        ));
+        assert!(matches!(
+            to_legacy_message(Error::SeatTaken {
+                seat: "A1".to_string()
+            }),
+            Message::Error(text) if text == "Seat A1 is already taken"
+        ));
+    }
+
+    #[test]
+    fn ticket_lists_follow_purchases_and_transfers() {
+        let ana = register(principal(2), "ana", None);
+        let ben = register(principal(3), "ben", None);
+        listed_event(60, 10, "Main Hall");
+        listed_event(61, 20, "Main Hall");
+        let buy = |owner, user_id, event_id, seat: &str| {
+            set_caller(owner);
+            run(process_purchase(PurchaseTicketPayload {
+                event_id,
+                user_id,
+                seat_number: seat.to_string(),
+                ..Default::default()
+            }))
+            .unwrap()
+        };
+        let first = buy(principal(2), ana.id, 60, "A1");
+        let second = buy(principal(2), ana.id, 61, "A1");
+        let third = buy(principal(3), ben.id, 60, "A2");
+        let ids = |tickets: Vec<Ticket>| -> Vec<u64> {
+            tickets.into_iter().map(|ticket| ticket.id).collect()
+        };
+        assert_eq!(ids(list_tickets_for_user(ana.id)), [first.id, second.id]);
+        assert_eq!(ids(list_tickets_for_event(60)), [first.id, third.id]);
+        assert_eq!(ids(list_tickets_for_event(61)), [second.id]);
+
+        set_caller(principal(2));
+        transfer_ticket(first.id, ben.id).unwrap();
+        assert_eq!(ids(list_tickets_for_user(ana.id)), [second.id]);
+        assert_eq!(ids(list_tickets_for_user(ben.id)), [first.id, third.id]);
+        assert_eq!(ids(list_tickets_for_event(60)), [first.id, third.id]);
+        assert!(list_tickets_for_user(999).is_empty());
+    }
+
+    #[test]
+    fn text_searches_rank_listed_events_by_where_their_words_match() {
+        set_caller(principal(3));
+        let create = |name: &str, location: &str, date, description: &str| {
+            create_event(CreateEventPayload {
+                name: name.to_string(),
+                location: location.to_string(),
+                date,
+                ticket_price: 5,
+                total_tickets: 10,
+                description: Some(description.to_string()),
+                ..Default::default()
+            })
+            .unwrap()
+            .id
+        };
+        let night = create("Jazz Night", "Berlin", 10, "");
+        let rock = create("Rock Show", "Berlin", 30, "With a jazz support act");
+        let brunch = create("Jazz Brunch", "Munich", 20, "");
+        create("Jazz Rehearsal", "Berlin", 40, "");
+        let acoustic = create("Folk Evening", "Berlin", 50, "Jazz standards");
+        update_event(
+            acoustic,
+            UpdateEventPayload {
+                description: Some("Acoustic sets".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        for event_id in [night, rock, brunch, acoustic] {
+            publish_event(event_id).unwrap();
+        }
+
+        let found = |query: &str| -> Vec<u64> {
+            search_events_text(query.to_string(), 10)
+                .into_iter()
+                .map(|listing| listing.event.id)
+                .collect()
+        };
+        // Drafts stay out, and edits replace the words an event was indexed under
+        assert_eq!(found("JAZZ berlin"), [night, rock]);
+        assert_eq!(found("jaz"), [night, brunch, rock]);
+        assert_eq!(found("acoustic"), [acoustic]);
+        assert!(found("jazz munich berlin").is_empty());
+        assert!(found(" , ").is_empty());
+        assert!(found("rehearsal").is_empty());
+        assert_eq!(search_events_text("jazz".to_string(), 1).len(), 1);
+    }
+
+    #[test]
+    fn event_details_are_validated_kept_and_searchable() {
+        set_caller(principal(3));
+        let payload = CreateEventPayload {
+            name: "Late Set".to_string(),
+            location: "Main Hall".to_string(),
+            date: 100,
+            ticket_price: 5,
+            total_tickets: 10,
+            category: Some(" Music ".to_string()),
+            description: Some("  Three bands  ".to_string()),
+            tags: Some(vec![
+                " Jazz ".to_string(),
+                "jazz".to_string(),
+                String::new(),
+            ]),
+            media_urls: Some(vec![
+                "https://cdn.example.com/a.png".to_string(),
+                " ".to_string(),
+            ]),
+            organizer_contact: Some(" box office ".to_string()),
+            doors_open: Some(90),
+            end_time: Some(200),
+            timezone: Some("Europe/Berlin".to_string()),
+            minimum_age: Some(18),
+            ..Default::default()
+        };
+        let rejected = |payload: CreateEventPayload| match create_event(payload) {
+            Err(Error::InvalidField { field, .. }) => field,
+            other => panic!(
+                "expected an invalid field, got {:?}",
+                other.map(|event| event.id)
+            ),
+        };
+        for (field, broken) in [
+            (
+                "media_urls",
+                CreateEventPayload {
+                    media_urls: Some(vec!["http://cdn.example.com/a.png".to_string()]),
+                    ..payload.clone()
+                },
+            ),
+            (
+                "timezone",
+                CreateEventPayload {
+                    timezone: Some("Berlin time".to_string()),
+                    ..payload.clone()
+                },
+            ),
+            (
+                "minimum_age",
+                CreateEventPayload {
+                    minimum_age: Some(MAX_MINIMUM_AGE + 1),
+                    ..payload.clone()
+                },
+            ),
+            (
+                "doors_open",
+                CreateEventPayload {
+                    doors_open: Some(101),
+                    ..payload.clone()
+                },
+            ),
+            (
+                "end_time",
+                CreateEventPayload {
+                    end_time: Some(100),
+                    ..payload.clone()
+                },
+            ),
+        ] {
+            assert_eq!(rejected(broken), field);
+        }
+
+        let event = create_event(payload).unwrap();
+        assert_eq!(event.category.as_deref(), Some("Music"));
+        let details = get_event_details(event.id).unwrap();
+        assert_eq!(details.description, "Three bands");
+        assert_eq!(details.tags, ["jazz"]);
+        assert_eq!(details.media_urls, ["https://cdn.example.com/a.png"]);
+        assert_eq!(details.organizer_contact.as_deref(), Some("box office"));
+        assert_eq!(details.timezone.as_deref(), Some("Europe/Berlin"));
+        assert_eq!(details.minimum_age, Some(18));
+
+        publish_event(event.id).unwrap();
+        postpone_event(event.id, 1_100).unwrap();
+        let details = get_event_details(event.id).unwrap();
+        assert_eq!(
+            (details.doors_open, details.end_time),
+            (Some(1_090), Some(1_200))
+        );
+
+        let found = |tag: &str, category: &str, audience_age| {
+            search_ids(SearchEventsPayload {
+                tag: Some(tag.to_string()),
+                category: Some(category.to_string()),
+                audience_age,
+                ..Default::default()
+            })
+            .0
+        };
+        assert_eq!(found("JAZZ", "music", Some(18)), [event.id]);
+        assert_eq!(found("jazz", "music", None), [event.id]);
+        assert!(found("jazz", "music", Some(17)).is_empty());
+        assert!(found("rock", "music", None).is_empty());
+        assert!(found("jazz", "theatre", None).is_empty());
+    }
+
+    #[test]
+    fn events_at_a_venue_start_from_their_own_copy_of_its_seat_map() {
+        set_caller(principal(3));
+        let stalls = |seats, accessible: u32| VenueSection {
+            name: "Stalls".to_string(),
+            seat_class: SeatClass::Standard,
+            rows: vec![VenueRow {
+                name: "A".to_string(),
+                seats,
+                accessible_seats: vec![AccessibleSeat {
+                    number: accessible,
+                    features: vec![AccessibilityFeature::Wheelchair],
+                }],
+            }],
+        };
+        let venue = |capacity, section| CreateVenuePayload {
+            name: "Hall".to_string(),
+            address: "1 Main St".to_string(),
+            capacity,
+            sections: vec![section],
+            ..Default::default()
+        };
+        assert!(matches!(
+            create_venue(venue(3, stalls(4, 1))),
+            Err(Error::InvalidField { field, .. }) if field == "capacity"
+        ));
+        assert!(matches!(
+            create_venue(venue(100, stalls(4, 5))),
+            Err(Error::UnknownSeat { seat }) if seat == "Stalls-A-5"
+        ));
+        let hall = create_venue(venue(100, stalls(4, 1))).unwrap();
+
+        let at_hall = |total_tickets| CreateEventPayload {
+            name: "Concert".to_string(),
+            date: 100,
+            ticket_price: 5,
+            total_tickets,
+            venue_id: Some(hall.id),
+            ..Default::default()
+        };
+        assert!(matches!(
+            create_event(at_hall(101)),
+            Err(Error::InvalidField { field, .. }) if field == "total_tickets"
+        ));
+        let event = create_event(at_hall(0)).unwrap();
+        assert_eq!(event.location, "Hall, 1 Main St");
+        assert_eq!(event.total_tickets, 100);
+        let seating = get_event_seating(event.id).unwrap();
+        assert_eq!(seating.venue_id, Some(hall.id));
+        assert_eq!(seating.sections[0].rows[0].seats, 4);
+
+        // The event's copy and the venue change independently
+        update_event_seating(
+            event.id,
+            UpdateEventSeatingPayload {
+                blocked_seats: Some(vec!["Stalls-A-2".to_string()]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        set_caller(principal(4));
+        assert!(matches!(
+            update_venue(hall.id, venue(100, stalls(6, 1))),
+            Err(Error::Unauthorized {
+                required_role: Role::VenueOwner
+            })
+        ));
+        set_caller(principal(3));
+        update_venue(hall.id, venue(100, stalls(6, 1))).unwrap();
+        assert_eq!(get_venue(hall.id).unwrap().sections[0].rows[0].seats, 6);
+        let seating = get_event_seating(event.id).unwrap();
+        assert_eq!(seating.sections[0].rows[0].seats, 4);
+        assert_eq!(seating.blocked_seats, ["Stalls-A-2"]);
+        assert_eq!(list_venues().len(), 1);
+    }
+
+    #[test]
+    fn schedules_step_by_day_or_week_and_leave_out_their_exceptions() {
+        // Day 4 after the epoch, 5 January 1970, was a Monday
+        let day = |n: u64| n * NANOS_PER_DAY;
+        let days = |dates: Vec<u64>| -> Vec<u64> {
+            dates.into_iter().map(|date| date / NANOS_PER_DAY).collect()
+        };
+        let schedule = |recurrence, until: Option<u64>, count, exceptions: Vec<u64>| Schedule {
+            recurrence,
+            until: until.map(day),
+            count,
+            exceptions: exceptions.into_iter().map(day).collect(),
+        };
+        let dates = |start, schedule: Schedule| schedule_dates(day(start), &schedule);
+
+        let fortnightly = Recurrence::Weekly {
+            interval: 2,
+            weekdays: vec![Weekday::Wednesday, Weekday::Monday],
+        };
+        assert_eq!(
+            days(dates(4, schedule(fortnightly.clone(), None, Some(5), vec![])).unwrap()),
+            [4, 6, 18, 20, 32]
+        );
+        // Exceptions apply after the count, so they shorten the run
+        assert_eq!(
+            days(dates(4, schedule(fortnightly, None, Some(5), vec![18])).unwrap()),
+            [4, 6, 20, 32]
+        );
+        let weekly = Recurrence::Weekly {
+            interval: 1,
+            weekdays: Vec::new(),
+        };
+        assert_eq!(
+            days(dates(4, schedule(weekly, Some(25), None, vec![])).unwrap()),
+            [4, 11, 18, 25]
+        );
+        assert_eq!(
+            days(
+                dates(
+                    1,
+                    schedule(Recurrence::Daily { interval: 3 }, None, Some(3), vec![])
+                )
+                .unwrap()
+            ),
+            [1, 4, 7]
+        );
+        let custom = Recurrence::Custom {
+            dates: vec![day(9), day(3), day(3)],
+        };
+        assert_eq!(
+            days(dates(3, schedule(custom.clone(), None, None, vec![])).unwrap()),
+            [3, 9]
+        );
+        assert_eq!(
+            days(dates(3, schedule(custom.clone(), Some(5), None, vec![])).unwrap()),
+            [3]
+        );
+
+        for broken in [
+            schedule(custom, None, None, vec![3, 9]),
+            schedule(Recurrence::Daily { interval: 1 }, None, None, vec![]),
+            schedule(Recurrence::Daily { interval: 0 }, None, Some(2), vec![]),
+            schedule(Recurrence::Daily { interval: 1 }, None, Some(0), vec![]),
+            schedule(Recurrence::Daily { interval: 1 }, Some(2), None, vec![]),
+            schedule(
+                Recurrence::Daily { interval: 1 },
+                Some(4 + MAX_OCCURRENCES as u64),
+                None,
+                vec![],
+            ),
+        ] {
+            assert!(matches!(
+                dates(4, broken),
+                Err(Error::InvalidField { field, .. }) if field == "schedule"
+            ));
+        }
+
+        set_caller(principal(3));
+        let series = create_event_series(CreateEventSeriesPayload {
+            event: CreateEventPayload {
+                name: "Yoga".to_string(),
+                location: "Studio".to_string(),
+                date: day(4),
+                ticket_price: 5,
+                total_tickets: 12,
+                doors_open: Some(day(4) - 60),
+                ..Default::default()
+            },
+            schedule: schedule(
+                Recurrence::Weekly {
+                    interval: 1,
+                    weekdays: vec![Weekday::Monday],
+                },
+                None,
+                Some(3),
+                vec![11],
+            ),
+        })
+        .unwrap();
+        assert_eq!(series.occurrences, 2);
+        let occurrences = list_series_occurrences(series.id).unwrap();
+        let occurrence_days: Vec<u64> = occurrences
+            .iter()
+            .map(|occurrence| occurrence.date / NANOS_PER_DAY)
+            .collect();
+        assert_eq!(occurrence_days, [4, 18]);
+        assert!(occurrences
+            .iter()
+            .all(|occurrence| occurrence.available_tickets == 12));
+        let last = occurrences[1].event_id;
+        assert_eq!(
+            load(&EVENTS_STORAGE, last).unwrap().unwrap().series_id,
+            Some(series.id)
+        );
+        assert_eq!(
+            get_event_details(last).unwrap().doors_open,
+            Some(day(18) - 60)
+        );
+        assert!(matches!(
+            list_series_occurrences(series.id + 100),
+            Err(Error::SeriesNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn sales_reports_add_up_each_purchase_as_it_happens() {
+        set_caller(controller());
+        set_payment_ledger(principal(0x1E)).unwrap();
+        let buyer = register(principal(2), "ivy", None);
+        let mut event = listed_event(70, 10, "Main Hall");
+        event.ticket_price = 100;
+        event.organizer = Some(principal(3));
+        store(&EVENTS_STORAGE, 70, &event).unwrap();
+        set_caller(principal(3));
+        let row = |name: &str, seat_class| VenueSection {
+            name: name.to_string(),
+            seat_class,
+            rows: vec![VenueRow {
+                name: "A".to_string(),
+                seats: 2,
+                ..Default::default()
+            }],
+        };
+        update_event_seating(
+            70,
+            UpdateEventSeatingPayload {
+                sections: Some(vec![
+                    row("Box", SeatClass::Vip),
+                    row("Stalls", SeatClass::Standard),
+                ]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let buy = |at: u64, seat: &str| {
+            set_time(at);
+            set_caller(principal(2));
+            reply_to(
+                "icrc2_transfer_from",
+                Ok::<_, TransferFromError>(candid::Nat::from(1u64)),
+            );
+            run(process_purchase(PurchaseTicketPayload {
+                event_id: 70,
+                user_id: buyer.id,
+                seat_number: seat.to_string(),
+                ..Default::default()
+            }))
+            .unwrap()
+        };
+        let day = 3 * NANOS_PER_DAY;
+        let tickets = [
+            buy(day + NANOS_PER_HOUR + 5, "Box-A-1"),
+            buy(day + 2 * NANOS_PER_HOUR - 1, "Stalls-A-1"),
+            buy(day + 3 * NANOS_PER_HOUR, "Stalls-A-2"),
+        ];
+        set_caller(principal(3));
+        check_in_ticket(tickets[0].id).unwrap();
+
+        let report = get_sales_report(70, SalesInterval::Hour).unwrap();
+        let buckets: Vec<(u64, u64)> = report
+            .buckets
+            .iter()
+            .map(|bucket| (bucket.start, bucket.tickets))
+            .collect();
+        assert_eq!(
+            buckets,
+            [(day + NANOS_PER_HOUR, 2), (day + 3 * NANOS_PER_HOUR, 1)]
+        );
+        let daily = get_sales_report(70, SalesInterval::Day).unwrap();
+        assert_eq!(daily.buckets.len(), 1);
+        assert_eq!((daily.buckets[0].start, daily.buckets[0].tickets), (day, 3));
+
+        assert_eq!(report.sales.tickets_sold, 3);
+        assert_eq!(report.check_in_rate, 3_333);
+        assert_eq!(report.refund_rate, 0);
+        assert_eq!(
+            report
+                .sales
+                .buyer_tiers
+                .iter()
+                .map(|tier| tier.tickets)
+                .sum::<u64>(),
+            3
+        );
+        let currency = &report.sales.currencies[0];
+        let revenue: u64 = tickets.iter().map(|ticket| ticket.price).sum();
+        assert_eq!((currency.token.clone(), currency.revenue), (None, revenue));
+        let classes: Vec<(Option<SeatClass>, u64)> = currency
+            .classes
+            .iter()
+            .map(|class| (class.seat_class, class.tickets))
+            .collect();
+        assert_eq!(
+            classes,
+            [(Some(SeatClass::Vip), 1), (Some(SeatClass::Standard), 2)]
+        );
+        assert_eq!(report.averages[0].average_price, revenue / 3);
+
+        set_caller(principal(5));
+        assert!(matches!(
+            get_sales_report(70, SalesInterval::Day),
+            Err(Error::Unauthorized {
+                required_role: Role::Organizer
+            })
+        ));
+    }
+
+    #[test]
+    fn exports_page_through_an_events_tickets_in_chunks() {
+        let user = register(principal(2), "jo", None);
+        for event_id in [80, 81, 82] {
+            listed_event(event_id, 10, "Main Hall");
+        }
+        let buy = |event_id, seat: &str| {
+            set_caller(principal(2));
+            run(process_purchase(PurchaseTicketPayload {
+                event_id,
+                user_id: user.id,
+                seat_number: seat.to_string(),
+                ..Default::default()
+            }))
+            .unwrap()
+            .id
+        };
+        let first = buy(80, "A1");
+        buy(81, "A1");
+        let second = buy(80, "B,\"2\"");
+        let third = buy(80, "A3");
+
+        set_caller(controller());
+        let attendees = |event_id, cursor, limit| {
+            export_attendees(
+                event_id,
+                ExportOptions {
+                    format: ExportFormat::Csv,
+                    redact: Some(vec![ExportField::Email]),
+                    cursor,
+                    limit,
+                },
+            )
+            .unwrap()
+        };
+        let header = format!("{}\n", ATTENDEE_COLUMNS.join(","));
+        let row = |ticket_id: u64, seat: &str| format!("{},jo,,{},,0,0,false,\n", ticket_id, seat);
+
+        let empty = attendees(82, None, None);
+        assert_eq!((empty.data, empty.next_cursor), (header.clone(), None));
+        let chunk = attendees(80, None, Some(2));
+        assert_eq!(
+            chunk.data,
+            header + &row(first, "A1") + &row(second, "\"B,\"\"2\"\"\"")
+        );
+        assert_eq!(chunk.next_cursor, Some(third));
+        let last = attendees(80, chunk.next_cursor, Some(2));
+        assert_eq!((last.data, last.next_cursor), (row(third, "A3"), None));
+        assert_eq!(attendees(80, None, Some(3)).next_cursor, None);
+        assert_eq!(attendees(80, None, Some(0)).next_cursor, Some(second));
+
+        let sales = export_sales(
+            80,
+            ExportOptions {
+                format: ExportFormat::Json,
+                redact: None,
+                cursor: Some(second),
+                limit: None,
+            },
+        )
+        .unwrap();
+        let rows: Vec<serde_json::Value> = serde_json::from_str(&sales.data).unwrap();
+        let ids: Vec<u64> = rows
+            .iter()
+            .map(|row| row["ticket_id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(ids, [second, third]);
+        assert_eq!(rows[0]["username"], "jo");
+        assert_eq!(sales.next_cursor, None);
+        let none = export_sales(
+            82,
+            ExportOptions {
+                format: ExportFormat::Json,
+                redact: None,
+                cursor: None,
+                limit: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(none.data, "[]");
+
+        set_caller(principal(2));
+        assert!(matches!(
+            export_attendees(
+                80,
+                ExportOptions {
+                    format: ExportFormat::Json,
+                    redact: None,
+                    cursor: None,
+                    limit: None,
+                },
+            ),
+            Err(Error::Unauthorized {
+                required_role: Role::Organizer
+            })
+        ));
+    }
+
+    #[test]
+    fn imports_write_every_row_or_report_each_invalid_one() {
+        let concert = |name: &str, location: &str| CreateEventPayload {
+            name: name.to_string(),
+            location: location.to_string(),
+            date: 100,
+            ticket_price: 5,
+            total_tickets: 10,
+            ..Default::default()
+        };
+        let hall = |capacity| CreateVenuePayload {
+            name: "Hall".to_string(),
+            address: "1 Main St".to_string(),
+            capacity,
+            ..Default::default()
+        };
+        let at = |venue_row, event: CreateEventPayload| ImportEventRow {
+            event,
+            venue_row: Some(venue_row),
+            seating: None,
+        };
+
+        set_caller(principal(7));
+        assert!(matches!(
+            import_catalog(ImportPayload::default()),
+            Err(Error::Unauthorized {
+                required_role: Role::Organizer
+            })
+        ));
+        set_caller(controller());
+        assert!(matches!(
+            import_catalog(ImportPayload::default()),
+            Err(Error::MissingField { .. })
+        ));
+
+        let rejected = import_catalog(ImportPayload {
+            venues: vec![hall(100), hall(0)],
+            events: vec![
+                at(0, concert("Opening", "")),
+                at(1, concert("Closing", "")),
+                ImportEventRow {
+                    event: concert("", "Park"),
+                    ..Default::default()
+                },
+                at(5, concert("Encore", "")),
+            ],
+        });
+        let Err(Error::ImportRejected { errors }) = rejected else {
+            panic!("the import should be rejected");
+        };
+        let rows: Vec<(String, u32)> = errors
+            .iter()
+            .map(|error| (format!("{:?}", error.table), error.row))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                ("Venues".to_string(), 1),
+                ("Events".to_string(), 1),
+                ("Events".to_string(), 2),
+                ("Events".to_string(), 3)
+            ]
+        );
+        assert!(list_venues().is_empty());
+        assert!(list_all_events().is_empty());
+
+        let document = r#"{
+            "venues": [{
+                "name": "Hall",
+                "address": "1 Main St",
+                "capacity": 100,
+                "sections": [{
+                    "name": "Stalls",
+                    "seat_class": "Standard",
+                    "rows": [{"name": "A", "seats": 4, "accessible_seats": []}]
+                }]
+            }],
+            "events": [
+                {
+                    "event": {"name": "Opening", "location": "", "date": 100,
+                              "ticket_price": 5, "total_tickets": 0},
+                    "venue_row": 0,
+                    "seating": {"blocked_seats": ["Stalls-A-1"]}
+                },
+                {
+                    "event": {"name": "Picnic", "location": "Park", "date": 200,
+                              "ticket_price": 5, "total_tickets": 50}
+                }
+            ]
+        }"#;
+        let summary = import_catalog_json(document.to_string()).unwrap();
+        assert_eq!((summary.venue_ids.len(), summary.event_ids.len()), (1, 2));
+        let opening = load(&EVENTS_STORAGE, summary.event_ids[0])
+            .unwrap()
+            .unwrap();
+        assert_eq!(opening.venue_id, Some(summary.venue_ids[0]));
+        assert_eq!(opening.total_tickets, 100);
+        let seating = get_event_seating(opening.id).unwrap();
+        assert_eq!(seating.venue_id, Some(summary.venue_ids[0]));
+        assert_eq!(seating.blocked_seats, ["Stalls-A-1"]);
+        let picnic = load(&EVENTS_STORAGE, summary.event_ids[1])
+            .unwrap()
+            .unwrap();
+        assert_eq!((picnic.location.as_str(), picnic.venue_id), ("Park", None));
+
+        assert!(matches!(
+            import_catalog_json("{\"venues\": 3}".to_string()),
+            Err(Error::InvalidField { field, .. }) if field == "document"
+        ));
+    }
+
+    #[test]
+    fn usernames_are_unique_ignoring_case_and_profiles_hide_email_from_others() {
+        let alice = register(principal(1), "Alice", None);
+        set_caller(principal(2));
+        assert!(matches!(
+            register_user(RegisterUserPayload {
+                username: " aLICE ".to_string(),
+                email: "other@example.com".to_string(),
+                referral_code: None,
+            }),
+            Err(Error::UsernameTaken { username }) if username == "aLICE"
+        ));
+        let bob = register(principal(2), "Bob", None);
+
+        // Others see the profile without its email; the owner sees everything
+        let seen = get_user_by_username(" ALICE".to_string()).unwrap();
+        assert_eq!((seen.id, seen.username.as_str()), (alice.id, "Alice"));
+        assert_eq!(seen.email, None);
+        set_caller(principal(1));
+        assert_eq!(
+            get_user(alice.id).unwrap().email.as_deref(),
+            Some("Alice@example.com")
+        );
+        assert_eq!(get_my_profile().unwrap().id, alice.id);
+        set_caller(Principal::anonymous());
+        assert!(matches!(
+            get_my_profile(),
+            Err(Error::Unauthorized {
+                required_role: Role::AccountOwner
+            })
+        ));
+        assert!(matches!(
+            get_user_by_username("carol".to_string()),
+            Err(Error::UsernameNotFound { username }) if username == "carol"
+        ));
+
+        // Renaming frees the old name, and a user may change the case of their own
+        set_caller(principal(1));
+        let rename = |user_id, username: &str| {
+            update_profile(
+                user_id,
+                UpdateProfilePayload {
+                    username: Some(username.to_string()),
+                    ..Default::default()
+                },
+            )
+        };
+        assert!(matches!(
+            rename(alice.id, "BOB"),
+            Err(Error::UsernameTaken { .. })
+        ));
+        assert_eq!(rename(alice.id, "ALICE").unwrap().username, "ALICE");
+        rename(alice.id, "Ada").unwrap();
+        assert!(matches!(
+            get_user_by_username("alice".to_string()),
+            Err(Error::UsernameNotFound { .. })
+        ));
+        set_caller(principal(2));
+        assert_eq!(rename(bob.id, "alice").unwrap().username, "alice");
+    }
+}
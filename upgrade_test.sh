@@ -0,0 +1,62 @@
+#!/usr/bin/env bash
+# Installs an older build of the canister, fills it with data in that build's
+# storage format, upgrades to the current code and checks the data survived.
+#
+# Usage: ./upgrade_test.sh [old-git-ref]
+# The ref defaults to the first commit, which predates schema versioning.
+# Needs a running replica (`dfx start --background`).
+set -euo pipefail
+
+CANISTER=icp_rust_boilerplate_backend
+OLD_REF=${1:-$(git rev-list --max-parents=0 HEAD)}
+
+WORKTREE=$(mktemp -d)
+trap 'git worktree remove --force "$WORKTREE"' EXIT
+git worktree add --detach "$WORKTREE" "$OLD_REF"
+
+cargo build --manifest-path="$WORKTREE/src/$CANISTER/Cargo.toml" \
+    --target wasm32-unknown-unknown \
+    --target-dir "$WORKTREE/target" \
+    --release --package "$CANISTER"
+OLD_WASM="$WORKTREE/target/wasm32-unknown-unknown/release/$CANISTER.wasm"
+
+dfx canister create "$CANISTER"
+dfx canister install "$CANISTER" --mode reinstall --yes --wasm "$OLD_WASM"
+
+# Ids come from one shared counter: user 0, event 1, tickets 2 and 3
+dfx canister call "$CANISTER" register_user \
+    '(record { username = "alice"; email = "alice@example.com" })'
+dfx canister call "$CANISTER" create_event \
+    '(record { name = "Jazz Night"; location = "Berlin"; date = 1_700_000_000_000_000_000; ticket_price = 100; total_tickets = 50 })'
+dfx canister call "$CANISTER" purchase_ticket \
+    '(record { event_id = 1; user_id = 0; seat_number = "A1" })'
+dfx canister call "$CANISTER" purchase_ticket_with_dynamic_pricing \
+    '(record { event_id = 1; user_id = 0; seat_number = "A2" })'
+
+./did.sh
+dfx deploy "$CANISTER" --yes
+
+expect() {
+    local description=$1 pattern=$2
+    shift 2
+    if ! dfx canister call "$CANISTER" "$@" | grep -q "$pattern"; then
+        echo "FAILED: $description" >&2
+        exit 1
+    fi
+    echo "ok: $description"
+}
+
+expect "events survive the upgrade" 'Jazz Night' list_all_events
+expect "tickets survive the upgrade" '"A2"' list_tickets_for_user '(0)'
+expect "legacy tickets are indexed by event" '"A1"' list_tickets_for_event '(1)'
+expect "legacy users get a referral code" 'REF0' get_referral_stats '(0)'
+expect "legacy events default to fixed pricing" 'Fixed' list_all_events
+expect "legacy events stay on sale" 'OnSale' list_all_events
+expect "legacy events are indexed by location" 'Jazz Night' search_events '(record { location = opt "berlin" })'
+expect "legacy tickets are minted in the transaction log" '7mint' icrc3_get_blocks '(vec { record { start = 0; length = 10 } })'
+expect "legacy tickets are counted in sales analytics" 'tickets_sold = 2' get_sales_report '(1, variant { Day })'
+expect "emails of legacy users stay taken" 'EmailTaken' register_user \
+    '(record { username = "alice2"; email = "ALICE@example.com" })'
+expect "usernames of legacy users stay taken" 'UsernameTaken' register_user \
+    '(record { username = "ALICE"; email = "alice3@example.com" })'
+expect "legacy users can be looked up by username" 'id = 0' get_user_by_username '("Alice")'